@@ -0,0 +1,96 @@
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// How a query's terms, and the documents it runs against, are broken into tokens before
+/// comparison. Only applies to term-based clauses - fuzzy clauses always see the raw text.
+#[derive(
+    Archive,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Clone,
+    serde::Serialize,
+    serde::Deserialize,
+    Serialize,
+    Deserialize,
+)]
+#[archive(compare(PartialEq))]
+#[archive_attr(derive(CheckBytes, Debug))]
+#[serde(default)]
+pub struct AnalyzerConfig {
+    /// Lowercase every token, so `Darcy` and `darcy` compare equal
+    pub lowercase: bool,
+    /// Reduce tokens to their Snowball stem, so `incidents` matches `incident`
+    pub stemmer: Option<Language>,
+    /// Words dropped from both queries and documents
+    pub stopwords: Stopwords,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        Self {
+            lowercase: true,
+            stemmer: None,
+            stopwords: Stopwords::None,
+        }
+    }
+}
+
+/// Languages with a Snowball stemmer available
+#[derive(
+    Archive,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Clone,
+    Copy,
+    serde::Serialize,
+    serde::Deserialize,
+    Serialize,
+    Deserialize,
+)]
+#[archive(compare(PartialEq))]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum Language {
+    Arabic,
+    Danish,
+    Dutch,
+    English,
+    Finnish,
+    French,
+    German,
+    Greek,
+    Hungarian,
+    Italian,
+    Norwegian,
+    Portuguese,
+    Romanian,
+    Russian,
+    Spanish,
+    Swedish,
+    Tamil,
+    Turkish,
+}
+
+#[derive(
+    Archive,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Clone,
+    serde::Serialize,
+    serde::Deserialize,
+    Serialize,
+    Deserialize,
+)]
+#[archive(compare(PartialEq))]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum Stopwords {
+    None,
+    English,
+    /// A caller supplied list, compared against lowercased tokens
+    Custom(Vec<String>),
+}
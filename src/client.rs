@@ -1,5 +1,12 @@
-use lib::SplinterClient;
-use std::{net::SocketAddr, time::Duration};
+use lib::{
+    sample, scoring::ScoringModel, DryRun, PersistentQuery, ResultCursor, SplinterClient,
+    TrialQuery,
+};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime},
+};
 use tarpc::{client, context, tokio_serde::formats::Bincode};
 use tokio::time::sleep;
 use tracing::Instrument;
@@ -13,13 +20,26 @@ async fn main() -> anyhow::Result<()> {
     // let server_addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), 8247);
     tracing::info!(message = "Starting up...");
     let server_addr = SocketAddr::from(([127, 0, 0, 1], 8766));
-    let transport = tarpc::serde_transport::tcp::connect(server_addr, Bincode::default);
+    let mut transport = tarpc::serde_transport::tcp::connect(server_addr, Bincode::default);
+    // Dry runs send whole sample corpora in one request
+    transport.config_mut().max_frame_length(usize::MAX);
 
     // WorldClient is generated by the service attribute. It has a constructor `new` that takes a
     // config and any Transport as input.
     let client = SplinterClient::new(client::Config::default(), transport.await?).spawn();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().is_some_and(|command| command == "dry-run") {
+        return dry_run(&client, &args[1..]).await;
+    }
+    // Otherwise, any arguments are query ids to watch for results
+    let watched = args
+        .iter()
+        .map(|query_id| query_id.parse())
+        .collect::<Result<Vec<u64>, _>>()?;
 
+    let hello_client = client.clone();
     let hello = async move {
+        let client = hello_client;
         tokio::select! {
             hello1 = client.hello(context::current(), format!("{}1", "Tom")) => { hello1 }
             hello2 = client.hello(context::current(), format!("{}2", "Astra")) => { hello2 }
@@ -30,8 +50,104 @@ async fn main() -> anyhow::Result<()> {
 
     event!(Level::INFO, message = "Hello call invoked", result=?hello);
 
+    if !watched.is_empty() {
+        watch(&client, watched).await?;
+    }
+
     // Let the background span processor finish.
     sleep(Duration::from_micros(1)).await;
 
     Ok(())
 }
+
+/// Logs every new result for `query_ids` as it's found, resuming from the last one seen for each
+async fn watch(client: &SplinterClient, query_ids: Vec<u64>) -> anyhow::Result<()> {
+    let mut cursors = query_ids
+        .into_iter()
+        .map(|query_id| ResultCursor {
+            query_id,
+            after: None,
+        })
+        .collect::<Vec<_>>();
+    loop {
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() + Duration::from_secs(30);
+        for result in client.watch_results(ctx, cursors.clone()).await?? {
+            event!(
+                Level::INFO,
+                message = "New result",
+                query_id = result.source_query,
+                result.sequence,
+                result.document_id,
+                result.score
+            );
+            if let Some(cursor) = cursors
+                .iter_mut()
+                .find(|cursor| cursor.query_id == result.source_query)
+            {
+                cursor.after = Some(result.sequence);
+            }
+        }
+    }
+}
+
+const DRY_RUN_USAGE: &str = "usage: client dry-run <directory or .jsonl file> \
+                             (--query-id <id> | <query> <threshold> [Skim|Bm25|Normalized])";
+
+/// Tries a stored or new query out on a local sample corpus, printing how many documents it
+/// matched, how their scores spread and the best matches
+async fn dry_run(client: &SplinterClient, args: &[String]) -> anyhow::Result<()> {
+    let (corpus, query) = match args {
+        [corpus, flag, query_id] if flag == "--query-id" => {
+            (corpus, TrialQuery::Stored(query_id.parse()?))
+        }
+        [corpus, query, threshold, scoring @ ..] if scoring.len() <= 1 => {
+            let scoring = match scoring.first().map(String::as_str) {
+                None | Some("Skim") => ScoringModel::Skim,
+                Some("Bm25") => ScoringModel::Bm25,
+                Some("Normalized") => ScoringModel::Normalized,
+                Some(other) => anyhow::bail!("Unknown scoring model {other}\n{DRY_RUN_USAGE}"),
+            };
+            let query = PersistentQuery::new(0, "dry run", query, threshold.parse()?)?
+                .with_scoring(scoring);
+            (corpus, TrialQuery::AdHoc(Box::new(query)))
+        }
+        _ => anyhow::bail!(DRY_RUN_USAGE),
+    };
+    let documents = sample::load(Path::new(corpus))?;
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + Duration::from_secs(300);
+    let report = client.dry_run(ctx, query, documents, 10).await??;
+    print_dry_run(&report);
+    Ok(())
+}
+
+fn print_dry_run(report: &DryRun) {
+    let share = match report.documents {
+        0 => 0.0,
+        documents => 100.0 * report.matched as f64 / documents as f64,
+    };
+    println!(
+        "{:?} matched {} of {} documents ({share:.1}%) at a threshold of {} ({:?})",
+        report.query.query,
+        report.matched,
+        report.documents,
+        report.query.score_threshold,
+        report.query.scoring,
+    );
+    if let Some(scores) = report.scores {
+        println!(
+            "Scores: min {:.3}, median {:.3}, p90 {:.3}, max {:.3}, mean {:.3}",
+            scores.min, scores.median, scores.p90, scores.max, scores.mean
+        );
+    }
+    for found in &report.top {
+        println!("{:>10.3}  {}", found.score, found.name);
+        if let Some(snippet) = &found.snippet {
+            println!(
+                "            {}",
+                snippet.split_whitespace().collect::<Vec<_>>().join(" ")
+            );
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use lib::{DryRun, DryRunMatch, ScoreDistribution, TextSource};
+
+use crate::{
+    search::{corpus::CorpusStats, fuzzy_terms::FuzzyTermIndex, CompiledQuery, Document, Searcher},
+    webhooks,
+};
+
+/// Searches a sample of documents with a query, the way the shards would, and sums up what it
+/// would have matched.
+///
+/// BM25 is scored against statistics over the whole sample, as a node would once it had seen all
+/// of it, rather than the node's own.
+pub(crate) fn dry_run(query: &CompiledQuery, sources: &[TextSource], top: usize) -> DryRun {
+    let searcher = Searcher::new();
+    let fuzzy_terms = FuzzyTermIndex::build([&query.query]);
+    // Kept between counting and searching, so each document is only analyzed once
+    let documents = sources
+        .iter()
+        .map(|source| Document::new(source).with_fuzzy_terms(&fuzzy_terms))
+        .collect::<Vec<_>>();
+    let mut corpus_stats = CorpusStats::default();
+    for document in &documents {
+        corpus_stats.add(document, [&query.query.analyzer]);
+    }
+    let mut matches = sources
+        .iter()
+        .zip(documents)
+        .filter_map(|(source, document)| {
+            let document = document.with_corpus_stats(&corpus_stats);
+            Some((searcher.search(query, &document)?, source))
+        })
+        .collect::<Vec<_>>();
+    matches.sort_by(|(a, _), (b, _)| b.score.total_cmp(&a.score));
+    let scores = matches.iter().rev().map(|(found, _)| found.score);
+    DryRun {
+        query: query.query.clone(),
+        documents: sources.len() as u32,
+        matched: matches.len() as u32,
+        scores: distribution(&scores.collect::<Vec<_>>()),
+        top: matches
+            .into_iter()
+            .take(top)
+            .map(|(found, source)| DryRunMatch {
+                snippet: webhooks::snippet(&source.data, &found.match_indices),
+                document_id: found.document_id,
+                name: found.name,
+                score: found.score,
+                match_indices: found.match_indices,
+            })
+            .collect(),
+    }
+}
+
+/// Sums up scores sorted lowest first, picking the nearest score for the median and 90th
+/// percentile rather than interpolating
+fn distribution(scores: &[f64]) -> Option<ScoreDistribution> {
+    let (min, max) = (*scores.first()?, *scores.last()?);
+    let percentile = |share: f64| scores[((scores.len() - 1) as f64 * share).round() as usize];
+    Some(ScoreDistribution {
+        min,
+        median: percentile(0.5),
+        p90: percentile(0.9),
+        max,
+        mean: scores.iter().sum::<f64>() / scores.len() as f64,
+    })
+}
+
+#[cfg(test)]
+mod dry_run_tests {
+    use super::*;
+    use lib::{scoring::ScoringModel, PersistentQuery};
+
+    #[test]
+    fn test_dry_run_report() {
+        let sources = [
+            "The bank held rates",
+            "Rates, rates and the bank",
+            "The river bank flooded",
+            "Nothing to see here",
+        ]
+        .map(|text| TextSource::from_content(text, text.to_string()));
+        let query = PersistentQuery::new(1, "rates", "rates AND bank", 0.1)
+            .unwrap()
+            .with_scoring(ScoringModel::Bm25);
+        let report = dry_run(&CompiledQuery::new(query).unwrap(), &sources, 1);
+        assert_eq!((report.documents, report.matched), (4, 2));
+        let [best] = &report.top[..] else {
+            panic!("Expected only the best match");
+        };
+        // Saying rates twice outweighs the extra word
+        assert_eq!(best.document_id, sources[1].id);
+        let scores = report.scores.unwrap();
+        assert_eq!(best.score, scores.max);
+        assert!(scores.min <= scores.median && scores.median <= scores.max);
+        assert_eq!(best.snippet.as_deref(), Some("Rates, rates and the bank"));
+
+        let nothing = PersistentQuery::new(1, "none", "zebra", 1.0).unwrap();
+        let report = dry_run(&CompiledQuery::new(nothing).unwrap(), &sources, 10);
+        assert_eq!(report.matched, 0);
+        assert!(report.scores.is_none() && report.top.is_empty());
+    }
+
+    #[test]
+    fn test_score_distribution() {
+        let scores = (1..=10).map(f64::from).collect::<Vec<_>>();
+        let distribution = distribution(&scores).unwrap();
+        assert_eq!((distribution.min, distribution.max), (1.0, 10.0));
+        assert_eq!((distribution.median, distribution.p90), (6.0, 9.0));
+        assert_eq!(distribution.mean, 5.5);
+        assert_eq!(super::distribution(&[]), None);
+    }
+}
@@ -1,4 +1,5 @@
 use axum::{http::StatusCode, response::IntoResponse, Json};
+use lib::{query::QueryParseError, TarkineError};
 use serde_json::json;
 use thiserror::Error;
 
@@ -12,6 +13,12 @@ pub enum ApiError {
     NonExistentId,
     #[error("Could not submit message over internal channel")]
     InternalChannelError,
+    #[error("Error reading/writing from storage layer")]
+    Storage,
+    #[error("Could not parse query: {0}")]
+    InvalidQuery(String),
+    #[error("Conflicts with an earlier request: {0}")]
+    Conflict(String),
 }
 
 impl IntoResponse for ApiError {
@@ -21,6 +28,9 @@ impl IntoResponse for ApiError {
             ApiError::QuerySubmission => (StatusCode::BAD_REQUEST, self.to_string()),
             ApiError::NonExistentId => (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()),
             ApiError::InternalChannelError => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
+            ApiError::Storage => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
+            ApiError::InvalidQuery(_) => (StatusCode::BAD_REQUEST, self.to_string()),
+            ApiError::Conflict(_) => (StatusCode::CONFLICT, self.to_string()),
         };
         let body = Json(json!({ "error": err_msg }));
 
@@ -33,3 +43,23 @@ impl<T> From<tachyonix::SendError<T>> for ApiError {
         ApiError::InternalChannelError
     }
 }
+
+impl From<TarkineError> for ApiError {
+    fn from(err: TarkineError) -> Self {
+        match err {
+            TarkineError::Id => ApiError::NonExistentId,
+            TarkineError::InternalChannel => ApiError::InternalChannelError,
+            TarkineError::InvalidQuery(reason) => ApiError::InvalidQuery(reason),
+            TarkineError::Conflict(reason) => ApiError::Conflict(reason),
+            TarkineError::Storage | TarkineError::Network | TarkineError::Parsing => {
+                ApiError::Storage
+            }
+        }
+    }
+}
+
+impl From<QueryParseError> for ApiError {
+    fn from(err: QueryParseError) -> Self {
+        ApiError::InvalidQuery(err.to_string())
+    }
+}
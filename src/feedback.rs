@@ -0,0 +1,203 @@
+use bytecheck::CheckBytes;
+use lib::{scoring::ScoringModel, Calibration, PersistentQuery, TarkineError, ThresholdPoint};
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// A user's verdict on one of a query's results, kept with the score it was given
+#[derive(Archive, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub(crate) struct Label {
+    pub(crate) sequence: u64,
+    pub(crate) score: f64,
+    /// The model the result was scored under
+    pub(crate) scoring: ScoringModel,
+    pub(crate) relevant: bool,
+}
+
+/// Labeled results, persisted for recommending thresholds from.
+///
+/// Cheap to clone. Labels are keyed by query id and then sequence number, both big endian, so a
+/// query's labels sit together in result order.
+#[derive(Clone)]
+pub(crate) struct Feedback {
+    labels: sled::Tree,
+}
+
+impl Feedback {
+    pub(crate) fn open(db: &sled::Db) -> Result<Self, TarkineError> {
+        Ok(Self {
+            labels: db.open_tree("feedback")?,
+        })
+    }
+
+    /// Stores labels for a query's results, replacing any earlier labels for the same results
+    pub(crate) async fn record(&self, query_id: u64, labels: &[Label]) -> Result<(), TarkineError> {
+        let mut batch = sled::Batch::default();
+        for label in labels {
+            let bytes = rkyv::to_bytes::<_, 64>(label).map_err(|_e| TarkineError::Parsing)?;
+            batch.insert(&label_key(query_id, label.sequence)[..], bytes.as_slice());
+        }
+        self.labels.apply_batch(batch)?;
+        self.labels.flush_async().await?;
+        Ok(())
+    }
+
+    /// Every label given for a query's results, in result order
+    pub(crate) fn labels(&self, query_id: u64) -> Result<Vec<Label>, TarkineError> {
+        self.labels
+            .scan_prefix(query_id.to_be_bytes())
+            .values()
+            .map(|raw| lib::from_archive::<Label>(&raw?))
+            .collect()
+    }
+
+    /// Forgets every label for a query
+    pub(crate) fn clear(&self, query_id: u64) -> Result<(), TarkineError> {
+        let mut batch = sled::Batch::default();
+        for key in self.labels.scan_prefix(query_id.to_be_bytes()).keys() {
+            batch.remove(key?);
+        }
+        self.labels.apply_batch(batch)?;
+        Ok(())
+    }
+}
+
+fn label_key(query_id: u64, sequence: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&query_id.to_be_bytes());
+    key[8..].copy_from_slice(&sequence.to_be_bytes());
+    key
+}
+
+/// Works out precision and recall over the labeled results at each threshold that would keep a
+/// different set of them, and recommends one. Without `min_precision`, that's the threshold with
+/// the best F1 score.
+///
+/// Results scoring below the threshold in force at the time were never stored, so couldn't be
+/// labeled - recall only counts the relevant results that were.
+pub(crate) fn calibrate(
+    query: &PersistentQuery,
+    labels: &[Label],
+    min_precision: Option<f64>,
+) -> Calibration {
+    let labels = labels
+        .iter()
+        .filter(|label| label.scoring == query.scoring)
+        .collect::<Vec<_>>();
+    let relevant = labels.iter().filter(|label| label.relevant).count();
+    let mut thresholds = labels
+        .iter()
+        .map(|label| label.score)
+        // Thresholds have to be above 0
+        .filter(|&score| score > 0.0)
+        .collect::<Vec<_>>();
+    thresholds.sort_unstable_by(f64::total_cmp);
+    thresholds.dedup();
+    let curve = thresholds
+        .into_iter()
+        .map(|threshold| {
+            let kept = labels.iter().filter(|label| label.score >= threshold);
+            let (kept, kept_relevant) = kept.fold((0, 0), |(kept, kept_relevant), label| {
+                (kept + 1, kept_relevant + label.relevant as usize)
+            });
+            ThresholdPoint {
+                threshold,
+                precision: kept_relevant as f64 / kept as f64,
+                recall: match relevant {
+                    0 => 0.0,
+                    relevant => kept_relevant as f64 / relevant as f64,
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+    let candidates = curve.iter().filter(|point| point.recall > 0.0);
+    let recommended = match min_precision {
+        // The lowest qualifying threshold has the best recall
+        Some(min_precision) => candidates
+            .filter(|point| point.precision >= min_precision)
+            .min_by(|a, b| a.threshold.total_cmp(&b.threshold)),
+        None => candidates.max_by(|a, b| f1(a).total_cmp(&f1(b))),
+    };
+    Calibration {
+        query_id: query.id,
+        scoring: query.scoring,
+        score_threshold: query.score_threshold,
+        relevant: relevant as u32,
+        irrelevant: (labels.len() - relevant) as u32,
+        recommended_threshold: recommended.map(|point| point.threshold),
+        curve,
+        applied: false,
+    }
+}
+
+fn f1(point: &ThresholdPoint) -> f64 {
+    2.0 * point.precision * point.recall / (point.precision + point.recall)
+}
+
+#[cfg(test)]
+mod feedback_tests {
+    use super::*;
+
+    fn label(sequence: u64, score: f64, relevant: bool) -> Label {
+        Label {
+            sequence,
+            score,
+            scoring: ScoringModel::Bm25,
+            relevant,
+        }
+    }
+
+    #[test]
+    fn test_calibration_curve() {
+        let query = PersistentQuery::new(7, "q", "rates AND boe", 1.0)
+            .unwrap()
+            .with_scoring(ScoringModel::Bm25);
+        let labels = [
+            label(1, 1.5, false),
+            label(2, 2.0, true),
+            label(3, 2.5, false),
+            label(4, 3.0, true),
+            label(5, 4.0, true),
+            // Scored under another model, so on another scale
+            Label {
+                scoring: ScoringModel::Skim,
+                ..label(6, 120.0, false)
+            },
+        ];
+        let calibration = calibrate(&query, &labels, None);
+        assert_eq!((calibration.relevant, calibration.irrelevant), (3, 2));
+        let thresholds = calibration.curve.iter().map(|point| point.threshold);
+        assert_eq!(thresholds.collect::<Vec<_>>(), [1.5, 2.0, 2.5, 3.0, 4.0]);
+        assert_eq!(calibration.curve[1].precision, 0.75);
+        assert_eq!(calibration.curve[1].recall, 1.0);
+        // F1 is 6/7 at 2.0, against 4/5 at 3.0
+        assert_eq!(calibration.recommended_threshold, Some(2.0));
+        let strict = calibrate(&query, &labels, Some(1.0));
+        assert_eq!(strict.recommended_threshold, Some(3.0));
+        assert_eq!(
+            calibrate(&query, &labels[..1], None).recommended_threshold,
+            None
+        );
+    }
+
+    #[test]
+    fn test_labels_persist_per_query() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let feedback = Feedback::open(&db).unwrap();
+        futures::executor::block_on(async {
+            feedback
+                .record(1, &[label(300, 2.0, true), label(20, 1.0, false)])
+                .await
+                .unwrap();
+            feedback.record(2, &[label(5, 3.0, true)]).await.unwrap();
+            // Labeling a result again replaces its label
+            feedback.record(1, &[label(20, 1.0, true)]).await.unwrap();
+        });
+        assert_eq!(
+            feedback.labels(1).unwrap(),
+            [label(20, 1.0, true), label(300, 2.0, true)]
+        );
+        feedback.clear(1).unwrap();
+        assert!(feedback.labels(1).unwrap().is_empty());
+        assert_eq!(feedback.labels(2).unwrap().len(), 1);
+    }
+}
@@ -1,18 +1,81 @@
 use bytecheck::CheckBytes;
-use rkyv::{Archive, Deserialize, Serialize};
+use rkyv::{validation::validators::DefaultValidator, Archive, Deserialize, Serialize};
 
+use std::collections::BTreeMap;
 use thiserror::Error;
 use tracing_subscriber::prelude::*;
 
+pub mod analysis;
+pub mod metadata;
+pub mod query;
+pub mod sample;
+pub mod scoring;
+
+use analysis::AnalyzerConfig;
+use metadata::MetadataValue;
+use query::{QueryNode, QueryParseError};
+use scoring::ScoringModel;
+
 #[tarpc::service]
 pub trait Splinter {
     async fn hello(name: String) -> String;
     async fn healthcheck() -> String;
     async fn peer_health_capacity() -> LoadCapacityData;
     async fn get_query(query_id: u64) -> Result<PersistentQuery, TarkineError>;
-    async fn submit_query(query: PersistentQuery);
-    async fn submit_document(document: TextSource);
-    async fn get_results(query_id: u64) -> Vec<IndexData>;
+    /// Stores a new query under an id allocated by the server, returning the id. The query's own
+    /// `id` is ignored.
+    ///
+    /// Submitting again with the same `idempotency_key` returns the id from the first submission
+    /// instead of creating a duplicate, so failed submissions can be retried safely.
+    ///
+    /// With `backfill` set, the query is also run over the documents the node has retained, if it
+    /// retains any, and what it finds is stored as results marked as backfilled.
+    async fn submit_query(
+        query: PersistentQuery,
+        idempotency_key: Option<String>,
+        backfill: bool,
+    ) -> Result<u64, TarkineError>;
+    /// Changes the given parts of a stored query, returning it as it now stands
+    async fn update_query(
+        query_id: u64,
+        update: QueryUpdate,
+    ) -> Result<PersistentQuery, TarkineError>;
+    /// Removes a query, so it stops matching. Its results are kept unless `purge_results` is set.
+    async fn delete_query(query_id: u64, purge_results: bool) -> Result<(), TarkineError>;
+    async fn list_queries(page: QueryPage) -> Result<Vec<PersistentQuery>, TarkineError>;
+    /// Returns `false` if the document was dropped for having the same id as one submitted within
+    /// the node's dedup window
+    async fn submit_document(document: TextSource) -> Result<bool, TarkineError>;
+    async fn get_results(query_id: u64, range: ResultRange)
+        -> Result<Vec<IndexData>, TarkineError>;
+    /// Waits for new results on any of the given queries, returning as soon as there are some.
+    ///
+    /// Returns an empty list if nothing turns up before the request's deadline, so clients can
+    /// control how long each call waits through the context deadline. Pass the sequence number of
+    /// the last result seen for each query to resume without missing anything.
+    async fn watch_results(cursors: Vec<ResultCursor>) -> Result<Vec<IndexData>, TarkineError>;
+    /// Records whether each of a query's results was relevant, then recalibrates its threshold
+    /// from everything labeled so far. Labeling a result again replaces its earlier label.
+    async fn label_results(
+        query_id: u64,
+        labels: Vec<ResultLabel>,
+        options: CalibrationOptions,
+    ) -> Result<Calibration, TarkineError>;
+    /// Recommends a threshold for a query from its labeled results
+    async fn calibrate_threshold(
+        query_id: u64,
+        options: CalibrationOptions,
+    ) -> Result<Calibration, TarkineError>;
+    /// Runs a stored or ad-hoc query against a document, reporting how each of its clauses fared
+    /// and whether the document would have become a result. Nothing is stored or delivered.
+    async fn explain(query: TrialQuery, document: TextSource) -> Result<Explanation, TarkineError>;
+    /// Runs a stored or ad-hoc query against a sample of documents, reporting how many it would
+    /// match, how their scores spread and the `top` best matches. Nothing is stored or delivered.
+    async fn dry_run(
+        query: TrialQuery,
+        documents: Vec<TextSource>,
+        top: u32,
+    ) -> Result<DryRun, TarkineError>;
 }
 
 pub fn init_tracing(service_name: &str) -> anyhow::Result<()> {
@@ -32,16 +95,7 @@ pub struct LoadCapacityData {
 }
 
 #[derive(
-    Archive,
-    Debug,
-    PartialEq,
-    Eq,
-    Hash,
-    Clone,
-    serde::Serialize,
-    serde::Deserialize,
-    Serialize,
-    Deserialize,
+    Archive, Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize, Serialize, Deserialize,
 )]
 // This will generate a PartialEq impl between our unarchived and archived types
 #[archive(compare(PartialEq))]
@@ -49,58 +103,357 @@ pub struct LoadCapacityData {
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct PersistentQuery {
     pub name: String,
-    pub query: String, // the query the user makes - later this should be a dedicated structure for more complex query types
-    pub id: u64,       // prefix/namespace to store stuff in database
-    pub score_threshold: i64,
+    pub query: String, // the query the user makes
+    pub parsed: QueryNode,
+    pub analyzer: AnalyzerConfig,
+    /// Allocated by the server when the query is submitted
+    pub id: u64, // prefix/namespace to store stuff in database
+    /// How matches are scored
+    pub scoring: ScoringModel,
+    /// Lowest score a match needs to become a result, on the scale of the scoring model
+    pub score_threshold: f64,
+    /// Where to POST each match as it's found, if anywhere
+    pub webhook: Option<Webhook>,
     result_count: u32,
 }
 
 impl PersistentQuery {
-    pub fn new(id: u64, name: impl Into<String>, q: impl Into<String>, threshold: i64) -> Self {
-        Self {
+    pub fn new(
+        id: u64,
+        name: impl Into<String>,
+        q: impl Into<String>,
+        threshold: f64,
+    ) -> Result<Self, QueryParseError> {
+        let query = q.into();
+        Ok(Self {
             name: name.into(),
-            query: q.into(),
+            parsed: QueryNode::parse(&query)?,
+            query,
+            analyzer: AnalyzerConfig::default(),
             id,
-            score_threshold: threshold, // Need a good way of refining this
+            scoring: ScoringModel::default(),
+            score_threshold: threshold,
+            webhook: None,
             result_count: 0,
+        })
+    }
+
+    pub fn with_analyzer(mut self, analyzer: AnalyzerConfig) -> Self {
+        self.analyzer = analyzer;
+        self
+    }
+
+    /// Scores matches with `scoring` instead of Skim. The threshold has to be on its scale.
+    pub fn with_scoring(mut self, scoring: ScoringModel) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    pub fn with_webhook(mut self, webhook: Webhook) -> Self {
+        self.webhook = Some(webhook);
+        self
+    }
+
+    /// Applies the parts of `update` that are set, re-parsing the query if it changed
+    pub fn apply(&mut self, update: QueryUpdate) -> Result<(), QueryParseError> {
+        if let Some(query) = update.query {
+            self.parsed = QueryNode::parse(&query)?;
+            self.query = query;
+        }
+        if let Some(name) = update.name {
+            self.name = name;
+        }
+        if let Some(threshold) = update.score_threshold {
+            self.score_threshold = threshold;
+        }
+        if let Some(scoring) = update.scoring {
+            self.scoring = scoring;
+        }
+        if let Some(analyzer) = update.analyzer {
+            self.analyzer = analyzer;
+        }
+        if update.remove_webhook {
+            self.webhook = None;
         }
+        if let Some(webhook) = update.webhook {
+            self.webhook = Some(webhook);
+        }
+        Ok(())
+    }
+
+    /// Re-parses `query`, so a `parsed` tree sent over the wire can't disagree with the text.
+    pub fn reparse(&mut self) -> Result<(), QueryParseError> {
+        self.parsed = QueryNode::parse(&self.query)?;
+        Ok(())
     }
 }
 
+/// A URL a query's matches are POSTed to, as JSON, as they're found.
+///
+/// Pending deliveries and their retries are only kept in memory, so delivery is at most once
+/// across restarts: anything not yet delivered when the node stops is dropped without being
+/// parked. Results are always stored, so a receiver that needs every match can read back from the
+/// last sequence number it was sent.
 #[derive(
+    Archive,
     Debug,
     PartialEq,
     Eq,
+    Hash,
     Clone,
-    Archive,
-    Deserialize,
-    Serialize,
     serde::Serialize,
     serde::Deserialize,
+    Serialize,
+    Deserialize,
+)]
+#[archive(compare(PartialEq))]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct Webhook {
+    pub url: String,
+    /// Key to sign each delivery's body with. Signed deliveries carry an HMAC-SHA256 of the body in
+    /// the `X-Tarkine-Signature` header.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Whether to send the text surrounding the match along with it
+    #[serde(default)]
+    pub snippet: bool,
+}
+
+impl Webhook {
+    /// The parsed URL, as long as it's one we can deliver to
+    pub fn url(&self) -> Result<url::Url, String> {
+        let url =
+            url::Url::parse(&self.url).map_err(|err| format!("Invalid webhook url: {err}"))?;
+        match url.scheme() {
+            "http" | "https" => Ok(url),
+            scheme => Err(format!("Webhook url has to be http or https, not {scheme}")),
+        }
+    }
+}
+
+#[derive(
+    Debug, PartialEq, Clone, Archive, Deserialize, Serialize, serde::Serialize, serde::Deserialize,
 )]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct IndexData {
     /// Contains all necessary information to add a document to a query's results
     pub source_query: u64,
     pub key: u64,
+    /// Position in the query's results, assigned when the result is stored. Increases with every
+    /// result, so it can be used to page through or resume reading them.
+    pub sequence: u64,
     pub document_id: u64,
     pub name: String,
     pub match_indices: Vec<[usize; 2]>,
-    pub score: i64,
+    /// On the scale of `scoring`
+    pub score: f64,
+    /// The query's scoring model when the result was found, which can since have changed
+    #[serde(default)]
+    pub scoring: ScoringModel,
+    /// Found by searching documents retained from before the query was submitted, rather than as
+    /// the document arrived
+    #[serde(default)]
+    pub backfilled: bool,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) struct MatchData {
-    snippet: String,
-    score: u32,
+impl IndexData {
+    /// Identifies a match of a query against a document, so the same document matching again gives
+    /// the same key
+    pub fn result_key(query_id: u64, document_id: u64) -> u64 {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&query_id.to_le_bytes());
+        bytes[8..].copy_from_slice(&document_id.to_le_bytes());
+        xxhash_rust::xxh3::xxh3_64(&bytes)
+    }
+}
+
+/// Which of a query's results to read, in the order they were stored
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ResultRange {
+    /// Only return results stored after this sequence number
+    pub after: Option<u64>,
+    /// Return at most this many results
+    pub limit: Option<u32>,
+}
+
+/// Changes to a stored query. Anything left unset stays as it is.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct QueryUpdate {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, alias = "query_string")]
+    pub query: Option<String>,
+    #[serde(default, alias = "threshold")]
+    pub score_threshold: Option<f64>,
+    /// Each model scores on its own scale, so this usually wants a new threshold along with it
+    #[serde(default)]
+    pub scoring: Option<ScoringModel>,
+    #[serde(default)]
+    pub analyzer: Option<AnalyzerConfig>,
+    #[serde(default)]
+    pub webhook: Option<Webhook>,
+    /// Stop delivering the query's matches to its webhook
+    #[serde(default)]
+    pub remove_webhook: bool,
+}
+
+/// A page of the stored queries. Queries are listed in storage order, which is stable but isn't
+/// the order of their ids - pass the last id of one page as `after` to get the next.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct QueryPage {
+    /// Start after the query with this id in storage order. It's a cursor rather than a bound, so
+    /// queries with lower ids can still come after it.
+    pub after: Option<u64>,
+    /// Return at most this many queries
+    pub limit: Option<u32>,
+}
+
+/// A query whose results are being watched, and where to resume from
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct ResultCursor {
+    pub query_id: u64,
+    /// Sequence number of the last result already seen for this query, if any
+    pub after: Option<u64>,
+}
+
+/// Whether one of a query's stored results was what the query was after
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct ResultLabel {
+    /// The result's sequence number
+    pub sequence: u64,
+    pub relevant: bool,
+}
+
+/// How to pick a query's threshold from its labeled results
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct CalibrationOptions {
+    /// Recommend the lowest threshold keeping precision at least this high, instead of the one
+    /// balancing precision and recall best
+    #[serde(default)]
+    pub min_precision: Option<f64>,
+    /// Set the query's threshold to the recommendation
+    #[serde(default)]
+    pub apply: bool,
+}
+
+/// A threshold recommended from a query's labeled results, along with how every threshold that
+/// would have made a difference compares
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Calibration {
+    pub query_id: u64,
+    /// The model the labeled scores and the thresholds are on. Labels given under a different
+    /// model are left out.
+    pub scoring: ScoringModel,
+    /// The query's threshold, after applying the recommendation if that was asked for
+    pub score_threshold: f64,
+    pub relevant: u32,
+    pub irrelevant: u32,
+    /// Precision and recall over the labeled results at each labeled score, lowest first
+    pub curve: Vec<ThresholdPoint>,
+    /// Missing until at least one result has been labeled relevant
+    pub recommended_threshold: Option<f64>,
+    /// Whether the query's threshold was set to the recommendation
+    pub applied: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ThresholdPoint {
+    pub threshold: f64,
+    /// Share of the labeled results scoring at least the threshold that were relevant
+    pub precision: f64,
+    /// Share of the relevant results scoring at least the threshold
+    pub recall: f64,
+}
+
+/// A query to try out on documents without them becoming results, for explaining a match or a
+/// dry run
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum TrialQuery {
+    /// A stored query, by id
+    Stored(u64),
+    /// A query that's checked as if it were being submitted, but never stored
+    AdHoc(Box<PersistentQuery>),
+}
+
+/// Why a document did or didn't become one of a query's results. Nothing about the document is
+/// stored or delivered while working it out.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Explanation {
+    /// The query as it was run, including its parsed tree
+    pub query: PersistentQuery,
+    /// How the whole query fared, clause by clause
+    pub clauses: ClauseExplanation,
+    /// The match's score on the query's scoring model, missing if its clauses didn't match
+    pub score: Option<f64>,
+    pub score_threshold: f64,
+    /// Whether the document would have become a result
+    pub passed: bool,
+    /// Char positions of everything matched in the main text, as a result would have them
+    pub match_indices: Vec<[usize; 2]>,
+}
+
+/// How a single clause of a query fared against a document
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ClauseExplanation {
+    pub clause: QueryNode,
+    pub matched: bool,
+    /// What the clause added to the query's score. Under `Normalized` scoring this is BM25, as
+    /// only the whole query's score is normalized.
+    pub score: f64,
+    /// Char positions of what the clause matched, in the text it was matched against - clauses
+    /// under a `field:` point into that field, and metadata has none
+    pub positions: Vec<[usize; 2]>,
+    /// The clauses this one is made of, in order. An AND gives up at its first clause that
+    /// doesn't match, so the ones after it aren't listed.
+    pub children: Vec<ClauseExplanation>,
+}
+
+/// How a query fared against a sample of documents
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DryRun {
+    pub query: PersistentQuery,
+    pub documents: u32,
+    /// Documents that would have become results
+    pub matched: u32,
+    /// How the scores of the documents that matched spread, missing if none did
+    pub scores: Option<ScoreDistribution>,
+    /// The best scoring matches, best first
+    pub top: Vec<DryRunMatch>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ScoreDistribution {
+    pub min: f64,
+    pub median: f64,
+    /// Nine in ten matches scored this or lower
+    pub p90: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DryRunMatch {
+    pub document_id: u64,
+    pub name: String,
+    pub score: f64,
+    pub match_indices: Vec<[usize; 2]>,
+    /// The main text around the first match, if anything in it matched
+    pub snippet: Option<String>,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct TextSource {
     pub id: u64,
     pub name: String,
+    /// The main text. Query clauses without a field name are matched against this, and
+    /// `match_indices` always point into it.
     pub data: String,
-    // Feature idea -
+    /// Other named text, like a title, that clauses can be scoped to with `title:darcy`
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Structured values that clauses can filter on with `source:reuters` or `published>2026-01-01`
+    #[serde(default)]
+    pub metadata: BTreeMap<String, MetadataValue>,
 }
 
 impl TextSource {
@@ -109,7 +462,56 @@ impl TextSource {
             id: rand::random(),
             data: text.to_string(),
             name: text_name,
+            fields: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// Like `new`, but the id is derived from the name and text, so submitting the same document
+    /// twice gives it the same id both times. Documents with fields or metadata want
+    /// [`TextSource::with_content_id`] once those are added.
+    pub fn from_content(text: impl ToString, text_name: String) -> Self {
+        Self::new(text, text_name).with_content_id()
+    }
+
+    pub fn with_field(mut self, name: impl ToString, text: impl ToString) -> Self {
+        self.fields.insert(name.to_string(), text.to_string());
+        self
+    }
+
+    pub fn with_metadata(mut self, name: impl ToString, value: MetadataValue) -> Self {
+        self.metadata.insert(name.to_string(), value);
+        self
+    }
+
+    /// Replaces the id with one derived from everything else in the document
+    pub fn with_content_id(mut self) -> Self {
+        self.id = self.content_id();
+        self
+    }
+
+    /// xxh3 of the name, text, fields and metadata. Everything goes in with its length first, so
+    /// moving text from one to another gives a different id. Fields and metadata are hashed in key
+    /// order, and metadata values as their JSON.
+    pub fn content_id(&self) -> u64 {
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        let mut update = |bytes: &[u8]| {
+            hasher.update(&(bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        update(self.name.as_bytes());
+        update(self.data.as_bytes());
+        update(&(self.fields.len() as u64).to_le_bytes());
+        for (name, text) in &self.fields {
+            update(name.as_bytes());
+            update(text.as_bytes());
+        }
+        update(&(self.metadata.len() as u64).to_le_bytes());
+        for (name, value) in &self.metadata {
+            update(name.as_bytes());
+            update(&serde_json::to_vec(value).expect("Metadata values always serialize"));
         }
+        hasher.digest()
     }
 
     // Lazy loading from supported sources, etc
@@ -126,6 +528,12 @@ pub enum TarkineError {
     Id,
     #[error("Could not parse bytes")]
     Parsing,
+    #[error("Could not submit message over internal channel")]
+    InternalChannel,
+    #[error("Could not parse query: {0}")]
+    InvalidQuery(String),
+    #[error("Conflicts with an earlier request: {0}")]
+    Conflict(String),
 }
 
 impl From<sled::Error> for TarkineError {
@@ -133,3 +541,44 @@ impl From<sled::Error> for TarkineError {
         Self::Storage
     }
 }
+
+impl From<QueryParseError> for TarkineError {
+    fn from(err: QueryParseError) -> Self {
+        Self::InvalidQuery(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for TarkineError {
+    fn from(_: std::io::Error) -> Self {
+        Self::Storage
+    }
+}
+
+impl<T> From<tachyonix::SendError<T>> for TarkineError {
+    fn from(_: tachyonix::SendError<T>) -> Self {
+        Self::InternalChannel
+    }
+}
+
+/// Validates and deserialises an rkyv archive of `T`.
+///
+/// Bytes coming back out of sled or off disk have no alignment guarantees, so they get copied into
+/// an `AlignedVec` before validation.
+pub fn from_archive<T>(bytes: &[u8]) -> Result<T, TarkineError>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<T, rkyv::Infallible>,
+{
+    let mut aligned = rkyv::AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+    let archived = rkyv::check_archived_root::<T>(&aligned).map_err(|_| {
+        tracing::error!(
+            message = "Failed to validate rkyv bytes",
+            archived_type = std::any::type_name::<T>()
+        );
+        TarkineError::Parsing
+    })?;
+    archived
+        .deserialize(&mut rkyv::Infallible)
+        .map_err(|_| TarkineError::Parsing)
+}
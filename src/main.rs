@@ -1,24 +1,36 @@
-use futures_lite::AsyncWriteExt;
-use glommio::{LocalExecutorBuilder, Placement, io::DmaStreamWriterBuilder, defer};
+use glommio::{LocalExecutorBuilder, Placement, CpuSet};
 use itertools::Itertools;
-use lib::{IndexData, PersistentQuery, TextSource};
-use search::Searcher;
 use tracing::{Level, event};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use std::{error, net::SocketAddr, path::{PathBuf}};
+use std::{error, net::SocketAddr, num::NonZeroUsize, path::{PathBuf}, time::Duration};
 
 mod data_source;
+mod dry_run;
 mod errors;
+mod feedback;
+mod results;
+mod retention;
 mod search;
 mod rpc_server;
+mod server;
+mod shard;
+mod state;
+mod streams;
+mod webhooks;
 
-use crate::rpc_server::server_runtime;
+use crate::retention::{DocumentRetention, RetentionLimits};
+use crate::shard::{BackfillRoutes, DocumentBroadcast, QueryShard, ShardRuntime, ShardedQueries};
+use crate::state::NodeState;
 
-const DATA_PATH: &str = "output_data";
+const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Retention with an age limit but no size limit is still capped at this
+const DEFAULT_RETENTION_MB: u64 = 1024;
 
 fn main() -> Result<(), Box<dyn error::Error>> {
     let db_path = PathBuf::from("splinter.data");
+    let results_path = PathBuf::from("results");
+    let retention_path = PathBuf::from("retained");
     tracing_subscriber::registry()
     .with(tracing_subscriber::EnvFilter::new(
         std::env::var("RUST_LOG")
@@ -28,26 +40,92 @@ fn main() -> Result<(), Box<dyn error::Error>> {
     .init();
     let core_count = std::thread::available_parallelism()?;
     event!(Level::INFO, core_count);
-    let bind_addr = SocketAddr::from(([127, 0, 0, 1], 8766));
+    // One shard per core unless told otherwise
+    let shard_count = match std::env::var("TARKINE_SHARDS") {
+        Ok(count) => count.parse::<NonZeroUsize>()?.get(),
+        Err(_) => core_count.get(),
+    };
+    let cpus = CpuSet::online()?.iter().map(|location| location.cpu).collect_vec();
+    event!(Level::INFO, shard_count, ?cpus);
+    let rpc_addr = SocketAddr::from(([127, 0, 0, 1], 8766));
+    let http_addr = SocketAddr::from(([127, 0, 0, 1], 8765));
 
-    let (_write_map, read_map) = flashmap::with_capacity(1000);
-    let (send_chan, recv_chan) = tachyonix::channel(1024);
-    event!(Level::INFO, message="Starting API server thread");
-    let server_threads =
-        std::thread::spawn(move || server_runtime(bind_addr, db_path, send_chan));
-        let shard1 = QueryShard {
-            inner: read_map,
-            engine: Searcher::new(),
-        };
-    event!(Level::INFO, message="Starting Indexing Thread");
-    let processor_thread = LocalExecutorBuilder::new(Placement::Fixed(1))
-        .spawn(|| index_runtime(shard1, recv_chan))?;
+    let (write_maps, readers) = ShardedQueries::new(shard_count);
+    // Documents with the same id as one seen this recently are dropped, 0 turns it off
+    let dedup_window = match std::env::var("TARKINE_DEDUP_WINDOW_SECS") {
+        Ok(secs) => Duration::from_secs(secs.parse()?),
+        Err(_) => DEFAULT_DEDUP_WINDOW,
+    };
+    event!(Level::INFO, ?dedup_window);
+    let (doc_broadcast, doc_receivers) = DocumentBroadcast::new(shard_count, 1024);
+    let doc_broadcast = doc_broadcast.with_dedup_window(dedup_window);
+    // Off unless either limit is set, as backfilling is the only thing it's for
+    let retention_mb = std::env::var("TARKINE_RETENTION_MB").ok().map(|mb| mb.parse::<u64>()).transpose()?;
+    let retention_age = std::env::var("TARKINE_RETENTION_SECS").ok().map(|secs| secs.parse().map(Duration::from_secs)).transpose()?;
+    let retention_limits = (retention_mb.is_some() || retention_age.is_some()).then(|| RetentionLimits {
+        max_bytes: retention_mb.unwrap_or(DEFAULT_RETENTION_MB) * 1024 * 1024,
+        max_age: retention_age,
+    });
+    event!(Level::INFO, ?retention_limits);
+    let retention = retention_limits.map(|limits| DocumentRetention::new(retention_path, limits, shard_count));
+    let (deliveries, delivery_queue) = tachyonix::channel(1024);
+    let mut state = NodeState::open(db_path, results_path, doc_broadcast, deliveries, write_maps)?;
+    if let Some(limits) = retention_limits {
+        state = state.with_retention(limits);
+    }
+    event!(Level::INFO, message="Starting API server threads");
+    let http_state = state.clone();
+    let results = state.results();
+    let webhooks = state.webhooks();
+    let corpus_stats = state.corpus_stats();
+    let starting_stats = corpus_stats.snapshot()?;
+    let webhook_thread = {
+        let webhooks = webhooks.clone();
+        std::thread::spawn(move || webhooks::delivery_runtime(webhooks, delivery_queue))
+    };
+    let server_threads = std::thread::spawn(move || rpc_server::server_runtime(rpc_addr, state));
+    let http_thread = std::thread::spawn(move || server::server_runtime(http_addr, http_state));
+    let (routes, backfill_inboxes) = BackfillRoutes::new(shard_count);
+    event!(Level::INFO, message="Starting Indexing Threads");
+    let processor_threads = readers
+        .into_iter()
+        .zip(doc_receivers)
+        .zip(backfill_inboxes)
+        .enumerate()
+        .map(|(shard_id, ((reader, recv_chan), backfilled))| {
+            let shard = QueryShard::new(reader).with_starting_stats(&starting_stats);
+            let shard = match shard_id {
+                // Every shard sees every document, so any one's statistics will do for explanations
+                0 => shard.with_corpus_stats(corpus_stats.clone()),
+                _ => shard,
+            };
+            let results = results.clone();
+            let webhooks = webhooks.clone();
+            let retention = retention.clone();
+            let routes = routes.clone();
+            // Spread shards over the online cpus, doubling up if we've been asked for more shards than cores
+            let cpu = cpus[shard_id % cpus.len()];
+            LocalExecutorBuilder::new(Placement::Fixed(cpu))
+                .name(&format!("search-shard-{shard_id}"))
+                .spawn(move || ShardRuntime { shard_id, shard, results, webhooks, retention, routes }.run(recv_chan, backfilled))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
     match server_threads.join() {
         Ok(_) => event!(Level::INFO, message="Server thread has exited safely - shutting down"),
         Err(_) => event!(Level::ERROR, message="Server thread has crashed - restart application"),
     };
-    processor_thread.join()?;
+    match http_thread.join() {
+        Ok(_) => event!(Level::INFO, message="HTTP thread has exited safely - shutting down"),
+        Err(_) => event!(Level::ERROR, message="HTTP thread has crashed - restart application"),
+    };
+    match webhook_thread.join() {
+        Ok(_) => event!(Level::INFO, message="Webhook thread has exited safely - shutting down"),
+        Err(_) => event!(Level::ERROR, message="Webhook thread has crashed - restart application"),
+    };
+    for processor_thread in processor_threads {
+        processor_thread.join()?;
+    }
     /*
     TODO: Proper handling and error messages for thread exits.
     If we lose the server thread - do we drain the search threads and let the application exit?
@@ -66,51 +144,3 @@ fn main() -> Result<(), Box<dyn error::Error>> {
     //     hello().await;
     // })?;
 }
-
-struct QueryShard {
-    inner: flashmap::ReadHandle<u64, PersistentQuery>,
-    engine: Searcher,
-}
-
-impl QueryShard {
-    async fn search(&self, text: TextSource) -> Vec<IndexData> {
-        // Later, a stream of results?
-        self.inner
-            .guard()
-            .values()
-            .filter_map(|q| self.engine.search(q, &text))
-            .collect_vec()
-    }
-}
-
-fn form_path(query_id: u64, doc_id: u64) -> PathBuf {
-    let mut path = PathBuf::from(DATA_PATH);
-    let subpath = PathBuf::from(format!("{query_id}"));
-    path.push(subpath);
-    path.push(format!("{doc_id}.rkyv"));
-    path
-}
-
-async fn index_runtime(shard: QueryShard, mut text_recv: tachyonix::Receiver<TextSource>) {
-    loop {
-        if let Ok(doc) = text_recv.recv().await {
-            event!(Level::INFO, message="document received", thread_id=glommio::executor().id(), ?doc.id, ?doc.name);
-            let doc_id=doc.id;
-            let mut search_results = shard.search(doc).await;
-            for index_data in search_results.drain(0..) {
-                glommio::spawn_local(async move {
-                    let output_path = form_path( index_data.source_query, index_data.document_id);
-                    let debug_path = output_path.clone(); // fight me
-                    defer!(event!(Level::INFO, message="Wrote results into file!", thread_id=glommio::executor().id(), ?doc_id, file_path=?debug_path));
-                    std::fs::create_dir_all(output_path.parent().unwrap()).expect("Couldn't create dir manually"); // yay std lib functions
-                    let dma_file = glommio::io::DmaFile::create(output_path).await.expect("Couldn't create file");
-                    let mut writer = DmaStreamWriterBuilder::new(dma_file).build();
-                    let index_buffer = rkyv::to_bytes::<_, 1024>(&index_data).unwrap(); // Live dangerously
-                    writer.write_all(index_buffer.as_slice()).await.expect("Couldn't write file!");
-                
-                })
-                .await;
-            }
-        }
-    }
-}
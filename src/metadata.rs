@@ -0,0 +1,219 @@
+use chrono::{DateTime, Duration, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+
+/// A structured value attached to a document, which queries can filter on with `field:value` or
+/// compare against with `field>value`.
+///
+/// In JSON, numbers are numbers, arrays of strings are keyword lists, and strings are timestamps
+/// if they read as one (`2026-01-03T09:30:00Z` or `2026-01-03`), or plain text otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataValue {
+    /// Matched as a whole, after analysis - `source:reuters` doesn't match `reuters breakingviews`
+    Text(String),
+    Number(f64),
+    Timestamp(DateTime<Utc>),
+    /// Matched if any one of the keywords is
+    Keywords(Vec<String>),
+}
+
+impl MetadataValue {
+    /// How the value orders against a number or timestamp written in a query, or `None` if the
+    /// two can't be compared. Text and keywords only support equality, which needs an analyzer.
+    ///
+    /// Relative times like `now-24h` are worked out from the current time on every call, so a
+    /// standing query's window moves along with the clock.
+    pub fn compare(&self, literal: &str) -> Option<Ordering> {
+        match self {
+            MetadataValue::Number(value) => value.partial_cmp(&literal.parse::<f64>().ok()?),
+            MetadataValue::Timestamp(value) => {
+                Some(value.cmp(&resolve_timestamp(literal, Utc::now())?))
+            }
+            MetadataValue::Text(_) | MetadataValue::Keywords(_) => None,
+        }
+    }
+}
+
+/// Reads a timestamp as written in a query or document: RFC 3339, or a bare `2026-01-03` for
+/// midnight UTC that day
+pub fn parse_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(text) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    Some(DateTime::from_utc(date.and_hms_opt(0, 0, 0)?, Utc))
+}
+
+/// Reads a timestamp as written in a query: anything `parse_timestamp` takes, or `now` with an
+/// optional offset in seconds, minutes, hours, days or weeks - `now-24h`, `now+1w`
+pub fn resolve_timestamp(text: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let Some(offset) = text.strip_prefix("now") else {
+        return parse_timestamp(text);
+    };
+    if offset.is_empty() {
+        return Some(now);
+    }
+    let (sign, offset) = match offset.split_at(1) {
+        ("+", offset) => (1, offset),
+        ("-", offset) => (-1, offset),
+        _ => return None,
+    };
+    let (amount, unit) = offset.split_at(offset.len() - offset.chars().last()?.len_utf8());
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let seconds = amount.parse::<i64>().ok()?.checked_mul(unit_seconds)?;
+    // chrono panics on durations it can't represent in milliseconds
+    if seconds.unsigned_abs() > (i64::MAX / 1000) as u64 {
+        return None;
+    }
+    now.checked_add_signed(Duration::seconds(sign * seconds))
+}
+
+/// Whether a query literal can be compared against numeric or timestamp metadata
+pub fn is_comparable(literal: &str) -> bool {
+    literal.parse::<f64>().is_ok() || resolve_timestamp(literal, Utc::now()).is_some()
+}
+
+/// Metadata as it appears in JSON
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Untagged {
+    Number(f64),
+    Keywords(Vec<String>),
+    Text(String),
+}
+
+/// Metadata in formats that can't describe themselves, like the bincode tarpc uses. The variants
+/// have to stay in the same order as `MetadataValue`'s.
+#[derive(Deserialize)]
+#[serde(rename = "MetadataValue")]
+enum Tagged {
+    Text(String),
+    Number(f64),
+    Timestamp(DateTime<Utc>),
+    Keywords(Vec<String>),
+}
+
+impl Serialize for MetadataValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        const NAME: &str = "MetadataValue";
+        let human_readable = serializer.is_human_readable();
+        match self {
+            MetadataValue::Text(text) if human_readable => serializer.serialize_str(text),
+            MetadataValue::Number(number) if human_readable => serializer.serialize_f64(*number),
+            MetadataValue::Timestamp(timestamp) if human_readable => {
+                serializer.serialize_str(&timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            MetadataValue::Keywords(keywords) if human_readable => keywords.serialize(serializer),
+            MetadataValue::Text(text) => {
+                serializer.serialize_newtype_variant(NAME, 0, "Text", text)
+            }
+            MetadataValue::Number(number) => {
+                serializer.serialize_newtype_variant(NAME, 1, "Number", number)
+            }
+            MetadataValue::Timestamp(timestamp) => {
+                serializer.serialize_newtype_variant(NAME, 2, "Timestamp", timestamp)
+            }
+            MetadataValue::Keywords(keywords) => {
+                serializer.serialize_newtype_variant(NAME, 3, "Keywords", keywords)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MetadataValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Ok(match Tagged::deserialize(deserializer)? {
+                Tagged::Text(text) => MetadataValue::Text(text),
+                Tagged::Number(number) => MetadataValue::Number(number),
+                Tagged::Timestamp(timestamp) => MetadataValue::Timestamp(timestamp),
+                Tagged::Keywords(keywords) => MetadataValue::Keywords(keywords),
+            });
+        }
+        Ok(match Untagged::deserialize(deserializer)? {
+            Untagged::Number(number) => MetadataValue::Number(number),
+            Untagged::Keywords(keywords) => MetadataValue::Keywords(keywords),
+            Untagged::Text(text) => match parse_timestamp(&text) {
+                Some(timestamp) => MetadataValue::Timestamp(timestamp),
+                None => MetadataValue::Text(text),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod metadata_tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_json_values() {
+        let metadata: BTreeMap<String, MetadataValue> = serde_json::from_str(
+            r#"{"source": "reuters", "severity": 3, "published": "2026-01-03T09:30:00Z",
+                "tags": ["markets", "rates"]}"#,
+        )
+        .unwrap();
+        let published = parse_timestamp("2026-01-03T09:30:00Z").unwrap();
+        assert_eq!(
+            metadata["source"],
+            MetadataValue::Text("reuters".to_string())
+        );
+        assert_eq!(metadata["severity"], MetadataValue::Number(3.0));
+        assert_eq!(metadata["published"], MetadataValue::Timestamp(published));
+        assert_eq!(
+            metadata["tags"],
+            MetadataValue::Keywords(vec!["markets".to_string(), "rates".to_string()])
+        );
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert_eq!(
+            serde_json::from_str::<BTreeMap<_, _>>(&json).unwrap(),
+            metadata
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        let published = MetadataValue::Timestamp(parse_timestamp("2026-01-03T09:30:00Z").unwrap());
+        assert_eq!(published.compare("2026-01-03"), Some(Ordering::Greater));
+        assert_eq!(
+            published.compare("2026-01-03T10:30:00+01:00"),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(published.compare("soon"), None);
+        assert_eq!(
+            MetadataValue::Number(3.0).compare("2.5"),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(MetadataValue::Text("3".to_string()).compare("3"), None);
+        assert!(is_comparable("-1.5") && is_comparable("2026-01-03") && !is_comparable("jan"));
+    }
+
+    #[test]
+    fn test_relative_timestamps() {
+        let now = parse_timestamp("2026-01-03T09:30:00Z").unwrap();
+        let resolve = |text| resolve_timestamp(text, now);
+        assert_eq!(resolve("now"), Some(now));
+        assert_eq!(resolve("now-24h"), parse_timestamp("2026-01-02T09:30:00Z"));
+        assert_eq!(resolve("now+1w"), parse_timestamp("2026-01-10T09:30:00Z"));
+        assert_eq!(resolve("now-90m"), parse_timestamp("2026-01-03T08:00:00Z"));
+        assert_eq!(
+            resolve("2026-01-01"),
+            parse_timestamp("2026-01-01T00:00:00Z")
+        );
+        for invalid in ["now-", "now-h", "now*2d", "now-2y", "now-9999999999999999w"] {
+            assert_eq!(resolve(invalid), None, "{invalid}");
+        }
+        // Only queries can be relative, a document's "now" is just text
+        assert_eq!(
+            serde_json::from_str::<MetadataValue>("\"now\"").unwrap(),
+            MetadataValue::Text("now".to_string())
+        );
+    }
+}
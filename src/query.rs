@@ -0,0 +1,927 @@
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+use std::cmp::Ordering;
+use thiserror::Error;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A parsed percolation query.
+///
+/// A query with no boolean syntax at all (`mr darcy`) is kept whole as a [`QueryNode::Fuzzy`]
+/// match, exactly as queries behaved before the query language existed. As soon as the query uses
+/// `AND`, `OR`, `NOT`, parentheses, quotes, `NEAR/n`, `term~n`, a wildcard, a `/regex/`, `field:`,
+/// a comparison or a range, every bare word becomes a [`QueryNode::Term`]
+/// that must appear as a whole word in the document, and words next to each other are implicitly
+/// `AND`ed together.
+#[derive(
+    Archive,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Clone,
+    serde::Serialize,
+    serde::Deserialize,
+    Serialize,
+    Deserialize,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(compare(PartialEq))]
+#[archive_attr(derive(CheckBytes, Debug))]
+#[archive_attr(check_bytes(
+    bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: bytecheck::Error"
+))]
+pub enum QueryNode {
+    /// Skim-style fuzzy match of the whole string against the document
+    Fuzzy(String),
+    /// A single word that must appear in the document
+    Term(String),
+    /// A single word that must appear in the document with no more than `distance` edits
+    /// (insertions, deletions or substitutions) - written `darcy~1`, or `darcy~` for 2 edits
+    FuzzyTerm { term: String, distance: u32 },
+    /// A single word matching a pattern, where `*` stands for any run of characters and `?` for
+    /// any one - written `micro*` or `colo?r`
+    Wildcard(String),
+    /// A regular expression found anywhere in the text as written, rather than in its analyzed
+    /// words, so it can span punctuation - written `/INC-\d{6}/`
+    Regex(String),
+    /// Words that must appear next to each other, in order - written `"mr darcy"`
+    Phrase(Vec<String>),
+    /// Two terms or phrases no more than `distance` words apart, in either order - written
+    /// `darcy NEAR/5 elizabeth`
+    Near {
+        #[omit_bounds]
+        #[archive_attr(omit_bounds)]
+        left: Box<QueryNode>,
+        #[omit_bounds]
+        #[archive_attr(omit_bounds)]
+        right: Box<QueryNode>,
+        distance: u32,
+    },
+    And(
+        #[omit_bounds]
+        #[archive_attr(omit_bounds)]
+        Vec<QueryNode>,
+    ),
+    Or(
+        #[omit_bounds]
+        #[archive_attr(omit_bounds)]
+        Vec<QueryNode>,
+    ),
+    Not(
+        #[omit_bounds]
+        #[archive_attr(omit_bounds)]
+        Box<QueryNode>,
+    ),
+    /// A clause matched against one of the document's named text fields, or against the metadata
+    /// value of that name if there's no such field - written `title:darcy`, `title:"mr darcy"` or
+    /// `source:(reuters OR ap)`
+    Field {
+        field: String,
+        #[omit_bounds]
+        #[archive_attr(omit_bounds)]
+        clause: Box<QueryNode>,
+    },
+    /// A numeric or timestamp metadata value compared against a number or date - written
+    /// `published>2026-01-01`, `published>now-24h` or `severity>=3`. Comparisons only filter, they
+    /// don't add to the score.
+    Compare {
+        field: String,
+        op: Comparison,
+        value: String,
+    },
+    /// A numeric or timestamp metadata value between two bounds - written `price:[10 TO 20]`, with
+    /// `{}` for an exclusive end and `*` for an open one, e.g. `published:{now-7d TO *]`. Like
+    /// comparisons, ranges only filter.
+    Range {
+        field: String,
+        lower: Option<RangeBound>,
+        upper: Option<RangeBound>,
+    },
+}
+
+/// One end of a [`QueryNode::Range`]
+#[derive(
+    Archive,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Clone,
+    serde::Serialize,
+    serde::Deserialize,
+    Serialize,
+    Deserialize,
+)]
+#[archive(compare(PartialEq))]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct RangeBound {
+    pub value: String,
+    pub inclusive: bool,
+}
+
+impl RangeBound {
+    /// How a value has to compare against this bound if it's the lower end of the range
+    pub fn lower(&self) -> Comparison {
+        match self.inclusive {
+            true => Comparison::GreaterOrEqual,
+            false => Comparison::Greater,
+        }
+    }
+
+    /// How a value has to compare against this bound if it's the upper end of the range
+    pub fn upper(&self) -> Comparison {
+        match self.inclusive {
+            true => Comparison::LessOrEqual,
+            false => Comparison::Less,
+        }
+    }
+}
+
+#[derive(
+    Archive,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Clone,
+    Copy,
+    serde::Serialize,
+    serde::Deserialize,
+    Serialize,
+    Deserialize,
+)]
+#[archive(compare(PartialEq))]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+impl Comparison {
+    /// Whether a value that orders against the query's literal like this satisfies the comparison
+    pub fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Greater => ordering.is_gt(),
+            Comparison::GreaterOrEqual => ordering.is_ge(),
+            Comparison::Less => ordering.is_lt(),
+            Comparison::LessOrEqual => ordering.is_le(),
+        }
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+        })
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum QueryParseError {
+    #[error("Query is empty")]
+    Empty,
+    #[error("Unexpected '{0}'")]
+    UnexpectedToken(String),
+    #[error("Query ended unexpectedly")]
+    UnexpectedEnd,
+    #[error("Unbalanced parentheses")]
+    UnbalancedParens,
+    #[error("Query must contain at least one clause that isn't negated or a comparison")]
+    OnlyNegative,
+    #[error("Phrase is missing its closing quote")]
+    UnterminatedPhrase,
+    #[error("Phrase doesn't contain any words")]
+    EmptyPhrase,
+    #[error("NEAR can only be used between terms and phrases")]
+    InvalidNearOperand,
+    #[error("Edit distance of {0} is too large, the most allowed is {MAX_EDIT_DISTANCE}")]
+    EditDistanceTooLarge(u32),
+    #[error("Field clauses can't contain other field clauses or comparisons")]
+    NestedField,
+    #[error("Can't compare against '{0}', it has to be a number or a date")]
+    InvalidComparison(String),
+    #[error("Regex is missing its closing slash")]
+    UnterminatedRegex,
+    #[error("Range is missing its closing bracket")]
+    UnterminatedRange,
+    #[error("Ranges are written 'field:[from TO to]', not '{0}'")]
+    InvalidRange(String),
+    #[error("Query nests groups and NOTs more than {MAX_DEPTH} deep")]
+    TooDeep,
+}
+
+/// Largest edit distance allowed in `term~n`. The Levenshtein automata behind fuzzy terms grow
+/// quickly with distance, and past 2 edits most short words match each other anyway.
+pub const MAX_EDIT_DISTANCE: u32 = 2;
+
+/// Deepest that parentheses and `NOT`s can nest. The parser, and everything walking the tree after
+/// it, recurses once per level, so this keeps a hostile query from overflowing the stack.
+pub const MAX_DEPTH: usize = 128;
+
+impl QueryNode {
+    pub fn parse(query: &str) -> Result<Self, QueryParseError> {
+        let tokens = tokenize(query)?;
+        if tokens.is_empty() {
+            return Err(QueryParseError::Empty);
+        }
+        if tokens.iter().all(|t| matches!(t, Token::Word(_))) {
+            return Ok(QueryNode::Fuzzy(query.to_string()));
+        }
+        let mut parser = Parser {
+            tokens: tokens.into_iter().peekable(),
+            in_field: false,
+            depth: 0,
+        };
+        let node = parser.parse_or()?;
+        if let Some(token) = parser.tokens.next() {
+            return Err(match token {
+                Token::Close => QueryParseError::UnbalancedParens,
+                token => QueryParseError::UnexpectedToken(token.to_string()),
+            });
+        }
+        if !node.has_positive_clause() {
+            return Err(QueryParseError::OnlyNegative);
+        }
+        Ok(node)
+    }
+
+    /// Whether a document has to match something for this node to match, rather than just not
+    /// matching something. A query without one would match every document.
+    fn has_positive_clause(&self) -> bool {
+        match self {
+            QueryNode::Fuzzy(_)
+            | QueryNode::Term(_)
+            | QueryNode::FuzzyTerm { .. }
+            | QueryNode::Phrase(_)
+            | QueryNode::Wildcard(_)
+            | QueryNode::Regex(_)
+            | QueryNode::Near { .. } => true,
+            QueryNode::And(clauses) => clauses.iter().any(QueryNode::has_positive_clause),
+            QueryNode::Or(clauses) => clauses.iter().all(QueryNode::has_positive_clause),
+            QueryNode::Field { clause, .. } => clause.has_positive_clause(),
+            QueryNode::Not(_) | QueryNode::Compare { .. } | QueryNode::Range { .. } => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Near(u32),
+    Word(&'a str),
+    FuzzyWord(&'a str, u32),
+    Wildcard(&'a str),
+    /// The inside of `/regex/`, still with its escaped slashes
+    Regex(&'a str),
+    Phrase(&'a str),
+    /// `field:`, scoping whatever comes next
+    Field(&'a str),
+    Compare(&'a str, Comparison, &'a str),
+    /// The inside of `[from TO to]`, and whether each end is inclusive
+    Range(&'a str, bool, bool),
+}
+
+impl std::fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::And => write!(f, "AND"),
+            Token::Or => write!(f, "OR"),
+            Token::Not => write!(f, "NOT"),
+            Token::Near(distance) => write!(f, "NEAR/{distance}"),
+            Token::Word(word) => write!(f, "{word}"),
+            Token::FuzzyWord(word, distance) => write!(f, "{word}~{distance}"),
+            Token::Wildcard(word) => write!(f, "{word}"),
+            Token::Regex(regex) => write!(f, "/{regex}/"),
+            Token::Phrase(phrase) => write!(f, "\"{phrase}\""),
+            Token::Field(field) => write!(f, "{field}:"),
+            Token::Compare(field, op, value) => write!(f, "{field}{op}{value}"),
+            Token::Range(range, lower, upper) => {
+                let open = if *lower { '[' } else { '{' };
+                let close = if *upper { ']' } else { '}' };
+                write!(f, "{open}{range}{close}")
+            }
+        }
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token<'_>>, QueryParseError> {
+    let mut tokens = Vec::new();
+    let mut word_start = None;
+    let mut chars = query.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        // Brackets only start a range straight after a `field:`, elsewhere they're part of a word
+        let starts_range = (c == '[' || c == '{')
+            && word_start.is_some_and(|start| query[start..idx].ends_with(':'));
+        // Likewise slashes only start a regex at the start of a word or a `field:`
+        let starts_regex =
+            c == '/' && word_start.is_none_or(|start| query[start..idx].ends_with(':'));
+        if starts_regex {
+            if let Some(start) = word_start.take() {
+                push_word(&mut tokens, &query[start..idx])?;
+            }
+            let regex_start = idx + 1;
+            let mut escaped = false;
+            let (regex_end, _) = chars
+                .find(|&(_, c)| {
+                    let closes = c == '/' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    closes
+                })
+                .ok_or(QueryParseError::UnterminatedRegex)?;
+            tokens.push(Token::Regex(&query[regex_start..regex_end]));
+        } else if starts_range {
+            if let Some(start) = word_start.take() {
+                push_word(&mut tokens, &query[start..idx])?;
+            }
+            let range_start = idx + 1;
+            let (range_end, close) = chars
+                .find(|&(_, c)| c == ']' || c == '}')
+                .ok_or(QueryParseError::UnterminatedRange)?;
+            tokens.push(Token::Range(
+                &query[range_start..range_end],
+                c == '[',
+                close == ']',
+            ));
+        } else if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+            if let Some(start) = word_start.take() {
+                push_word(&mut tokens, &query[start..idx])?;
+            }
+            match c {
+                '(' => tokens.push(Token::Open),
+                ')' => tokens.push(Token::Close),
+                '"' => {
+                    let phrase_start = idx + 1;
+                    let (phrase_end, _) = chars
+                        .find(|&(_, c)| c == '"')
+                        .ok_or(QueryParseError::UnterminatedPhrase)?;
+                    tokens.push(Token::Phrase(&query[phrase_start..phrase_end]));
+                }
+                _ => {}
+            }
+        } else if word_start.is_none() {
+            word_start = Some(idx);
+        }
+    }
+    if let Some(start) = word_start {
+        push_word(&mut tokens, &query[start..])?;
+    }
+    Ok(tokens)
+}
+
+/// Splits a `field:` or comparison off the front of a word, before reading what's left
+fn push_word<'a>(tokens: &mut Vec<Token<'a>>, word: &'a str) -> Result<(), QueryParseError> {
+    let Some(split) = word.find([':', '<', '>']) else {
+        tokens.push(word_token(word)?);
+        return Ok(());
+    };
+    let (field, rest) = word.split_at(split);
+    if !is_field_name(field) {
+        tokens.push(word_token(word)?);
+        return Ok(());
+    }
+    let (op, value) = match rest.split_at(1) {
+        (":", rest) => {
+            tokens.push(Token::Field(field));
+            if !rest.is_empty() {
+                tokens.push(word_token(rest)?);
+            }
+            return Ok(());
+        }
+        (">", value) => match value.strip_prefix('=') {
+            Some(value) => (Comparison::GreaterOrEqual, value),
+            None => (Comparison::Greater, value),
+        },
+        (_, value) => match value.strip_prefix('=') {
+            Some(value) => (Comparison::LessOrEqual, value),
+            None => (Comparison::Less, value),
+        },
+    };
+    if value.is_empty() {
+        return Err(QueryParseError::UnexpectedEnd);
+    }
+    if !crate::metadata::is_comparable(value) {
+        return Err(QueryParseError::InvalidComparison(value.to_string()));
+    }
+    tokens.push(Token::Compare(field, op, value));
+    Ok(())
+}
+
+fn is_field_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+fn word_token(word: &str) -> Result<Token<'_>, QueryParseError> {
+    // Operators are case-sensitive, so "and"/"or"/"not" can still be searched for
+    let token = match word {
+        "AND" => Token::And,
+        "OR" => Token::Or,
+        "NOT" => Token::Not,
+        word => match word.strip_prefix("NEAR/").map(str::parse) {
+            Some(Ok(distance)) => Token::Near(distance),
+            _ => match word.rsplit_once('~') {
+                Some((term, "")) if !term.is_empty() => Token::FuzzyWord(term, MAX_EDIT_DISTANCE),
+                Some((term, distance)) if !term.is_empty() => match distance.parse() {
+                    Ok(distance) if distance > MAX_EDIT_DISTANCE => {
+                        return Err(QueryParseError::EditDistanceTooLarge(distance))
+                    }
+                    Ok(distance) => Token::FuzzyWord(term, distance),
+                    Err(_) => plain_word(word),
+                },
+                _ => plain_word(word),
+            },
+        },
+    };
+    Ok(token)
+}
+
+fn plain_word(word: &str) -> Token<'_> {
+    // A question mark on the end is far more likely to be punctuation than a wildcard
+    match word.contains('*') || word.trim_end_matches('?').contains('?') {
+        true => Token::Wildcard(word),
+        false => Token::Word(word),
+    }
+}
+
+struct Parser<'a> {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token<'a>>>,
+    /// Set while parsing the clause a `field:` applies to
+    in_field: bool,
+    /// How many groups and `NOT`s enclose the clause being parsed
+    depth: usize,
+}
+
+impl Parser<'_> {
+    /// Parses a clause one level deeper, giving up past [`MAX_DEPTH`]
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<QueryNode, QueryParseError>,
+    ) -> Result<QueryNode, QueryParseError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(QueryParseError::TooDeep);
+        }
+        self.depth += 1;
+        let node = parse(self);
+        self.depth -= 1;
+        node
+    }
+
+    fn parse_or(&mut self) -> Result<QueryNode, QueryParseError> {
+        let mut clauses = vec![self.parse_and()?];
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            clauses.push(self.parse_and()?);
+        }
+        Ok(collapse(clauses, QueryNode::Or))
+    }
+
+    fn parse_and(&mut self) -> Result<QueryNode, QueryParseError> {
+        let mut clauses = vec![self.parse_unary()?];
+        loop {
+            match self.tokens.peek() {
+                Some(Token::And) => {
+                    self.tokens.next();
+                    clauses.push(self.parse_unary()?);
+                }
+                // Implicit AND between neighbouring clauses
+                Some(
+                    Token::Word(_)
+                    | Token::FuzzyWord(..)
+                    | Token::Wildcard(_)
+                    | Token::Regex(_)
+                    | Token::Phrase(_)
+                    | Token::Open
+                    | Token::Not
+                    | Token::Field(_)
+                    | Token::Compare(..),
+                ) => clauses.push(self.parse_unary()?),
+                _ => break,
+            }
+        }
+        Ok(collapse(clauses, QueryNode::And))
+    }
+
+    fn parse_unary(&mut self) -> Result<QueryNode, QueryParseError> {
+        if self.tokens.next_if_eq(&Token::Not).is_some() {
+            let negated = self.nested(Self::parse_unary)?;
+            return Ok(QueryNode::Not(Box::new(negated)));
+        }
+        self.parse_near()
+    }
+
+    fn parse_near(&mut self) -> Result<QueryNode, QueryParseError> {
+        let left = self.parse_primary()?;
+        let Some(&Token::Near(distance)) = self.tokens.peek() else {
+            return Ok(left);
+        };
+        self.tokens.next();
+        let right = self.parse_primary()?;
+        let is_positional = |node: &QueryNode| {
+            matches!(
+                node,
+                QueryNode::Term(_) | QueryNode::FuzzyTerm { .. } | QueryNode::Phrase(_)
+            )
+        };
+        if !is_positional(&left) || !is_positional(&right) {
+            return Err(QueryParseError::InvalidNearOperand);
+        }
+        Ok(QueryNode::Near {
+            left: Box::new(left),
+            right: Box::new(right),
+            distance,
+        })
+    }
+
+    fn parse_primary(&mut self) -> Result<QueryNode, QueryParseError> {
+        match self.tokens.next() {
+            Some(Token::Word(word)) => Ok(QueryNode::Term(word.to_string())),
+            Some(Token::FuzzyWord(term, distance)) => Ok(QueryNode::FuzzyTerm {
+                term: term.to_string(),
+                distance,
+            }),
+            Some(Token::Wildcard(word)) => Ok(QueryNode::Wildcard(word.to_string())),
+            // The slashes only needed escaping to get them past the tokenizer
+            Some(Token::Regex(regex)) => Ok(QueryNode::Regex(regex.replace("\\/", "/"))),
+            Some(Token::Phrase(phrase)) => {
+                let words: Vec<_> = phrase.unicode_words().map(str::to_string).collect();
+                match words.len() {
+                    0 => Err(QueryParseError::EmptyPhrase),
+                    // A single word phrase is just a term
+                    1 => Ok(QueryNode::Term(words.into_iter().next().unwrap())),
+                    _ => Ok(QueryNode::Phrase(words)),
+                }
+            }
+            Some(Token::Open) => {
+                let node = self.nested(Self::parse_or)?;
+                match self.tokens.next() {
+                    Some(Token::Close) => Ok(node),
+                    _ => Err(QueryParseError::UnbalancedParens),
+                }
+            }
+            Some(Token::Field(_) | Token::Compare(..)) if self.in_field => {
+                Err(QueryParseError::NestedField)
+            }
+            Some(Token::Field(field)) if matches!(self.tokens.peek(), Some(Token::Range(..))) => {
+                let Some(Token::Range(range, lower, upper)) = self.tokens.next() else {
+                    unreachable!("just peeked a range");
+                };
+                parse_range(field, range, lower, upper)
+            }
+            Some(Token::Field(field)) => {
+                self.in_field = true;
+                let clause = self.parse_primary();
+                self.in_field = false;
+                Ok(QueryNode::Field {
+                    field: field.to_string(),
+                    clause: Box::new(clause?),
+                })
+            }
+            Some(Token::Compare(field, op, value)) => Ok(QueryNode::Compare {
+                field: field.to_string(),
+                op,
+                value: value.to_string(),
+            }),
+            Some(Token::Close) => Err(QueryParseError::UnbalancedParens),
+            Some(token) => Err(QueryParseError::UnexpectedToken(token.to_string())),
+            None => Err(QueryParseError::UnexpectedEnd),
+        }
+    }
+}
+
+fn parse_range(
+    field: &str,
+    range: &str,
+    lower_inclusive: bool,
+    upper_inclusive: bool,
+) -> Result<QueryNode, QueryParseError> {
+    let invalid = || QueryParseError::InvalidRange(range.to_string());
+    let [lower, "TO", upper] = range.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(invalid());
+    };
+    let bound = |value: &str, inclusive| match value {
+        "*" => Ok(None),
+        value if crate::metadata::is_comparable(value) => Ok(Some(RangeBound {
+            value: value.to_string(),
+            inclusive,
+        })),
+        value => Err(QueryParseError::InvalidComparison(value.to_string())),
+    };
+    let (lower, upper) = (
+        bound(lower, lower_inclusive)?,
+        bound(upper, upper_inclusive)?,
+    );
+    if lower.is_none() && upper.is_none() {
+        return Err(invalid());
+    }
+    Ok(QueryNode::Range {
+        field: field.to_string(),
+        lower,
+        upper,
+    })
+}
+
+fn collapse(mut clauses: Vec<QueryNode>, combine: fn(Vec<QueryNode>) -> QueryNode) -> QueryNode {
+    if clauses.len() == 1 {
+        clauses.pop().unwrap()
+    } else {
+        combine(clauses)
+    }
+}
+
+#[cfg(test)]
+mod query_tests {
+    use super::*;
+
+    fn term(word: &str) -> QueryNode {
+        QueryNode::Term(word.to_string())
+    }
+
+    #[test]
+    fn test_plain_text_is_fuzzy() {
+        assert_eq!(
+            QueryNode::parse("prid n prejudice"),
+            Ok(QueryNode::Fuzzy("prid n prejudice".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_grouped_query() {
+        let expected = QueryNode::And(vec![
+            QueryNode::Or(vec![term("outage"), term("incident")]),
+            QueryNode::Not(Box::new(term("test"))),
+        ]);
+        assert_eq!(
+            QueryNode::parse("(outage OR incident) AND NOT test"),
+            Ok(expected)
+        );
+    }
+
+    #[test]
+    fn test_and_binds_tighter_than_or() {
+        let expected = QueryNode::Or(vec![
+            term("a"),
+            QueryNode::And(vec![term("b"), term("c"), term("d")]),
+        ]);
+        assert_eq!(QueryNode::parse("a OR b AND c d"), Ok(expected));
+    }
+
+    #[test]
+    fn test_parse_phrase_and_near() {
+        let expected = QueryNode::And(vec![
+            QueryNode::Phrase(vec!["Mr".to_string(), "Darcy".to_string()]),
+            QueryNode::Near {
+                left: Box::new(term("darcy")),
+                right: Box::new(term("elizabeth")),
+                distance: 5,
+            },
+        ]);
+        assert_eq!(
+            QueryNode::parse("\"Mr. Darcy\" darcy NEAR/5 elizabeth"),
+            Ok(expected)
+        );
+    }
+
+    #[test]
+    fn test_parse_fuzzy_terms() {
+        let fuzzy = |term: &str, distance| QueryNode::FuzzyTerm {
+            term: term.to_string(),
+            distance,
+        };
+        assert_eq!(QueryNode::parse("darcy~1"), Ok(fuzzy("darcy", 1)));
+        assert_eq!(
+            QueryNode::parse("darcy~ NEAR/3 bingley~0"),
+            Ok(QueryNode::Near {
+                left: Box::new(fuzzy("darcy", 2)),
+                right: Box::new(fuzzy("bingley", 0)),
+                distance: 3,
+            })
+        );
+        // Not a valid distance, so just a word
+        assert_eq!(
+            QueryNode::parse("a AND b~c"),
+            Ok(QueryNode::And(vec![term("a"), term("b~c")]))
+        );
+        assert_eq!(
+            QueryNode::parse("darcy~3"),
+            Err(QueryParseError::EditDistanceTooLarge(3))
+        );
+    }
+
+    #[test]
+    fn test_parse_fields_and_comparisons() {
+        let field = |field: &str, clause| QueryNode::Field {
+            field: field.to_string(),
+            clause: Box::new(clause),
+        };
+        let expected = QueryNode::And(vec![
+            field("title", term("darcy")),
+            field("source", QueryNode::Or(vec![term("reuters"), term("ap")])),
+            QueryNode::Compare {
+                field: "published".to_string(),
+                op: Comparison::Greater,
+                value: "2026-01-01".to_string(),
+            },
+        ]);
+        assert_eq!(
+            QueryNode::parse("title:darcy source:(reuters OR ap) AND published>2026-01-01"),
+            Ok(expected)
+        );
+        assert_eq!(
+            QueryNode::parse("title:\"Mr Darcy\" severity<=3"),
+            Ok(QueryNode::And(vec![
+                field(
+                    "title",
+                    QueryNode::Phrase(vec!["Mr".to_string(), "Darcy".to_string()])
+                ),
+                QueryNode::Compare {
+                    field: "severity".to_string(),
+                    op: Comparison::LessOrEqual,
+                    value: "3".to_string(),
+                },
+            ]))
+        );
+        // Timestamps split on the first colon only
+        assert_eq!(
+            QueryNode::parse("published:2026-01-03T09:30:00Z"),
+            Ok(field("published", term("2026-01-03T09:30:00Z")))
+        );
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        let bound = |value: &str, inclusive| {
+            Some(RangeBound {
+                value: value.to_string(),
+                inclusive,
+            })
+        };
+        assert_eq!(
+            QueryNode::parse("outage AND price:[10 TO 20} published:{now-7d TO *]"),
+            Ok(QueryNode::And(vec![
+                term("outage"),
+                QueryNode::Range {
+                    field: "price".to_string(),
+                    lower: bound("10", true),
+                    upper: bound("20", false),
+                },
+                QueryNode::Range {
+                    field: "published".to_string(),
+                    lower: bound("now-7d", false),
+                    upper: None,
+                },
+            ]))
+        );
+        // Brackets anywhere else are just part of a word
+        assert_eq!(
+            QueryNode::parse("a AND [b]"),
+            Ok(QueryNode::And(vec![term("a"), term("[b]")]))
+        );
+        assert_eq!(
+            QueryNode::parse("a AND price:[10 TO 20"),
+            Err(QueryParseError::UnterminatedRange)
+        );
+        assert_eq!(
+            QueryNode::parse("a AND price:[10 20]"),
+            Err(QueryParseError::InvalidRange("10 20".to_string()))
+        );
+        assert_eq!(
+            QueryNode::parse("a AND price:[* TO *]"),
+            Err(QueryParseError::InvalidRange("* TO *".to_string()))
+        );
+        assert_eq!(
+            QueryNode::parse("a AND price:[cheap TO 20]"),
+            Err(QueryParseError::InvalidComparison("cheap".to_string()))
+        );
+        assert_eq!(
+            QueryNode::parse("price:[10 TO 20]"),
+            Err(QueryParseError::OnlyNegative)
+        );
+    }
+
+    #[test]
+    fn test_parse_patterns() {
+        assert_eq!(
+            QueryNode::parse(r"micro* colo?r OR title:/INC-\d{6}/"),
+            Ok(QueryNode::Or(vec![
+                QueryNode::And(vec![
+                    QueryNode::Wildcard("micro*".to_string()),
+                    QueryNode::Wildcard("colo?r".to_string()),
+                ]),
+                QueryNode::Field {
+                    field: "title".to_string(),
+                    clause: Box::new(QueryNode::Regex(r"INC-\d{6}".to_string())),
+                },
+            ]))
+        );
+        assert_eq!(
+            QueryNode::parse(r"/a\/b/ AND w/o"),
+            Ok(QueryNode::And(vec![
+                QueryNode::Regex("a/b".to_string()),
+                term("w/o"),
+            ]))
+        );
+        // Plain questions are still plain text
+        assert_eq!(
+            QueryNode::parse("who is mr darcy?"),
+            Ok(QueryNode::Fuzzy("who is mr darcy?".to_string()))
+        );
+        assert_eq!(
+            QueryNode::parse(r"a AND /INC-\d{6}"),
+            Err(QueryParseError::UnterminatedRegex)
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_queries() {
+        assert_eq!(QueryNode::parse("   "), Err(QueryParseError::Empty));
+        assert_eq!(
+            QueryNode::parse("(a OR b"),
+            Err(QueryParseError::UnbalancedParens)
+        );
+        assert_eq!(
+            QueryNode::parse("a OR b)"),
+            Err(QueryParseError::UnbalancedParens)
+        );
+        assert_eq!(
+            QueryNode::parse("a AND"),
+            Err(QueryParseError::UnexpectedEnd)
+        );
+        assert_eq!(
+            QueryNode::parse("a AND OR b"),
+            Err(QueryParseError::UnexpectedToken("OR".to_string()))
+        );
+        assert_eq!(
+            QueryNode::parse("NOT test"),
+            Err(QueryParseError::OnlyNegative)
+        );
+        assert_eq!(
+            QueryNode::parse("a OR NOT b"),
+            Err(QueryParseError::OnlyNegative)
+        );
+        assert_eq!(
+            QueryNode::parse("\"mr darcy"),
+            Err(QueryParseError::UnterminatedPhrase)
+        );
+        assert_eq!(
+            QueryNode::parse("a \" \""),
+            Err(QueryParseError::EmptyPhrase)
+        );
+        assert_eq!(
+            QueryNode::parse("(a OR b) NEAR/2 c"),
+            Err(QueryParseError::InvalidNearOperand)
+        );
+        assert_eq!(
+            QueryNode::parse("published>2026-01-01"),
+            Err(QueryParseError::OnlyNegative)
+        );
+        assert_eq!(
+            QueryNode::parse("title:(darcy AND source:reuters)"),
+            Err(QueryParseError::NestedField)
+        );
+        assert_eq!(
+            QueryNode::parse("a AND published>yesterday"),
+            Err(QueryParseError::InvalidComparison("yesterday".to_string()))
+        );
+        assert_eq!(
+            QueryNode::parse("a AND title:"),
+            Err(QueryParseError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn test_rejects_deeply_nested_queries() {
+        let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(QueryNode::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            QueryNode::parse(&nested(MAX_DEPTH + 1)),
+            Err(QueryParseError::TooDeep)
+        );
+        // Deep enough to overflow the stack without the limit
+        assert_eq!(
+            QueryNode::parse(&nested(200_000)),
+            Err(QueryParseError::TooDeep)
+        );
+        let negated = format!("a AND {}b", "NOT ".repeat(200_000));
+        assert_eq!(QueryNode::parse(&negated), Err(QueryParseError::TooDeep));
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let query = "(outage OR incident) AND NOT test title:outage severity>=3 price:{10 TO *] micro* /INC-\\d+/";
+        let node = QueryNode::parse(query).unwrap();
+        let bytes = rkyv::to_bytes::<_, 256>(&node).unwrap();
+        assert_eq!(crate::from_archive::<QueryNode>(&bytes).unwrap(), node);
+    }
+}
@@ -0,0 +1,705 @@
+use futures_lite::AsyncWriteExt;
+use glommio::io::{DmaFile, DmaStreamWriter, DmaStreamWriterBuilder};
+use lib::{IndexData, ResultRange, TarkineError};
+use rkyv::{AlignedVec, Deserialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::sync::watch;
+
+/// Segments are rolled over once they grow past this
+const SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+/// Payload length, payload crc32 and query id
+const HEADER_LEN: usize = 16;
+/// Records are padded out to this, so every payload starts aligned for zero-copy reads
+const RECORD_ALIGN: usize = 16;
+/// How many of a query's results in a segment go by between entries in its sparse index
+const INDEX_INTERVAL: usize = 64;
+
+/// Match results, appended to segment files by the search shards and read back in order by the API.
+///
+/// Each shard owns a directory of append-only segment files, written through a [`SegmentWriter`].
+/// A record is a [`HEADER_LEN`] byte header followed by the rkyv archived [`IndexData`], padded out
+/// to [`RECORD_ALIGN`]. Segments are scanned on startup, dropping anything after the first torn or
+/// corrupt record, to rebuild a sparse index of where each query's results are in each segment.
+///
+/// Sequence numbers come from sled's id generator, so they keep increasing across restarts.
+/// Purging a query's results records the sequence number they were purged at, rather than
+/// rewriting segments - anything at or before it is skipped when reading.
+#[derive(Debug, Clone)]
+pub(crate) struct ResultStore {
+    root: PathBuf,
+    db: sled::Db,
+    purged: sled::Tree,
+    index: Arc<RwLock<SegmentIndex>>,
+    // Bumped every time a shard commits results for a query, to wake anything watching it. Only
+    // queries with someone watching have an entry.
+    commits: Arc<Mutex<HashMap<u64, watch::Sender<u64>>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct SegmentId {
+    shard: usize,
+    segment: u64,
+}
+
+#[derive(Debug, Default)]
+struct SegmentIndex {
+    /// Length of the complete, synced records at the start of each segment
+    committed: HashMap<SegmentId, u64>,
+    /// Every segment holding results for a query, in the order they were first written to
+    queries: HashMap<u64, Vec<QuerySegment>>,
+}
+
+/// Where a query's results are in one segment
+#[derive(Debug)]
+struct QuerySegment {
+    id: SegmentId,
+    /// Sequence number and offset of the query's first result in the segment, then of every
+    /// [`INDEX_INTERVAL`]th one after it
+    offsets: Vec<(u64, u64)>,
+    /// How many of the query's results are in the segment
+    count: usize,
+    last_sequence: u64,
+    /// Just past the query's last record in the segment
+    end: u64,
+}
+
+/// A stored result's place in its segment
+struct Location {
+    query_id: u64,
+    sequence: u64,
+    offset: u64,
+    end: u64,
+}
+
+impl SegmentIndex {
+    fn commit(&mut self, id: SegmentId, committed: u64, locations: &[Location]) {
+        self.committed.insert(id, committed);
+        for location in locations {
+            let segments = self.queries.entry(location.query_id).or_default();
+            match segments.last_mut() {
+                Some(segment) if segment.id == id => {
+                    if segment.count % INDEX_INTERVAL == 0 {
+                        segment.offsets.push((location.sequence, location.offset));
+                    }
+                    segment.count += 1;
+                    segment.last_sequence = location.sequence;
+                    segment.end = location.end;
+                }
+                _ => segments.push(QuerySegment {
+                    id,
+                    offsets: vec![(location.sequence, location.offset)],
+                    count: 1,
+                    last_sequence: location.sequence,
+                    end: location.end,
+                }),
+            }
+        }
+    }
+}
+
+impl QuerySegment {
+    /// The byte ranges to read for results after `after`, starting from the last indexed result at
+    /// or before it. Each runs from one indexed result to the next, so a read can stop part way
+    /// through the segment.
+    fn chunks_after(&self, after: Option<u64>) -> Vec<(u64, u64)> {
+        let starts = self.offsets[self.indexed_before(after)..]
+            .iter()
+            .map(|&(_, offset)| offset);
+        starts
+            .clone()
+            .zip(starts.skip(1).chain([self.end]))
+            .collect()
+    }
+
+    fn indexed_before(&self, after: Option<u64>) -> usize {
+        let indexed = match after {
+            Some(after) => self
+                .offsets
+                .partition_point(|&(sequence, _)| sequence <= after),
+            None => 0,
+        };
+        indexed.saturating_sub(1)
+    }
+}
+
+impl ResultStore {
+    /// Opens the segments under `root`, including those of shards beyond `shard_count` left behind
+    /// by an earlier run, so their results stay readable.
+    pub(crate) fn open(
+        root: PathBuf,
+        db: &sled::Db,
+        shard_count: usize,
+    ) -> Result<Self, TarkineError> {
+        for shard in 0..shard_count {
+            std::fs::create_dir_all(shard_path(&root, shard))?;
+        }
+        let mut index = SegmentIndex::default();
+        for shard_dir in std::fs::read_dir(&root)? {
+            let shard_dir = shard_dir?;
+            let name = shard_dir.file_name();
+            let Some(shard) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("shard-"))
+                .and_then(|shard| shard.parse().ok())
+            else {
+                continue;
+            };
+            for segment_file in std::fs::read_dir(shard_dir.path())? {
+                let path = segment_file?.path();
+                let Some(segment) = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_suffix(".seg"))
+                    .and_then(|segment| segment.parse().ok())
+                else {
+                    continue;
+                };
+                scan_segment(&path, SegmentId { shard, segment }, &mut index)?;
+            }
+        }
+        tracing::info!(
+            message = "Opened result segments",
+            segments = index.committed.len(),
+            queries = index.queries.len()
+        );
+        Ok(Self {
+            root,
+            db: db.clone(),
+            purged: db.open_tree("purged_results")?,
+            index: Arc::new(RwLock::new(index)),
+            commits: Arc::default(),
+        })
+    }
+
+    /// Notifies the receiver whenever a shard commits new results for `query_id`
+    pub(crate) fn subscribe(&self, query_id: u64) -> watch::Receiver<u64> {
+        self.commits
+            .lock()
+            .expect("Result commits lock poisoned")
+            .entry(query_id)
+            .or_insert_with(|| watch::channel(0).0)
+            .subscribe()
+    }
+
+    /// Wakes whatever is watching the queries that just had results committed, and forgets the
+    /// queries nothing is watching any more
+    fn notify(&self, query_ids: impl IntoIterator<Item = u64>) {
+        let mut commits = self.commits.lock().expect("Result commits lock poisoned");
+        for query_id in query_ids {
+            if let Some(sender) = commits.get(&query_id) {
+                sender.send_modify(|commits| *commits += 1);
+            }
+        }
+        commits.retain(|_, sender| sender.receiver_count() > 0);
+    }
+
+    /// Starts a fresh segment for `shard`. Has to be called from the shard's glommio executor.
+    pub(crate) async fn writer(&self, shard: usize) -> Result<SegmentWriter, TarkineError> {
+        let segment = self
+            .index
+            .read()
+            .expect("Segment index lock poisoned")
+            .committed
+            .keys()
+            .filter(|id| id.shard == shard)
+            .map(|id| id.segment + 1)
+            .max()
+            .unwrap_or_default();
+        let id = SegmentId { shard, segment };
+        let writer = self.create_segment(id).await?;
+        Ok(SegmentWriter {
+            store: self.clone(),
+            id,
+            writer,
+        })
+    }
+
+    async fn create_segment(&self, id: SegmentId) -> Result<DmaStreamWriter, TarkineError> {
+        let file = DmaFile::create(self.segment_path(id))
+            .await
+            .map_err(std::io::Error::from)?;
+        self.index
+            .write()
+            .expect("Segment index lock poisoned")
+            .commit(id, 0, &[]);
+        Ok(DmaStreamWriterBuilder::new(file).build())
+    }
+
+    fn segment_path(&self, id: SegmentId) -> PathBuf {
+        let mut path = shard_path(&self.root, id.shard);
+        path.push(format!("{:020}.seg", id.segment));
+        path
+    }
+
+    /// Hides every result stored for a query so far. Their records stay in the segments, but are
+    /// never read back, while results stored later - should the id be reused - are unaffected.
+    pub(crate) fn purge(&self, query_id: u64) -> Result<(), TarkineError> {
+        let purged_at = self.db.generate_id()?;
+        self.purged
+            .insert(query_id.to_ne_bytes(), &purged_at.to_le_bytes())?;
+        self.index
+            .write()
+            .expect("Segment index lock poisoned")
+            .queries
+            .remove(&query_id);
+        Ok(())
+    }
+
+    fn purged_at(&self, query_id: u64) -> Result<Option<u64>, TarkineError> {
+        Ok(self
+            .purged
+            .get(query_id.to_ne_bytes())?
+            .and_then(|raw| Some(u64::from_le_bytes(raw.as_ref().try_into().ok()?))))
+    }
+
+    /// Reads a query's results in the order they were stored.
+    ///
+    /// Only the segments the sparse index lists for the query with results after `after` are read,
+    /// starting from the nearest indexed result at or before `after`. Records for other queries are
+    /// skipped on their header alone. Sequence numbers increase within a segment, so a segment is
+    /// read an indexed stretch at a time until it has given up `limit` results, and segments are
+    /// read in order of their first result until the later ones can only hold results after those
+    /// already found.
+    pub(crate) fn range(
+        &self,
+        query_id: u64,
+        range: &ResultRange,
+    ) -> Result<Vec<IndexData>, TarkineError> {
+        let limit = range.limit.map_or(usize::MAX, |limit| limit as usize);
+        self.read(query_id, range.after, u64::MAX, limit, |_| true)
+    }
+
+    /// Reads just the results of a query with the given sequence numbers, in order. Reading stops
+    /// at the largest of them, and only those asked for are deserialized.
+    pub(crate) fn lookup(
+        &self,
+        query_id: u64,
+        sequences: &BTreeSet<u64>,
+    ) -> Result<Vec<IndexData>, TarkineError> {
+        let (Some(&first), Some(&last)) = (sequences.first(), sequences.last()) else {
+            return Ok(Vec::new());
+        };
+        self.read(
+            query_id,
+            first.checked_sub(1),
+            last,
+            usize::MAX,
+            |sequence| sequences.contains(&sequence),
+        )
+    }
+
+    /// Reads up to `limit` of a query's results with sequence numbers after `after` and up to
+    /// `until` that are `wanted`
+    fn read(
+        &self,
+        query_id: u64,
+        after: Option<u64>,
+        until: u64,
+        limit: usize,
+        wanted: impl Fn(u64) -> bool,
+    ) -> Result<Vec<IndexData>, TarkineError> {
+        let after = after.max(self.purged_at(query_id)?);
+        let mut segments = {
+            let index = self.index.read().expect("Segment index lock poisoned");
+            index
+                .queries
+                .get(&query_id)
+                .into_iter()
+                .flatten()
+                .filter(|segment| after.is_none_or(|after| segment.last_sequence > after))
+                .filter(|segment| segment.offsets[0].0 <= until)
+                .map(|segment| {
+                    (
+                        segment.offsets[0].0,
+                        segment.id,
+                        segment.chunks_after(after),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        segments.sort_unstable_by_key(|&(first, ..)| first);
+        let mut results = Vec::<IndexData>::new();
+        for (first, id, chunks) in segments {
+            if results.len() >= limit && results.last().is_some_and(|last| last.sequence < first) {
+                break;
+            }
+            let path = self.segment_path(id);
+            let mut found = 0;
+            'segment: for (start, end) in chunks {
+                if found >= limit {
+                    break;
+                }
+                let chunk = read_aligned(&path, start, end)?;
+                let mut offset = 0;
+                while let Some((record_query, payload, next)) = next_record(&chunk, offset) {
+                    offset = next;
+                    if record_query != query_id {
+                        continue;
+                    }
+                    let result = validate_record(payload)?;
+                    if result.sequence > until {
+                        break 'segment;
+                    }
+                    if after.is_none_or(|after| result.sequence > after) && wanted(result.sequence)
+                    {
+                        results.push(
+                            result
+                                .deserialize(&mut rkyv::Infallible)
+                                .map_err(|_e| TarkineError::Parsing)?,
+                        );
+                        found += 1;
+                    }
+                }
+            }
+            results.sort_unstable_by_key(|result| result.sequence);
+            results.truncate(limit);
+        }
+        Ok(results)
+    }
+}
+
+/// Appends results to a shard's current segment, rolling over to a new one once it's full
+pub(crate) struct SegmentWriter {
+    store: ResultStore,
+    id: SegmentId,
+    writer: DmaStreamWriter,
+}
+
+impl SegmentWriter {
+    /// Appends a document's results, filling in their sequence numbers. They're synced to disk
+    /// before becoming visible to readers.
+    pub(crate) async fn append(&mut self, results: &mut [IndexData]) -> Result<(), TarkineError> {
+        if self.writer.current_pos() >= SEGMENT_BYTES {
+            self.roll().await?;
+        }
+        let mut locations = Vec::with_capacity(results.len());
+        for result in results.iter_mut() {
+            result.sequence = self.store.db.generate_id()?;
+            let offset = self.writer.current_pos();
+            self.writer.write_all(&encode_record(result)?).await?;
+            locations.push(Location {
+                query_id: result.source_query,
+                sequence: result.sequence,
+                offset,
+                end: self.writer.current_pos(),
+            });
+        }
+        let committed = self.writer.current_pos();
+        self.writer.sync().await.map_err(std::io::Error::from)?;
+        self.store
+            .index
+            .write()
+            .expect("Segment index lock poisoned")
+            .commit(self.id, committed, &locations);
+        self.store
+            .notify(locations.iter().map(|location| location.query_id));
+        Ok(())
+    }
+
+    async fn roll(&mut self) -> Result<(), TarkineError> {
+        self.writer.close().await?;
+        self.id.segment += 1;
+        self.writer = self.store.create_segment(self.id).await?;
+        tracing::info!(
+            message = "Rolled over result segment",
+            shard = self.id.shard,
+            segment = self.id.segment
+        );
+        Ok(())
+    }
+}
+
+fn shard_path(root: &Path, shard: usize) -> PathBuf {
+    root.join(format!("shard-{shard}"))
+}
+
+fn encode_record(result: &IndexData) -> Result<Vec<u8>, TarkineError> {
+    let payload = rkyv::to_bytes::<_, 1024>(result).map_err(|_e| TarkineError::Parsing)?;
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len() + RECORD_ALIGN);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&result.source_query.to_le_bytes());
+    record.extend_from_slice(&payload);
+    record.resize(padded(record.len()), 0);
+    Ok(record)
+}
+
+fn padded(len: usize) -> usize {
+    len.div_ceil(RECORD_ALIGN) * RECORD_ALIGN
+}
+
+/// Splits the record at `offset` into its query id and payload, along with where the next record
+/// starts. Returns `None` at the end of the segment, including any padding or torn write after the
+/// last complete record. The payload is checked against its crc, but not validated as an archive.
+fn next_record(segment: &[u8], offset: usize) -> Option<(u64, &[u8], usize)> {
+    let header = segment.get(offset..offset + HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let query_id = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    if len == 0 {
+        return None;
+    }
+    let payload_start = offset + HEADER_LEN;
+    let payload = segment.get(payload_start..payload_start + len)?;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    // Checked here rather than in `validate_record`, as a bad crc marks the end of the segment
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    Some((query_id, payload, padded(payload_start + len)))
+}
+
+/// Validates a record's payload in place, without copying it out of the segment
+fn validate_record(payload: &[u8]) -> Result<&rkyv::Archived<IndexData>, TarkineError> {
+    rkyv::check_archived_root::<IndexData>(payload).map_err(|_e| {
+        tracing::error!(message = "Failed to validate result record");
+        TarkineError::Parsing
+    })
+}
+
+/// Reads `start..end` of a segment into a buffer aligned for rkyv
+fn read_aligned(path: &Path, start: u64, end: u64) -> Result<AlignedVec, TarkineError> {
+    let mut buffer = AlignedVec::with_capacity((end - start) as usize);
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    std::io::copy(&mut file.take(end - start), &mut buffer)?;
+    Ok(buffer)
+}
+
+/// Indexes every valid record in a segment, stopping at the first torn or corrupt one
+fn scan_segment(path: &Path, id: SegmentId, index: &mut SegmentIndex) -> Result<(), TarkineError> {
+    let len = std::fs::metadata(path)?.len();
+    if len == 0 {
+        // Created by a shard that never got to write anything
+        std::fs::remove_file(path)?;
+        return Ok(());
+    }
+    let segment = read_aligned(path, 0, len)?;
+    let mut locations = Vec::new();
+    let mut offset = 0;
+    while let Some((query_id, payload, next)) = next_record(&segment, offset) {
+        let Ok(result) = validate_record(payload) else {
+            break;
+        };
+        locations.push(Location {
+            query_id,
+            sequence: result.sequence,
+            offset: offset as u64,
+            end: next as u64,
+        });
+        offset = next;
+    }
+    // A clean shutdown still leaves the zero padding from the last sync behind
+    if segment[offset..].iter().any(|&byte| byte != 0) {
+        tracing::warn!(
+            message = "Ignoring incomplete tail of result segment",
+            ?path,
+            valid_len = offset,
+            len
+        );
+    }
+    index.commit(id, offset as u64, &locations);
+    Ok(())
+}
+
+#[cfg(test)]
+mod results_tests {
+    use super::*;
+    use glommio::LocalExecutorBuilder;
+
+    fn result(query_id: u64, document_id: u64) -> IndexData {
+        IndexData {
+            source_query: query_id,
+            key: 0,
+            sequence: 0,
+            document_id,
+            name: format!("doc-{document_id}"),
+            match_indices: vec![[0, 1]],
+            score: 10.0,
+            scoring: lib::scoring::ScoringModel::Skim,
+            backfilled: false,
+        }
+    }
+
+    fn document_ids(results: &[IndexData]) -> Vec<u64> {
+        results.iter().map(|r| r.document_id).collect()
+    }
+
+    #[test]
+    fn test_results_read_back_in_order_and_by_range() {
+        let root = std::env::temp_dir().join(format!("tarkine-results-{}", rand::random::<u64>()));
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = ResultStore::open(root.clone(), &db, 2).unwrap();
+        let writer_store = store.clone();
+        let (mut commits, mut other_commits) = (store.subscribe(1), store.subscribe(2));
+        LocalExecutorBuilder::default()
+            .spawn(move || async move {
+                let mut shard_0 = writer_store.writer(0).await.unwrap();
+                let mut shard_1 = writer_store.writer(1).await.unwrap();
+                // Query 1 moves between shards part way through, as if the shard count changed
+                shard_0
+                    .append(&mut [result(1, 10), result(2, 20)])
+                    .await
+                    .unwrap();
+                shard_1.append(&mut [result(1, 11)]).await.unwrap();
+                shard_0.roll().await.unwrap();
+                shard_0.append(&mut [result(1, 12)]).await.unwrap();
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert_eq!(*commits.borrow_and_update(), 3);
+        // Only woken by the commit that had a result for it
+        assert_eq!(*other_commits.borrow_and_update(), 1);
+        let all = store.range(1, &ResultRange::default()).unwrap();
+        assert_eq!(document_ids(&all), [10, 11, 12]);
+        assert!(all.windows(2).all(|w| w[0].sequence < w[1].sequence));
+        let page = ResultRange {
+            after: Some(all[0].sequence),
+            limit: Some(1),
+        };
+        assert_eq!(document_ids(&store.range(1, &page).unwrap()), [11]);
+        assert!(store.range(3, &ResultRange::default()).unwrap().is_empty());
+
+        // A torn write at the end of a segment is dropped when the segments are reopened
+        let segment = store.segment_path(SegmentId {
+            shard: 0,
+            segment: 1,
+        });
+        let mut torn = std::fs::read(&segment).unwrap();
+        torn.extend_from_slice(&encode_record(&result(1, 13)).unwrap()[..20]);
+        std::fs::write(&segment, torn).unwrap();
+        let reopened = ResultStore::open(root.clone(), &db, 2).unwrap();
+        assert_eq!(
+            document_ids(&reopened.range(1, &ResultRange::default()).unwrap()),
+            [10, 11, 12]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_reads_start_near_the_cursor() {
+        let root = std::env::temp_dir().join(format!("tarkine-results-{}", rand::random::<u64>()));
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = ResultStore::open(root.clone(), &db, 1).unwrap();
+        let writer_store = store.clone();
+        LocalExecutorBuilder::default()
+            .spawn(move || async move {
+                let mut writer = writer_store.writer(0).await.unwrap();
+                for document_id in 0..200 {
+                    let mut results = [result(1, document_id), result(2, document_id)];
+                    writer.append(&mut results).await.unwrap();
+                }
+                writer.roll().await.unwrap();
+                writer.append(&mut [result(1, 200)]).await.unwrap();
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let all = store.range(1, &ResultRange::default()).unwrap();
+        assert_eq!(document_ids(&all), (0..=200).collect::<Vec<_>>());
+        {
+            let index = store.index.read().unwrap();
+            let segments = &index.queries[&1];
+            assert_eq!(segments.len(), 2);
+            let indexed = segments[0].offsets.iter().map(|&(sequence, _)| sequence);
+            let expected = [0, 64, 128, 192].map(|idx| all[idx].sequence);
+            assert_eq!(indexed.collect::<Vec<_>>(), expected);
+            // Starts from the indexed result before the cursor, then reads on an indexed stretch at
+            // a time
+            let offset = |idx: usize| segments[0].offsets[idx].1;
+            assert_eq!(
+                segments[0].chunks_after(Some(all[150].sequence)),
+                [(offset(2), offset(3)), (offset(3), segments[0].end)]
+            );
+            assert_eq!(segments[0].chunks_after(None)[0].0, offset(0));
+            assert_eq!(segments[0].last_sequence, all[199].sequence);
+        }
+        let after = |idx: usize| ResultRange {
+            after: Some(all[idx].sequence),
+            limit: None,
+        };
+        assert_eq!(
+            document_ids(&store.range(1, &after(150)).unwrap()),
+            (151..=200).collect::<Vec<_>>()
+        );
+        assert_eq!(document_ids(&store.range(1, &after(199)).unwrap()), [200]);
+        assert!(store.range(1, &after(200)).unwrap().is_empty());
+        let page = |idx: usize, limit: u32| ResultRange {
+            after: Some(all[idx].sequence),
+            limit: Some(limit),
+        };
+        assert_eq!(
+            document_ids(&store.range(1, &page(10, 5)).unwrap()),
+            [11, 12, 13, 14, 15]
+        );
+        // Carries on into the next segment when this one runs out
+        assert_eq!(
+            document_ids(&store.range(1, &page(197, 3)).unwrap()),
+            [198, 199, 200]
+        );
+        // Looks up just the results asked for
+        let wanted = BTreeSet::from([all[3].sequence, all[130].sequence, all[200].sequence]);
+        assert_eq!(
+            document_ids(&store.lookup(1, &wanted).unwrap()),
+            [3, 130, 200]
+        );
+
+        // The index comes out the same when rebuilt from the segments
+        let reopened = ResultStore::open(root.clone(), &db, 1).unwrap();
+        assert_eq!(
+            document_ids(&reopened.range(1, &after(150)).unwrap()),
+            (151..=200).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            reopened.index.read().unwrap().queries[&1][0].offsets.len(),
+            4
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_purged_results_stay_hidden() {
+        let root = std::env::temp_dir().join(format!("tarkine-results-{}", rand::random::<u64>()));
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = ResultStore::open(root.clone(), &db, 1).unwrap();
+        let append = |store: &ResultStore, results: Vec<IndexData>| {
+            let store = store.clone();
+            LocalExecutorBuilder::default()
+                .spawn(move || async move {
+                    let mut writer = store.writer(0).await.unwrap();
+                    writer.append(&mut results.clone()).await.unwrap();
+                })
+                .unwrap()
+                .join()
+                .unwrap();
+        };
+        append(&store, vec![result(1, 10), result(2, 20)]);
+        store.purge(1).unwrap();
+        assert!(store.range(1, &ResultRange::default()).unwrap().is_empty());
+        assert_eq!(
+            document_ids(&store.range(2, &ResultRange::default()).unwrap()),
+            [20]
+        );
+
+        // Still hidden after a restart, but results stored later under the same id aren't
+        let reopened = ResultStore::open(root.clone(), &db, 1).unwrap();
+        assert!(reopened
+            .range(1, &ResultRange::default())
+            .unwrap()
+            .is_empty());
+        append(&reopened, vec![result(1, 11)]);
+        assert_eq!(
+            document_ids(&reopened.range(1, &ResultRange::default()).unwrap()),
+            [11]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+use futures_lite::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use glommio::io::{BufferedFile, StreamReaderBuilder, StreamWriter, StreamWriterBuilder};
+use lib::{TarkineError, TextSource};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::shard::shard_for;
+
+/// Segments are rolled over once they grow past this, or past an eighth of a shard's share of the
+/// byte limit if that's smaller
+const SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+/// Roughly how many segments make up a full window, and so how much past the limits is kept
+/// before the oldest segment is dropped
+const SEGMENTS_PER_WINDOW: u64 = 8;
+
+/// How much of the document stream to keep around for backfilling new queries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RetentionLimits {
+    /// Across every shard
+    pub(crate) max_bytes: u64,
+    pub(crate) max_age: Option<Duration>,
+}
+
+/// Recently searched documents, kept on disk by the search shards so new queries can be run over
+/// them.
+///
+/// Each document is kept by just one shard, picked by hashing its id the same way queries are
+/// spread over shards, in a directory of append-only JSONL segment files. Whole segments are
+/// dropped, oldest first, once a shard holds more than its share of the byte limit or their newest
+/// document is older than the age limit, so retention is only as precise as a segment.
+///
+/// Segments are read and removed through glommio, a line at a time, so backfilling doesn't hold up
+/// the shard's other work. Scanning what an earlier run left behind happens on glommio's blocking
+/// thread pool.
+#[derive(Debug, Clone)]
+pub(crate) struct DocumentRetention {
+    root: PathBuf,
+    limits: RetentionLimits,
+    shard_count: usize,
+}
+
+impl DocumentRetention {
+    pub(crate) fn new(root: PathBuf, limits: RetentionLimits, shard_count: usize) -> Self {
+        Self {
+            root,
+            limits,
+            shard_count,
+        }
+    }
+
+    /// Opens a shard's buffer, starting a fresh segment after any left by an earlier run. Has to be
+    /// called from the shard's glommio executor.
+    pub(crate) async fn buffer(&self, shard: usize) -> Result<RetentionBuffer, TarkineError> {
+        let dir = self.root.join(format!("shard-{shard}"));
+        let segments = {
+            let dir = dir.clone();
+            glommio::executor()
+                .spawn_blocking(move || scan_segments(&dir))
+                .await?
+        };
+        let next_id = segments.last().map_or(0, |segment| segment.id + 1);
+        let max_bytes = self.limits.max_bytes / self.shard_count as u64;
+        let mut buffer = RetentionBuffer {
+            dir,
+            shard,
+            shard_count: self.shard_count,
+            max_bytes,
+            max_age: self.limits.max_age,
+            segment_bytes: (max_bytes / SEGMENTS_PER_WINDOW).clamp(1, SEGMENT_BYTES),
+            segments: segments.into(),
+            writer: None,
+        };
+        buffer.open_segment(next_id).await?;
+        buffer.evict(now()).await?;
+        tracing::info!(
+            message = "Opened document retention",
+            shard,
+            segments = buffer.segments.len(),
+            bytes = buffer.segments.iter().map(|s| s.bytes).sum::<u64>()
+        );
+        Ok(buffer)
+    }
+}
+
+/// A single shard's retained documents
+pub(crate) struct RetentionBuffer {
+    dir: PathBuf,
+    shard: usize,
+    shard_count: usize,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    segment_bytes: u64,
+    // Oldest first, the last one is being written to
+    segments: VecDeque<Segment>,
+    writer: Option<StreamWriter>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    /// Length of the complete records written to it
+    bytes: u64,
+    /// When its first and last documents were retained, in milliseconds since the epoch
+    first: i64,
+    last: i64,
+}
+
+#[derive(Serialize)]
+struct RetainedRef<'a> {
+    retained_at: i64,
+    document: &'a TextSource,
+}
+
+#[derive(Deserialize)]
+struct Retained {
+    retained_at: i64,
+    document: TextSource,
+}
+
+impl RetentionBuffer {
+    /// Keeps a document, if it's this shard's to keep
+    pub(crate) async fn retain(&mut self, document: &TextSource) -> Result<(), TarkineError> {
+        self.retain_at(document, now()).await
+    }
+
+    async fn retain_at(&mut self, document: &TextSource, now: i64) -> Result<(), TarkineError> {
+        if shard_for(document.id, self.shard_count) != self.shard {
+            return Ok(());
+        }
+        let mut record = serde_json::to_vec(&RetainedRef {
+            retained_at: now,
+            document,
+        })
+        .map_err(|_e| TarkineError::Parsing)?;
+        record.push(b'\n');
+        let current = self.segments.back().expect("No segment to retain into");
+        let expired = self.max_age.is_some_and(|max_age| {
+            now - current.first > max_age_millis(max_age) / SEGMENTS_PER_WINDOW as i64
+        });
+        if current.bytes > 0
+            && (current.bytes + record.len() as u64 > self.segment_bytes || expired)
+        {
+            self.open_segment(current.id + 1).await?;
+        }
+        let writer = self.writer.as_mut().expect("No segment writer");
+        writer.write_all(&record).await?;
+        let current = self.segments.back_mut().expect("No segment to retain into");
+        if current.bytes == 0 {
+            current.first = now;
+        }
+        current.bytes += record.len() as u64;
+        current.last = now;
+        self.evict(now).await
+    }
+
+    /// Reads back every document still within the window, oldest first
+    pub(crate) async fn documents(&mut self) -> Result<Vec<TextSource>, TarkineError> {
+        self.documents_at(now()).await
+    }
+
+    async fn documents_at(&mut self, now: i64) -> Result<Vec<TextSource>, TarkineError> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush().await?;
+        }
+        let oldest = self
+            .max_age
+            .map_or(i64::MIN, |max_age| now - max_age_millis(max_age));
+        let mut documents = Vec::new();
+        for segment in &self.segments {
+            let file = BufferedFile::open(segment_path(&self.dir, segment.id))
+                .await
+                .map_err(std::io::Error::from)?;
+            let mut reader = StreamReaderBuilder::new(file).build();
+            let mut lines = (&mut reader).take(segment.bytes);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if lines.read_until(b'\n', &mut line).await? == 0 {
+                    break;
+                }
+                let Some(record) = records(&line).next() else {
+                    break;
+                };
+                if record.retained_at >= oldest {
+                    documents.push(record.document);
+                }
+                glommio::yield_if_needed().await;
+            }
+            reader.close().await.map_err(std::io::Error::from)?;
+        }
+        Ok(documents)
+    }
+
+    async fn open_segment(&mut self, id: u64) -> Result<(), TarkineError> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.close().await?;
+        }
+        let file = BufferedFile::create(segment_path(&self.dir, id))
+            .await
+            .map_err(std::io::Error::from)?;
+        self.writer = Some(StreamWriterBuilder::new(file).build());
+        self.segments.push_back(Segment {
+            id,
+            bytes: 0,
+            first: 0,
+            last: 0,
+        });
+        Ok(())
+    }
+
+    /// Drops the oldest segments while over the byte limit, or while entirely older than the age
+    /// limit. The one being written to is always kept.
+    async fn evict(&mut self, now: i64) -> Result<(), TarkineError> {
+        while self.segments.len() > 1 {
+            let total = self.segments.iter().map(|s| s.bytes).sum::<u64>();
+            let oldest = &self.segments[0];
+            let expired = self
+                .max_age
+                .is_some_and(|max_age| now - oldest.last > max_age_millis(max_age));
+            if total <= self.max_bytes && !expired {
+                break;
+            }
+            glommio::io::remove(segment_path(&self.dir, oldest.id))
+                .await
+                .map_err(std::io::Error::from)?;
+            tracing::debug!(
+                message = "Dropped retained documents",
+                shard = self.shard,
+                segment = oldest.id,
+                bytes = oldest.bytes
+            );
+            self.segments.pop_front();
+        }
+        Ok(())
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn max_age_millis(max_age: Duration) -> i64 {
+    max_age.as_millis().try_into().unwrap_or(i64::MAX)
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:020}.jsonl"))
+}
+
+/// Parses records up to the first torn or corrupt line, which marks the end of the segment
+fn records(contents: &[u8]) -> impl Iterator<Item = Retained> + '_ {
+    contents
+        .split_inclusive(|&byte| byte == b'\n')
+        .map_while(|line| {
+            let line = line.strip_suffix(b"\n")?;
+            serde_json::from_slice::<Retained>(line).ok()
+        })
+}
+
+/// Measures the segments in a shard's directory left behind by an earlier run, oldest first,
+/// removing any without a valid record
+fn scan_segments(dir: &Path) -> Result<Vec<Segment>, TarkineError> {
+    std::fs::create_dir_all(dir)?;
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(id) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|id| id.parse().ok())
+        else {
+            continue;
+        };
+        match scan_segment(&path, id)? {
+            Some(segment) => segments.push(segment),
+            None => std::fs::remove_file(&path)?,
+        }
+    }
+    segments.sort_unstable_by_key(|segment| segment.id);
+    Ok(segments)
+}
+
+/// Measures the valid records in a segment left behind by an earlier run, or returns `None` if it
+/// has none
+fn scan_segment(path: &Path, id: u64) -> Result<Option<Segment>, TarkineError> {
+    let contents = std::fs::read(path)?;
+    let mut segment = Segment {
+        id,
+        bytes: 0,
+        first: 0,
+        last: 0,
+    };
+    for line in contents.split_inclusive(|&byte| byte == b'\n') {
+        let Some(record) = records(line).next() else {
+            break;
+        };
+        if segment.bytes == 0 {
+            segment.first = record.retained_at;
+        }
+        segment.bytes += line.len() as u64;
+        segment.last = record.retained_at;
+    }
+    if segment.bytes < contents.len() as u64 {
+        tracing::warn!(
+            message = "Ignoring incomplete tail of retained documents",
+            ?path,
+            valid_len = segment.bytes,
+            len = contents.len()
+        );
+    }
+    Ok((segment.bytes > 0).then_some(segment))
+}
+
+#[cfg(test)]
+mod retention_tests {
+    use super::*;
+    use glommio::LocalExecutorBuilder;
+
+    fn names(documents: &[TextSource]) -> Vec<&str> {
+        documents.iter().map(|doc| doc.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_retained_documents_read_back_and_expire() {
+        let root = std::env::temp_dir().join(format!("tarkine-retained-{}", rand::random::<u64>()));
+        let limits = RetentionLimits {
+            max_bytes: 1024 * 1024,
+            max_age: Some(Duration::from_secs(80)),
+        };
+        let retention = DocumentRetention::new(root.clone(), limits, 1);
+        LocalExecutorBuilder::default()
+            .spawn(move || async move {
+                let mut buffer = retention.buffer(0).await.unwrap();
+                let start = now();
+                for (idx, name) in ["first", "second", "third"].into_iter().enumerate() {
+                    let document = TextSource::from_content("text", name.to_string());
+                    let retained_at = start + idx as i64 * 30_000;
+                    buffer.retain_at(&document, retained_at).await.unwrap();
+                }
+                // Ten seconds a segment, so each document got one of its own
+                assert_eq!(buffer.segments.len(), 3);
+                let documents = buffer.documents_at(start + 60_000).await.unwrap();
+                assert_eq!(names(&documents), ["first", "second", "third"]);
+                // Past the window, but still on disk until something else is retained
+                let documents = buffer.documents_at(start + 90_000).await.unwrap();
+                assert_eq!(names(&documents), ["second", "third"]);
+                let fourth = TextSource::from_content("text", "fourth".to_string());
+                buffer.retain_at(&fourth, start + 90_000).await.unwrap();
+                assert_eq!(buffer.segments.len(), 3);
+                let documents = buffer.documents_at(start + 90_000).await.unwrap();
+                assert_eq!(names(&documents), ["second", "third", "fourth"]);
+
+                // Reopening keeps what's on disk, dropping a torn last line
+                drop(buffer);
+                let last = segment_path(&root.join("shard-0"), 3);
+                let mut torn = std::fs::read(&last).unwrap();
+                torn.extend_from_slice(b"{\"retained_at\": 1");
+                std::fs::write(&last, torn).unwrap();
+                let mut buffer = retention.buffer(0).await.unwrap();
+                let documents = buffer.documents_at(start + 90_000).await.unwrap();
+                assert_eq!(names(&documents), ["second", "third", "fourth"]);
+                std::fs::remove_dir_all(root).unwrap();
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_retention_bounded_by_size_and_shard() {
+        let root = std::env::temp_dir().join(format!("tarkine-retained-{}", rand::random::<u64>()));
+        let limits = RetentionLimits {
+            max_bytes: 2 * 8 * 1024,
+            max_age: None,
+        };
+        let retention = DocumentRetention::new(root.clone(), limits, 2);
+        LocalExecutorBuilder::default()
+            .spawn(move || async move {
+                let mut buffers = [
+                    retention.buffer(0).await.unwrap(),
+                    retention.buffer(1).await.unwrap(),
+                ];
+                let documents = (0..200)
+                    .map(|idx| TextSource::from_content("x".repeat(200), format!("doc-{idx}")))
+                    .collect::<Vec<_>>();
+                for document in &documents {
+                    for buffer in &mut buffers {
+                        buffer.retain(document).await.unwrap();
+                    }
+                }
+                let mut kept = Vec::new();
+                for buffer in &mut buffers {
+                    let bytes = buffer.segments.iter().map(|s| s.bytes).sum::<u64>();
+                    assert!(bytes <= 8 * 1024 + buffer.segment_bytes, "{bytes}");
+                    kept.extend(buffer.documents().await.unwrap());
+                }
+                // Each document is kept by one shard, and only the newest are kept at all
+                let mut ids = kept.iter().map(|doc| doc.id).collect::<Vec<_>>();
+                ids.sort_unstable();
+                ids.dedup();
+                assert_eq!(ids.len(), kept.len());
+                assert!(kept.len() < documents.len());
+                assert!(kept.iter().any(|doc| doc.id == documents[199].id));
+                assert!(kept.iter().all(|doc| doc.id != documents[0].id));
+                std::fs::remove_dir_all(root).unwrap();
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
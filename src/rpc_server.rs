@@ -1,10 +1,17 @@
 use futures::{self, StreamExt};
-use lib::{IndexData, PersistentQuery, Splinter, TarkineError, TextSource};
+use lib::{
+    Calibration, CalibrationOptions, DryRun, Explanation, IndexData, PersistentQuery, QueryPage,
+    QueryUpdate, ResultCursor, ResultLabel, ResultRange, Splinter, TarkineError, TextSource,
+    TrialQuery,
+};
 use rand::{
     distributions::{Distribution, Uniform},
     thread_rng,
 };
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
 use tarpc::{
     context,
     server::{self, incoming::Incoming, Channel},
@@ -13,27 +20,16 @@ use tarpc::{
 use tokio::time;
 use tracing::instrument;
 
+use crate::state::NodeState;
+
 #[derive(Debug, Clone)]
 struct Server {
     addr: SocketAddr,
-    doc_channel: tachyonix::Sender<TextSource>,
-    query_map: sled::Tree,
+    state: NodeState,
 }
 impl Server {
-    fn new(
-        addr: SocketAddr,
-        doc_channel: tachyonix::Sender<TextSource>,
-        path: PathBuf,
-    ) -> Result<Self, sled::Error> {
-        tracing::info!(message="Starting RPC server state", database_path=?path);
-        let db_cfg = sled::Config::default().use_compression(true).path(path);
-        let db = db_cfg.open()?;
-        let tree = db.open_tree("queries")?;
-        Ok(Self {
-            addr,
-            doc_channel,
-            query_map: tree,
-        })
+    fn new(addr: SocketAddr, state: NodeState) -> Self {
+        Self { addr, state }
     }
 }
 
@@ -70,37 +66,134 @@ impl Splinter for Server {
         _: context::Context,
         query_id: u64,
     ) -> Result<PersistentQuery, TarkineError> {
-        use rkyv::Deserialize;
-        let Some(mut raw_query) = self.query_map.get(query_id.to_ne_bytes())? else {return Err(TarkineError::Id)};
-        let archived =
-            rkyv::check_archived_root::<PersistentQuery>(raw_query.as_mut()).map_err(|e| {
-                tracing::error!(message = "Failed to deserialise rkyv bytes", underlying_error=?e);
-                TarkineError::Parsing
-            })?;
-        archived
-            .deserialize(&mut rkyv::Infallible)
-            .map_err(|_e| TarkineError::Parsing)
+        self.state.get_query(query_id)
     }
 
-    async fn submit_query(self, _: context::Context, query: PersistentQuery) {
-        unimplemented!() // TODO
+    #[instrument(skip(self))]
+    async fn submit_query(
+        self,
+        _: context::Context,
+        query: PersistentQuery,
+        idempotency_key: Option<String>,
+        backfill: bool,
+    ) -> Result<u64, TarkineError> {
+        self.state
+            .submit_query(query, idempotency_key, backfill)
+            .await
     }
 
-    async fn submit_document(self, _: context::Context, document: TextSource) {
-        unimplemented!() // TODO
+    #[instrument(skip(self))]
+    async fn update_query(
+        self,
+        _: context::Context,
+        query_id: u64,
+        update: QueryUpdate,
+    ) -> Result<PersistentQuery, TarkineError> {
+        self.state.update_query(query_id, update).await
     }
 
-    async fn get_results(self, _: context::Context, query_id: u64) -> Vec<IndexData> {
-        unimplemented!() // TODO
+    #[instrument]
+    async fn delete_query(
+        self,
+        _: context::Context,
+        query_id: u64,
+        purge_results: bool,
+    ) -> Result<(), TarkineError> {
+        self.state.delete_query(query_id, purge_results).await
+    }
+
+    #[instrument]
+    async fn list_queries(
+        self,
+        _: context::Context,
+        page: QueryPage,
+    ) -> Result<Vec<PersistentQuery>, TarkineError> {
+        self.state.list_queries(page)
+    }
+
+    #[instrument(skip(self, document), fields(document.id, document.name))]
+    async fn submit_document(
+        self,
+        _: context::Context,
+        document: TextSource,
+    ) -> Result<bool, TarkineError> {
+        self.state.submit_document(document).await
+    }
+
+    #[instrument]
+    async fn get_results(
+        self,
+        _: context::Context,
+        query_id: u64,
+        range: ResultRange,
+    ) -> Result<Vec<IndexData>, TarkineError> {
+        self.state.get_results(query_id, range).await
+    }
+
+    #[instrument]
+    async fn watch_results(
+        self,
+        ctx: context::Context,
+        cursors: Vec<ResultCursor>,
+    ) -> Result<Vec<IndexData>, TarkineError> {
+        // Give up a little before the client does, so an empty response makes it back in time
+        let wait = ctx
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .saturating_sub(WATCH_MARGIN);
+        self.state
+            .watch_results(cursors, time::Instant::now() + wait)
+            .await
+    }
+
+    #[instrument(skip(self, labels))]
+    async fn label_results(
+        self,
+        _: context::Context,
+        query_id: u64,
+        labels: Vec<ResultLabel>,
+        options: CalibrationOptions,
+    ) -> Result<Calibration, TarkineError> {
+        self.state.label_results(query_id, labels, options).await
+    }
+
+    #[instrument(skip(self))]
+    async fn calibrate_threshold(
+        self,
+        _: context::Context,
+        query_id: u64,
+        options: CalibrationOptions,
+    ) -> Result<Calibration, TarkineError> {
+        self.state.calibrate_threshold(query_id, options).await
+    }
+
+    #[instrument(skip(self, document), fields(document.id, document.name))]
+    async fn explain(
+        self,
+        _: context::Context,
+        query: TrialQuery,
+        document: TextSource,
+    ) -> Result<Explanation, TarkineError> {
+        self.state.explain(query, document).await
+    }
+
+    #[instrument(skip(self, documents), fields(documents = documents.len()))]
+    async fn dry_run(
+        self,
+        _: context::Context,
+        query: TrialQuery,
+        documents: Vec<TextSource>,
+        top: u32,
+    ) -> Result<DryRun, TarkineError> {
+        self.state.dry_run(query, documents, top).await
     }
 }
 
+const WATCH_MARGIN: Duration = Duration::from_millis(500);
+
 #[instrument]
-async fn rpc_server(
-    addr: SocketAddr,
-    db_path: PathBuf,
-    doc_channel: tachyonix::Sender<TextSource>,
-) -> Result<(), Box<dyn std::error::Error>> {
+async fn rpc_server(addr: SocketAddr, state: NodeState) -> Result<(), Box<dyn std::error::Error>> {
     let mut listener = tarpc::serde_transport::tcp::listen(&addr, Bincode::default).await?;
     listener.config_mut().max_frame_length(usize::MAX);
     listener
@@ -112,12 +205,7 @@ async fn rpc_server(
         // serve is generated by the service attribute. It takes as input any type implementing
         // the generated World trait.
         .map(|channel| {
-            let server = Server::new(
-                channel.transport().peer_addr().unwrap(),
-                doc_channel.clone(),
-                db_path.clone(),
-            )
-            .expect("Couldn't start server state or open databases");
+            let server = Server::new(channel.transport().peer_addr().unwrap(), state.clone());
             channel.execute(server.serve())
         })
         // Max 10 channels.
@@ -128,19 +216,15 @@ async fn rpc_server(
 }
 
 #[instrument]
-pub fn server_runtime(
-    addr: SocketAddr,
-    db_path: PathBuf,
-    doc_channel: tachyonix::Sender<TextSource>,
-) {
+pub fn server_runtime(addr: SocketAddr, state: NodeState) {
     let runtime = tokio::runtime::Builder::new_current_thread()
         .worker_threads(1)
         .enable_io()
         .enable_time()
-        .thread_name("http server")
+        .thread_name("rpc server")
         .build()
         .expect("Couldn't build server");
     runtime
-        .block_on(rpc_server(addr, db_path, doc_channel))
+        .block_on(rpc_server(addr, state))
         .expect("Server failed");
 }
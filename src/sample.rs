@@ -0,0 +1,193 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::{metadata::MetadataValue, TextSource};
+
+/// Why a sample corpus couldn't be loaded
+#[derive(Debug, Error)]
+pub enum SampleError {
+    #[error("Couldn't read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Couldn't read a document from {name}: {reason}")]
+    Document { name: String, reason: String },
+}
+
+/// Loads a sample corpus to try queries out on: every file in a directory, or a single file.
+///
+/// JSONL files (`.jsonl`) hold a document per line, read with [`from_json`]. Any other file is a
+/// document of its own, named after the file. Directories aren't searched recursively.
+pub fn load(path: &Path) -> Result<Vec<TextSource>, SampleError> {
+    let read_error = |source| SampleError::Read {
+        path: path.to_path_buf(),
+        source,
+    };
+    if !path.is_dir() {
+        return load_file(path);
+    }
+    let mut files = std::fs::read_dir(path)
+        .map_err(read_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(read_error)?;
+    files.retain(|file| file.is_file());
+    files.sort_unstable();
+    let mut documents = Vec::new();
+    for file in files {
+        documents.extend(load_file(&file)?);
+    }
+    Ok(documents)
+}
+
+fn load_file(path: &Path) -> Result<Vec<TextSource>, SampleError> {
+    let text = std::fs::read_to_string(path).map_err(|source| SampleError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if path
+        .extension()
+        .is_none_or(|extension| extension != "jsonl")
+    {
+        return Ok(vec![TextSource::from_content(text, file_name)]);
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            let name = format!("{file_name}:{}", idx + 1);
+            let value = serde_json::from_str(line).map_err(|err| SampleError::Document {
+                name: name.clone(),
+                reason: err.to_string(),
+            })?;
+            from_json(value, &name)
+        })
+        .collect()
+}
+
+/// A document in the shape `/document/submit` takes, though the name can be left out too
+#[derive(Deserialize)]
+struct SubmittedDocument {
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(default)]
+    name: Option<String>,
+    data: String,
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, MetadataValue>,
+}
+
+/// Reads a document from a JSON object, falling back on `name` if it doesn't have one.
+///
+/// Objects with a `data` member are documents in the shape `/document/submit` takes. Any other
+/// object is a record, like a line of an export: its strings become named text fields, which
+/// together make up the main text, and the rest of its members become metadata where they can.
+/// Either way, documents without an id get one derived from their name and text.
+pub fn from_json(value: Value, name: &str) -> Result<TextSource, SampleError> {
+    let invalid = |reason: String| SampleError::Document {
+        name: name.to_string(),
+        reason,
+    };
+    let Value::Object(members) = value else {
+        return Err(invalid("expected a JSON object".to_string()));
+    };
+    if members.contains_key("data") {
+        let document = serde_json::from_value::<SubmittedDocument>(Value::Object(members))
+            .map_err(|err| invalid(err.to_string()))?;
+        let name = document.name.unwrap_or_else(|| name.to_string());
+        let source = TextSource {
+            fields: document.fields,
+            metadata: document.metadata,
+            ..TextSource::new(document.data, name)
+        };
+        return Ok(match document.id {
+            Some(id) => TextSource { id, ..source },
+            None => source.with_content_id(),
+        });
+    }
+    let mut fields = BTreeMap::new();
+    let mut metadata = BTreeMap::new();
+    for (member, value) in members {
+        match value {
+            Value::String(text) => {
+                fields.insert(member, text);
+            }
+            value => {
+                if let Ok(value) = serde_json::from_value::<MetadataValue>(value) {
+                    metadata.insert(member, value);
+                }
+            }
+        }
+    }
+    let data = fields.values().cloned().collect::<Vec<_>>().join("\n\n");
+    Ok(TextSource {
+        fields,
+        metadata,
+        ..TextSource::new(data, name.to_string())
+    }
+    .with_content_id())
+}
+
+#[cfg(test)]
+mod sample_tests {
+    use super::*;
+
+    #[test]
+    fn test_documents_and_records_from_json() {
+        let document = serde_json::json!({
+            "id": 7,
+            "data": "Rates held",
+            "fields": {"title": "BoE"},
+            "metadata": {"source": "reuters"},
+        });
+        let document = from_json(document, "line 1").unwrap();
+        assert_eq!((document.id, document.name.as_str()), (7, "line 1"));
+        assert_eq!(document.fields["title"], "BoE");
+
+        let record = serde_json::json!({
+            "title": "Rates held",
+            "body": "The BoE held rates",
+            "severity": 3,
+            "tags": ["rates", "uk"],
+            "nested": {"ignored": true},
+        });
+        let record = from_json(record, "requests.jsonl:2").unwrap();
+        assert_eq!(record.data, "The BoE held rates\n\nRates held");
+        assert_eq!(record.fields["title"], "Rates held");
+        assert_eq!(record.metadata["severity"], MetadataValue::Number(3.0));
+        assert_eq!(record.metadata.len(), 2);
+        assert_eq!(record.id, record.content_id());
+        assert!(from_json(serde_json::json!(["not", "an", "object"]), "x").is_err());
+    }
+
+    #[test]
+    fn test_load_directory_and_jsonl() {
+        let root = std::env::temp_dir().join(format!("tarkine-sample-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(root.join("nested")).unwrap();
+        std::fs::write(root.join("b.txt"), "Mr Darcy").unwrap();
+        let lines = "{\"title\": \"first\"}\n\n{\"name\": \"second\", \"data\": \"text\"}\n";
+        std::fs::write(root.join("a.jsonl"), lines).unwrap();
+        std::fs::write(root.join("nested/c.txt"), "not loaded").unwrap();
+
+        let documents = load(&root).unwrap();
+        let names = documents.iter().map(|document| document.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["a.jsonl:1", "second", "b.txt"]);
+        assert_eq!(load(&root.join("b.txt")).unwrap()[0].data, "Mr Darcy");
+        std::fs::write(root.join("bad.jsonl"), "{\"title\": ").unwrap();
+        assert!(load(&root).is_err());
+        assert!(load(&root.join("missing")).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// How a query's matches are scored, which is also the scale its threshold is written in
+#[derive(
+    Archive,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Clone,
+    Copy,
+    serde::Serialize,
+    serde::Deserialize,
+    Serialize,
+    Deserialize,
+)]
+#[archive(compare(PartialEq))]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum ScoringModel {
+    /// Skim's fuzzy match score, with exact clauses scored as if they'd fuzzy matched themselves.
+    /// Open-ended, and longer words and closer matches score higher.
+    #[default]
+    Skim,
+    /// BM25 over the words each clause matched, using document frequencies and lengths the node
+    /// has seen. Open-ended, but a word appearing once in a document of average length scores
+    /// about its inverse document frequency, so rare words count for more.
+    Bm25,
+    /// The BM25 score as a share of the most the query could score, from 0 to 1
+    Normalized,
+}
+
+impl ScoringModel {
+    /// Checks a threshold is somewhere a match could actually score
+    pub fn check_threshold(&self, threshold: f64) -> Result<(), String> {
+        match self {
+            _ if threshold.is_nan() || threshold <= 0.0 => {
+                Err(format!("Threshold has to be above 0, not {threshold}"))
+            }
+            ScoringModel::Normalized if threshold > 1.0 => Err(format!(
+                "Normalized scores go from 0 to 1, so a threshold of {threshold} can't be met"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod scoring_tests {
+    use super::*;
+
+    #[test]
+    fn test_thresholds_fit_the_scale() {
+        assert!(ScoringModel::Skim.check_threshold(120.0).is_ok());
+        assert!(ScoringModel::Bm25.check_threshold(2.5).is_ok());
+        assert!(ScoringModel::Normalized.check_threshold(0.4).is_ok());
+        assert!(ScoringModel::Normalized.check_threshold(1.5).is_err());
+        for scoring in [ScoringModel::Skim, ScoringModel::Normalized] {
+            assert!(scoring.check_threshold(0.0).is_err());
+            assert!(scoring.check_threshold(f64::NAN).is_err());
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use lib::analysis::{AnalyzerConfig, Language, Stopwords};
+use rust_stemmers::{Algorithm, Stemmer};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Lucene's default English stopword set
+const ENGLISH_STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// A normalized word, as produced by an [`Analyzer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Token {
+    pub(crate) text: String,
+    /// Index of the word in the original text. Dropped stopwords still count, so phrases and
+    /// proximity clauses measure distance in the text as written.
+    pub(crate) position: usize,
+    /// Inclusive char positions of the word in the original text, the same shape as `match_indices`
+    pub(crate) span: [usize; 2],
+}
+
+/// Turns text into tokens: unicode word segmentation, then optional lowercasing, stopword removal
+/// and Snowball stemming, as set out by the query's [`AnalyzerConfig`].
+pub(crate) struct Analyzer<'a> {
+    config: &'a AnalyzerConfig,
+    stemmer: Option<Stemmer>,
+}
+
+impl<'a> Analyzer<'a> {
+    pub(crate) fn new(config: &'a AnalyzerConfig) -> Self {
+        Self {
+            config,
+            stemmer: config.stemmer.map(|lang| Stemmer::create(algorithm(lang))),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &AnalyzerConfig {
+        self.config
+    }
+
+    pub(crate) fn analyze(&self, text: &str) -> Vec<Token> {
+        let (mut byte_pos, mut char_pos) = (0, 0);
+        text.unicode_word_indices()
+            .enumerate()
+            .filter_map(|(position, (start, word))| {
+                char_pos += text[byte_pos..start].chars().count();
+                let len = word.chars().count();
+                let span = [char_pos, char_pos + len - 1];
+                (byte_pos, char_pos) = (start + word.len(), char_pos + len);
+                self.normalize(word).map(|text| Token {
+                    text,
+                    position,
+                    span,
+                })
+            })
+            .collect()
+    }
+
+    /// Normalizes part of a word the way its tokens would be, short of stemming or dropping
+    /// stopwords, neither of which mean anything for a fragment
+    pub(crate) fn normalize_fragment(&self, fragment: &str) -> String {
+        match self.config.lowercase {
+            true => fragment.to_lowercase(),
+            false => fragment.to_string(),
+        }
+    }
+
+    /// Normalizes a single word, or returns `None` if it's a stopword
+    fn normalize(&self, word: &str) -> Option<String> {
+        let lowercased = word.to_lowercase();
+        let is_stopword = match &self.config.stopwords {
+            Stopwords::None => false,
+            Stopwords::English => ENGLISH_STOPWORDS.contains(&lowercased.as_str()),
+            Stopwords::Custom(words) => words.iter().any(|w| w.to_lowercase() == lowercased),
+        };
+        if is_stopword {
+            return None;
+        }
+        let word = if self.config.lowercase {
+            lowercased
+        } else {
+            word.to_string()
+        };
+        Some(match &self.stemmer {
+            Some(stemmer) => stemmer.stem(&word).into_owned(),
+            None => word,
+        })
+    }
+}
+
+fn algorithm(language: Language) -> Algorithm {
+    match language {
+        Language::Arabic => Algorithm::Arabic,
+        Language::Danish => Algorithm::Danish,
+        Language::Dutch => Algorithm::Dutch,
+        Language::English => Algorithm::English,
+        Language::Finnish => Algorithm::Finnish,
+        Language::French => Algorithm::French,
+        Language::German => Algorithm::German,
+        Language::Greek => Algorithm::Greek,
+        Language::Hungarian => Algorithm::Hungarian,
+        Language::Italian => Algorithm::Italian,
+        Language::Norwegian => Algorithm::Norwegian,
+        Language::Portuguese => Algorithm::Portuguese,
+        Language::Romanian => Algorithm::Romanian,
+        Language::Russian => Algorithm::Russian,
+        Language::Spanish => Algorithm::Spanish,
+        Language::Swedish => Algorithm::Swedish,
+        Language::Tamil => Algorithm::Tamil,
+        Language::Turkish => Algorithm::Turkish,
+    }
+}
+
+#[cfg(test)]
+mod analyzer_tests {
+    use super::*;
+
+    fn texts(tokens: &[Token]) -> Vec<&str> {
+        tokens.iter().map(|t| t.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_default_only_lowercases() {
+        let config = AnalyzerConfig::default();
+        let tokens = Analyzer::new(&config).analyze("The Outages, reported");
+        assert_eq!(texts(&tokens), ["the", "outages", "reported"]);
+        assert_eq!(tokens[1].span, [4, 10]);
+    }
+
+    #[test]
+    fn test_stemming_and_stopwords_keep_positions() {
+        let config = AnalyzerConfig {
+            stemmer: Some(Language::English),
+            stopwords: Stopwords::English,
+            ..AnalyzerConfig::default()
+        };
+        let tokens = Analyzer::new(&config).analyze("The outages were reported");
+        assert_eq!(texts(&tokens), ["outag", "were", "report"]);
+        assert_eq!(
+            tokens.iter().map(|t| t.position).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+    }
+}
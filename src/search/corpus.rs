@@ -0,0 +1,323 @@
+use lib::{analysis::AnalyzerConfig, TarkineError};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use super::{analyzer::Analyzer, prefilter, Document};
+
+/// How quickly repeats of a term stop adding to its BM25 score
+pub(crate) const K1: f64 = 1.2;
+
+/// How much BM25 discounts matches in texts longer than average
+pub(crate) const B: f64 = 0.75;
+
+/// How often the shard sharing its statistics swaps in a fresh snapshot of them
+pub(crate) const PUBLISH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a published snapshot is also written to sled. A restart loses the documents counted
+/// since.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most distinct terms an analyzer's statistics keep document frequencies for
+const MAX_TERMS: usize = 100_000;
+
+/// Term statistics over every document a shard has searched, for BM25 scoring. They're kept per
+/// analyzer, since the analyzer decides what counts as a term.
+///
+/// Every shard sees every document, so each keeps a copy of its own rather than waiting on a
+/// shared one to count a document, and they all come out the same. Only one shard's copy is
+/// published and persisted, and every shard starts from it after a restart.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CorpusStats {
+    by_analyzer: HashMap<AnalyzerConfig, TermStats>,
+}
+
+impl CorpusStats {
+    /// Counts a document towards the statistics of each of the analyzers
+    pub(crate) fn add<'c>(
+        &mut self,
+        document: &Document,
+        configs: impl IntoIterator<Item = &'c AnalyzerConfig>,
+    ) {
+        for config in configs {
+            let analyzer = Analyzer::new(config);
+            match self.by_analyzer.get_mut(config) {
+                Some(stats) => stats.add(document, &analyzer),
+                None => {
+                    let mut stats = TermStats::default();
+                    stats.add(document, &analyzer);
+                    self.by_analyzer.insert(config.clone(), stats);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, config: &AnalyzerConfig) -> Option<&TermStats> {
+        self.by_analyzer.get(config)
+    }
+}
+
+/// A snapshot of a shard's statistics, shared with the node state so explanations are scored
+/// close to the way the shard would score a match. The shard updates a copy of its own and swaps
+/// a fresh snapshot in every [`PUBLISH_INTERVAL`], so reading them never waits on it searching.
+///
+/// Snapshots are written to sled every [`PERSIST_INTERVAL`], one entry per analyzer keyed by its
+/// analyzer id, so BM25 scores mean the same after a restart.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedCorpusStats {
+    snapshot: Arc<RwLock<Arc<CorpusStats>>>,
+    // Missing for statistics that aren't persisted
+    tree: Option<sled::Tree>,
+    persisted_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl SharedCorpusStats {
+    /// Loads the last persisted statistics, skipping any analyzer's that can't be read
+    pub(crate) fn open(db: &sled::Db) -> Result<Self, TarkineError> {
+        let tree = db.open_tree("corpus_stats")?;
+        let mut stats = CorpusStats::default();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            match serde_json::from_slice::<(AnalyzerConfig, TermStats)>(&value) {
+                Ok((config, term_stats)) => {
+                    stats.by_analyzer.insert(config, term_stats);
+                }
+                Err(err) => tracing::error!(
+                    message = "Skipping unreadable corpus statistics",
+                    key = ?key,
+                    %err
+                ),
+            }
+        }
+        Ok(Self {
+            snapshot: Arc::new(RwLock::new(Arc::new(stats))),
+            tree: Some(tree),
+            persisted_at: Arc::default(),
+        })
+    }
+
+    pub(crate) fn snapshot(&self) -> Result<Arc<CorpusStats>, TarkineError> {
+        let snapshot = self
+            .snapshot
+            .read()
+            .map_err(|_e| TarkineError::InternalChannel)?;
+        Ok(snapshot.clone())
+    }
+
+    pub(crate) fn publish(&self, stats: CorpusStats) {
+        let stats = Arc::new(stats);
+        // Swapping an Arc can't leave it half written, so a poisoned lock is still fine to use
+        *self
+            .snapshot
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = stats.clone();
+        let Some(tree) = &self.tree else {
+            return;
+        };
+        let mut persisted_at = self
+            .persisted_at
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if persisted_at.is_some_and(|at| now.duration_since(at) < PERSIST_INTERVAL) {
+            return;
+        }
+        *persisted_at = Some(now);
+        if let Err(err) = persist(tree, &stats) {
+            tracing::error!(message = "Couldn't persist corpus statistics", ?err);
+        }
+    }
+}
+
+fn persist(tree: &sled::Tree, stats: &CorpusStats) -> Result<(), TarkineError> {
+    let mut batch = sled::Batch::default();
+    for (config, term_stats) in &stats.by_analyzer {
+        let value =
+            serde_json::to_vec(&(config, term_stats)).map_err(|_e| TarkineError::Parsing)?;
+        batch.insert(&prefilter::analyzer_id(config).to_be_bytes(), value);
+    }
+    tree.apply_batch(batch)?;
+    Ok(())
+}
+
+/// What a single analyzer made of the documents seen so far
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct TermStats {
+    documents: u64,
+    /// How many documents each term appeared in, in any of their texts or text metadata. Only the
+    /// [`MAX_TERMS`] most common are kept - the rest are dropped, rarest first, and then count as
+    /// unseen, which is about what they were worth anyway.
+    document_frequency: HashMap<String, u64>,
+    /// Texts seen and tokens in them, for the main text and then each named field
+    text_lengths: Lengths,
+    field_lengths: HashMap<String, Lengths>,
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct Lengths {
+    texts: u64,
+    tokens: u64,
+}
+
+impl TermStats {
+    fn add(&mut self, document: &Document, analyzer: &Analyzer) {
+        self.documents += 1;
+        let mut terms = HashSet::new();
+        for field in document.texts() {
+            let tokens = document.tokens(analyzer, field);
+            let lengths = match field {
+                Some(field) => self.field_lengths.entry(field.to_string()).or_default(),
+                None => &mut self.text_lengths,
+            };
+            lengths.texts += 1;
+            lengths.tokens += tokens.len() as u64;
+            terms.extend(tokens.iter().map(|token| token.text.clone()));
+        }
+        for text in document.metadata_texts() {
+            terms.extend(analyzer.analyze(text).into_iter().map(|token| token.text));
+        }
+        for term in terms {
+            *self.document_frequency.entry(term).or_default() += 1;
+        }
+        if self.document_frequency.len() > MAX_TERMS {
+            self.prune(MAX_TERMS * 3 / 4);
+        }
+    }
+
+    /// Keeps at most `keep` terms, dropping the rarest. Terms tied with the last one that would
+    /// fit are dropped too, so it can keep fewer.
+    fn prune(&mut self, keep: usize) {
+        let mut frequencies = self
+            .document_frequency
+            .values()
+            .copied()
+            .collect::<Vec<_>>();
+        if frequencies.len() <= keep {
+            return;
+        }
+        let (_, &mut cutoff, _) = frequencies.select_nth_unstable_by(keep, |a, b| b.cmp(a));
+        self.document_frequency
+            .retain(|_, &mut frequency| frequency > cutoff);
+    }
+
+    /// Inverse document frequency of an analyzed term - the fewer documents have it, the more
+    /// it's worth
+    pub(crate) fn idf(&self, term: &str) -> f64 {
+        let frequency = self
+            .document_frequency
+            .get(term)
+            .copied()
+            .unwrap_or_default();
+        idf(self.documents, frequency)
+    }
+
+    /// What the rarest possible term is worth, one no document has had
+    pub(crate) fn unseen_idf(&self) -> f64 {
+        idf(self.documents, 0)
+    }
+
+    /// Tokens in the main text (`None`) or a named field, on average over the documents that
+    /// had it
+    pub(crate) fn average_length(&self, field: Option<&str>) -> Option<f64> {
+        let lengths = match field {
+            Some(field) => *self.field_lengths.get(field)?,
+            None => self.text_lengths,
+        };
+        (lengths.texts > 0).then(|| lengths.tokens as f64 / lengths.texts as f64)
+    }
+}
+
+/// Lucene's inverse document frequency, which stays positive even for terms in every document
+pub(crate) fn idf(documents: u64, frequency: u64) -> f64 {
+    let (documents, frequency) = (documents as f64, frequency.min(documents) as f64);
+    (1.0 + (documents - frequency + 0.5) / (frequency + 0.5)).ln()
+}
+
+/// BM25's score for something appearing `frequency` times in a text `length` tokens long
+pub(crate) fn bm25(idf: f64, frequency: usize, length: usize, average_length: f64) -> f64 {
+    let frequency = frequency as f64;
+    let length_norm = 1.0 - B + B * length as f64 / average_length.max(1.0);
+    idf * frequency * (K1 + 1.0) / (frequency + K1 * length_norm)
+}
+
+#[cfg(test)]
+mod corpus_tests {
+    use super::*;
+    use lib::TextSource;
+
+    #[test]
+    fn test_document_frequencies_and_lengths() {
+        let config = AnalyzerConfig::default();
+        let sources = [
+            TextSource::new("The bank held rates", "a".to_string()).with_field("title", "Rates"),
+            TextSource::new("The river bank flooded again", "b".to_string()),
+            TextSource::new("Bank BANK bank", "c".to_string()),
+        ];
+        let mut stats = CorpusStats::default();
+        for source in &sources {
+            stats.add(&Document::new(source), [&config]);
+        }
+        let stats = stats.get(&config).unwrap();
+        // Repeats within a document only count once
+        assert_eq!(stats.idf("bank"), idf(3, 3));
+        assert_eq!(stats.idf("river"), idf(3, 1));
+        assert_eq!(stats.idf("rates"), idf(3, 1));
+        assert!(stats.idf("river") > stats.idf("bank"));
+        assert_eq!(stats.unseen_idf(), idf(3, 0));
+        assert_eq!(stats.average_length(None), Some(4.0));
+        assert_eq!(stats.average_length(Some("title")), Some(1.0));
+        assert_eq!(stats.average_length(Some("body")), None);
+    }
+
+    #[test]
+    fn test_rare_terms_are_pruned_first() {
+        let config = AnalyzerConfig::default();
+        let sources = [
+            TextSource::new("bank river", "a".to_string()),
+            TextSource::new("bank rates", "b".to_string()),
+            TextSource::new("bank rates flood", "c".to_string()),
+        ];
+        let mut stats = CorpusStats::default();
+        for source in &sources {
+            stats.add(&Document::new(source), [&config]);
+        }
+        let stats = stats.by_analyzer.get_mut(&config).unwrap();
+        // River and flood tie for the last place, so both go
+        stats.prune(3);
+        assert_eq!(stats.document_frequency.len(), 2);
+        assert_eq!(stats.idf("bank"), idf(3, 3));
+        assert_eq!(stats.idf("rates"), idf(3, 2));
+        // Dropped, so as rare as a term gets
+        assert_eq!(stats.idf("river"), stats.unseen_idf());
+        stats.prune(1);
+        assert_eq!(stats.idf("rates"), stats.unseen_idf());
+        assert_eq!(stats.idf("bank"), idf(3, 3));
+    }
+
+    #[test]
+    fn test_persisted_stats_survive_reopening() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = AnalyzerConfig::default();
+        let source = TextSource::new("The bank held rates", "a".to_string());
+        let mut stats = CorpusStats::default();
+        stats.add(&Document::new(&source), [&config]);
+        SharedCorpusStats::open(&db).unwrap().publish(stats);
+
+        let reopened = SharedCorpusStats::open(&db).unwrap().snapshot().unwrap();
+        let stats = reopened.get(&config).unwrap();
+        assert_eq!(stats.idf("bank"), idf(1, 1));
+        assert_eq!(stats.unseen_idf(), idf(1, 0));
+        assert_eq!(stats.average_length(None), Some(4.0));
+    }
+
+    #[test]
+    fn test_bm25_saturates_and_favours_short_texts() {
+        let once = bm25(1.0, 1, 10, 10.0);
+        assert!((once - 1.0).abs() < 1e-9);
+        assert!(bm25(1.0, 2, 10, 10.0) > once);
+        assert!(bm25(1.0, 1000, 10, 10.0) < K1 + 1.0);
+        assert!(bm25(1.0, 1, 5, 10.0) > once && bm25(1.0, 1, 20, 10.0) < once);
+    }
+}
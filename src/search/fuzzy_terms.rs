@@ -0,0 +1,172 @@
+use fst::{automaton::Levenshtein, IntoStreamer, Set};
+use lib::{analysis::AnalyzerConfig, query::QueryNode, PersistentQuery};
+use std::collections::{BTreeSet, HashMap};
+use tracing::{event, Level};
+
+use super::analyzer::{Analyzer, Token};
+
+/// Query terms within edit distance of a document, mapped to the document words they're close
+/// to and how many edits away those words are
+pub(crate) type Expansions = HashMap<String, Vec<(String, u32)>>;
+
+/// Every `term~n` clause in a shard's queries, compiled into an FST per analyzer config.
+///
+/// Documents are matched against it a word at a time: each distinct word becomes a Levenshtein
+/// automaton which is intersected with the FST, so the work per word is bounded by the size of the
+/// FST rather than the number of queries.
+#[derive(Default)]
+pub(crate) struct FuzzyTermIndex {
+    analyzers: HashMap<AnalyzerConfig, TermSet>,
+}
+
+struct TermSet {
+    terms: Set<Vec<u8>>,
+    // The largest distance asked for by any query, which is how far each automaton has to reach
+    max_distance: u32,
+}
+
+impl FuzzyTermIndex {
+    pub(crate) fn build<'a>(queries: impl IntoIterator<Item = &'a PersistentQuery>) -> Self {
+        let mut terms: HashMap<&AnalyzerConfig, (BTreeSet<String>, u32)> = HashMap::new();
+        for query in queries {
+            let analyzer = Analyzer::new(&query.analyzer);
+            for (term, distance) in fuzzy_terms(&query.parsed) {
+                let Some(term) = fuzzy_token(&analyzer, term) else {
+                    continue;
+                };
+                let (set, max_distance) = terms.entry(&query.analyzer).or_default();
+                set.insert(term);
+                *max_distance = (*max_distance).max(distance);
+            }
+        }
+        let analyzers = terms
+            .into_iter()
+            .map(|(config, (terms, max_distance))| {
+                let terms = Set::from_iter(terms).expect("BTreeSet iterates in sorted order");
+                let set = TermSet {
+                    terms,
+                    max_distance,
+                };
+                (config.clone(), set)
+            })
+            .collect();
+        Self { analyzers }
+    }
+
+    /// Finds the query terms each of the document's tokens is close enough to for at least one
+    /// query to match
+    pub(crate) fn expand(&self, config: &AnalyzerConfig, tokens: &[Token]) -> Expansions {
+        let mut expansions = Expansions::new();
+        let Some(set) = self.analyzers.get(config) else {
+            return expansions;
+        };
+        let mut words = tokens.iter().map(|t| t.text.as_str()).collect::<Vec<_>>();
+        words.sort_unstable();
+        words.dedup();
+        for word in words {
+            let automaton = match Levenshtein::new(word, set.max_distance) {
+                Ok(automaton) => automaton,
+                Err(err) => {
+                    event!(Level::DEBUG, message = "Skipping word for fuzzy terms", word, %err);
+                    continue;
+                }
+            };
+            let matched = set
+                .terms
+                .search(automaton)
+                .into_stream()
+                .into_strs()
+                .expect("FST keys are built from strings");
+            for term in matched {
+                let distance = edit_distance(&term, word);
+                expansions
+                    .entry(term)
+                    .or_default()
+                    .push((word.to_string(), distance));
+            }
+        }
+        expansions
+    }
+}
+
+/// Every `term~n` clause in a query, with its distance
+pub(crate) fn fuzzy_terms(node: &QueryNode) -> Vec<(&str, u32)> {
+    match node {
+        QueryNode::FuzzyTerm { term, distance } => vec![(term.as_str(), *distance)],
+        QueryNode::Fuzzy(_)
+        | QueryNode::Term(_)
+        | QueryNode::Phrase(_)
+        | QueryNode::Wildcard(_)
+        | QueryNode::Regex(_)
+        | QueryNode::Compare { .. }
+        | QueryNode::Range { .. } => Vec::new(),
+        QueryNode::Near { left, right, .. } => {
+            let mut terms = fuzzy_terms(left);
+            terms.extend(fuzzy_terms(right));
+            terms
+        }
+        QueryNode::And(clauses) | QueryNode::Or(clauses) => {
+            clauses.iter().flat_map(fuzzy_terms).collect()
+        }
+        QueryNode::Not(clause) | QueryNode::Field { clause, .. } => fuzzy_terms(clause),
+    }
+}
+
+/// The analyzed form of a fuzzy term, as long as it comes out as exactly one token
+pub(crate) fn fuzzy_token(analyzer: &Analyzer, term: &str) -> Option<String> {
+    let mut tokens = analyzer.analyze(term);
+    match tokens.len() {
+        1 => tokens.pop().map(|token| token.text),
+        _ => None,
+    }
+}
+
+/// Levenshtein distance in chars
+pub(crate) fn edit_distance(a: &str, b: &str) -> u32 {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len() as u32).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i as u32 + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + u32::from(a_char != *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod fuzzy_terms_tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("darcy", "darcy"), 0);
+        assert_eq!(edit_distance("darcy", "darci"), 1);
+        assert_eq!(edit_distance("darcy", "dary"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_expand_document_tokens() {
+        let queries = [
+            PersistentQuery::new(1, "q", "darcy~1", 1.0).unwrap(),
+            PersistentQuery::new(2, "q", "bingley~2 AND netherfield", 1.0).unwrap(),
+        ];
+        let index = FuzzyTermIndex::build(&queries);
+        let config = AnalyzerConfig::default();
+        let tokens = Analyzer::new(&config).analyze("Mr Darcey and Mr Bingly, not Mr Dorcey");
+        let expansions = index.expand(&config, &tokens);
+        // Automata reach as far as the largest distance in the shard, so this includes words the
+        // query itself is too strict for
+        assert_eq!(
+            expansions["darcy"],
+            [("darcey".to_string(), 1), ("dorcey".to_string(), 2)]
+        );
+        assert_eq!(expansions["bingley"], [("bingly".to_string(), 1)]);
+        assert!(!expansions.contains_key("netherfield"));
+    }
+}
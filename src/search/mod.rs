@@ -0,0 +1,1306 @@
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use std::{
+    cell::{OnceCell, RefCell},
+    cmp::Ordering,
+    collections::HashMap,
+    rc::Rc,
+};
+use tracing::{event, Level};
+
+use lib::{
+    analysis::AnalyzerConfig,
+    metadata::MetadataValue,
+    query::{Comparison, QueryNode, RangeBound},
+    scoring::ScoringModel,
+    ClauseExplanation, Explanation, IndexData, PersistentQuery, TextSource,
+};
+
+mod analyzer;
+pub(crate) mod corpus;
+pub(crate) mod fuzzy_terms;
+mod patterns;
+pub(crate) mod prefilter;
+
+use analyzer::{Analyzer, Token};
+use corpus::{CorpusStats, TermStats};
+use fuzzy_terms::{Expansions, FuzzyTermIndex};
+use patterns::Patterns;
+
+pub(crate) struct Searcher {
+    matcher: SkimMatcherV2,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MatchInformation {
+    score: f64,
+    positions: Vec<[usize; 2]>,
+}
+
+impl MatchInformation {
+    fn merge(mut self, other: MatchInformation) -> Self {
+        self.score += other.score;
+        self.positions.extend(other.positions);
+        self
+    }
+}
+
+/// A query ready to run against documents, checked and with its patterns compiled
+#[derive(Debug, Clone)]
+pub(crate) struct CompiledQuery {
+    pub(crate) query: PersistentQuery,
+    patterns: Patterns,
+    /// Kept out of live matching in its shard until the shard has backfilled it, so no document
+    /// is matched both live and by the backfill
+    pub(crate) held_for_backfill: bool,
+}
+
+impl CompiledQuery {
+    /// Validates the query and compiles its patterns, returning a description of the first
+    /// problem found
+    pub(crate) fn new(query: PersistentQuery) -> Result<Self, String> {
+        validate(&query)?;
+        let patterns = Patterns::compile(&query)?;
+        Ok(Self {
+            query,
+            patterns,
+            held_for_backfill: false,
+        })
+    }
+
+    /// Holds the query back from live matching until its shard has run it over retained documents
+    pub(crate) fn hold_for_backfill(mut self) -> Self {
+        self.held_for_backfill = true;
+        self
+    }
+}
+
+/// A document being searched. Analysis is done the first time a query needs it, and shared with
+/// every other query in the shard using the same analyzer settings.
+pub(crate) struct Document<'a> {
+    source: &'a TextSource,
+    tokens: RefCell<PerText<'a, [Token]>>,
+    fuzzy_terms: Option<&'a FuzzyTermIndex>,
+    expansions: RefCell<PerText<'a, Expansions>>,
+    corpus_stats: Option<&'a CorpusStats>,
+}
+
+/// Analysis results for the main text (`None`) and each named field, by analyzer settings
+type PerText<'a, T> = HashMap<Option<&'a str>, HashMap<AnalyzerConfig, Rc<T>>>;
+
+impl<'a> Document<'a> {
+    pub(crate) fn new(source: &'a TextSource) -> Self {
+        Self {
+            source,
+            tokens: RefCell::new(HashMap::new()),
+            fuzzy_terms: None,
+            expansions: RefCell::new(HashMap::new()),
+            corpus_stats: None,
+        }
+    }
+
+    /// Matches `term~n` clauses through the shard's fuzzy term index. Without one, fuzzy terms
+    /// never match.
+    pub(crate) fn with_fuzzy_terms(mut self, fuzzy_terms: &'a FuzzyTermIndex) -> Self {
+        self.fuzzy_terms = Some(fuzzy_terms);
+        self
+    }
+
+    /// Scores BM25 queries with the shard's term statistics. Without them, every term is as rare
+    /// as any other and every text is of average length.
+    pub(crate) fn with_corpus_stats(mut self, corpus_stats: &'a CorpusStats) -> Self {
+        self.corpus_stats = Some(corpus_stats);
+        self
+    }
+
+    /// The main text (`None`) and the name of each named field
+    fn texts(&self) -> impl Iterator<Item = Option<&'a str>> {
+        let fields = self.source.fields.keys().map(|field| Some(field.as_str()));
+        std::iter::once(None).chain(fields)
+    }
+
+    /// The text of every text and keyword metadata value
+    fn metadata_texts(&self) -> impl Iterator<Item = &'a str> {
+        let texts = self.source.metadata.values().flat_map(|value| match value {
+            MetadataValue::Text(text) => std::slice::from_ref(text),
+            MetadataValue::Keywords(keywords) => keywords.as_slice(),
+            MetadataValue::Number(_) | MetadataValue::Timestamp(_) => &[],
+        });
+        texts.map(String::as_str)
+    }
+
+    /// The main text, or the named field. Fields are looked up before evaluating clauses against
+    /// them, so they're always there.
+    fn text(&self, field: Option<&str>) -> &'a str {
+        match field {
+            Some(field) => &self.source.fields[field],
+            None => &self.source.data,
+        }
+    }
+
+    fn tokens(&self, analyzer: &Analyzer, field: Option<&'a str>) -> Rc<[Token]> {
+        if let Some(tokens) = self
+            .tokens
+            .borrow()
+            .get(&field)
+            .and_then(|cache| cache.get(analyzer.config()))
+        {
+            return tokens.clone();
+        }
+        let tokens: Rc<[Token]> = analyzer.analyze(self.text(field)).into();
+        self.tokens
+            .borrow_mut()
+            .entry(field)
+            .or_default()
+            .insert(analyzer.config().clone(), tokens.clone());
+        tokens
+    }
+
+    fn expansions(&self, analyzer: &Analyzer, field: Option<&'a str>) -> Rc<Expansions> {
+        if let Some(expansions) = self
+            .expansions
+            .borrow()
+            .get(&field)
+            .and_then(|cache| cache.get(analyzer.config()))
+        {
+            return expansions.clone();
+        }
+        let expansions = Rc::new(match self.fuzzy_terms {
+            Some(index) => index.expand(analyzer.config(), &self.tokens(analyzer, field)),
+            None => Expansions::new(),
+        });
+        self.expansions
+            .borrow_mut()
+            .entry(field)
+            .or_default()
+            .insert(analyzer.config().clone(), expansions.clone());
+        expansions
+    }
+
+    /// Posting list keys for every distinct term in the document's text, named fields and text
+    /// metadata, as analyzed by `config`, along with every fuzzy query term that's within edit
+    /// distance of one of the words in its text and fields
+    pub(crate) fn term_keys(&self, analyzer_id: u64, config: &AnalyzerConfig) -> Vec<u64> {
+        let analyzer = Analyzer::new(config);
+        let key = |term: &str| prefilter::term_key(analyzer_id, term);
+        let mut keys = Vec::new();
+        for field in self.texts() {
+            let tokens = self.tokens(&analyzer, field);
+            let expansions = self.expansions(&analyzer, field);
+            keys.extend(tokens.iter().map(|token| key(&token.text)));
+            keys.extend(expansions.keys().map(|term| key(term)));
+        }
+        for text in self.metadata_texts() {
+            keys.extend(analyzer.analyze(text).iter().map(|token| key(&token.text)));
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+}
+
+/// What an evaluation's clauses are matched against
+#[derive(Clone, Copy)]
+enum Target<'d> {
+    /// The document's main text (`None`), or one of its named fields
+    Text(Option<&'d str>),
+    /// A metadata value, which terms and phrases have to equal rather than appear in
+    Metadata(&'d MetadataValue),
+}
+
+/// A single query being evaluated against a single document
+struct Evaluation<'a, 'd> {
+    searcher: &'a Searcher,
+    analyzer: &'a Analyzer<'a>,
+    document: &'a Document<'d>,
+    patterns: &'a Patterns,
+    scoring: ScoringModel,
+    /// The shard's statistics for the query's analyzer, if it's collected any
+    stats: Option<&'a TermStats>,
+    target: Target<'d>,
+    // Only fetched once a term-based clause needs it, fuzzy clauses work on the raw text
+    tokens: OnceCell<Rc<[Token]>>,
+    /// Where to record how each clause fared, when explaining a match
+    trace: Option<&'a Trace>,
+}
+
+/// Explanations of finished clauses, with a list for each clause still being evaluated to collect
+/// its children in. Starts out with a single list, which ends up holding the root clause.
+type Trace = RefCell<Vec<Vec<ClauseExplanation>>>;
+
+impl Evaluation<'_, '_> {
+    fn tokens(&self) -> &[Token] {
+        self.tokens.get_or_init(|| match self.target {
+            Target::Text(field) => self.document.tokens(self.analyzer, field),
+            Target::Metadata(_) => Rc::from(Vec::new()),
+        })
+    }
+
+    /// Evaluates `node` against the document, returning the combined score and positions of
+    /// everything that matched, or `None` if the document doesn't satisfy the query.
+    fn evaluate(&self, node: &QueryNode) -> Option<MatchInformation> {
+        let Some(trace) = self.trace else {
+            return self.evaluate_clause(node);
+        };
+        trace.borrow_mut().push(Vec::new());
+        let outcome = self.evaluate_clause(node);
+        let mut trace = trace.borrow_mut();
+        let children = trace.pop().unwrap_or_default();
+        let explanation = ClauseExplanation {
+            clause: node.clone(),
+            matched: outcome.is_some(),
+            score: outcome.as_ref().map_or(0.0, |found| found.score),
+            positions: outcome
+                .as_ref()
+                .map(|found| found.positions.clone())
+                .unwrap_or_default(),
+            children,
+        };
+        if let Some(siblings) = trace.last_mut() {
+            siblings.push(explanation);
+        }
+        outcome
+    }
+
+    fn evaluate_clause(&self, node: &QueryNode) -> Option<MatchInformation> {
+        match node {
+            QueryNode::Fuzzy(query) => {
+                let Target::Text(field) = self.target else {
+                    return None;
+                };
+                let found = self.searcher.search_raw(query, self.document.text(field))?;
+                Some(match self.scoring {
+                    ScoringModel::Skim => found,
+                    ScoringModel::Bm25 | ScoringModel::Normalized => MatchInformation {
+                        score: self.fuzzy_bm25(query),
+                        ..found
+                    },
+                })
+            }
+            QueryNode::Term(_) | QueryNode::FuzzyTerm { .. } | QueryNode::Phrase(_) => {
+                if let Target::Metadata(value) = self.target {
+                    return self.match_metadata(value, node);
+                }
+                let occurrences = self.occurrences(node);
+                if occurrences.is_empty() {
+                    return None;
+                }
+                Some(MatchInformation {
+                    score: self.clause_score(node, &occurrences),
+                    positions: self.spans(&occurrences),
+                })
+            }
+            QueryNode::Wildcard(_) | QueryNode::Regex(_) => {
+                if let Target::Metadata(value) = self.target {
+                    return self.match_metadata(value, node);
+                }
+                let (matched, positions) = self.pattern_matches(node)?;
+                let score = match self.scoring {
+                    ScoringModel::Skim => self.self_score(&matched) as f64,
+                    ScoringModel::Bm25 | ScoringModel::Normalized => {
+                        self.bm25(self.pattern_idf(node, &matched), positions.len())
+                    }
+                };
+                Some(MatchInformation { score, positions })
+            }
+            QueryNode::Near {
+                left,
+                right,
+                distance,
+            } => {
+                let tokens = self.tokens();
+                let right_occurrences = self.occurrences(right);
+                let (mut left_matched, mut right_matched) = (Vec::new(), Vec::new());
+                for l in self.occurrences(left) {
+                    for &r in &right_occurrences {
+                        let (l_first, l_last) = (tokens[l[0]].position, tokens[l[1]].position);
+                        let (r_first, r_last) = (tokens[r[0]].position, tokens[r[1]].position);
+                        // Words between the end of one and the start of the other, plus one
+                        let gap = if r_first > l_last {
+                            r_first - l_last
+                        } else if l_first > r_last {
+                            l_first - r_last
+                        } else {
+                            // Overlapping occurrences, e.g. `darcy NEAR/2 darcy` hitting one word
+                            continue;
+                        };
+                        if gap <= *distance as usize {
+                            left_matched.push(l);
+                            right_matched.push(r);
+                        }
+                    }
+                }
+                if left_matched.is_empty() {
+                    return None;
+                }
+                left_matched.dedup();
+                right_matched.sort_unstable();
+                right_matched.dedup();
+                Some(MatchInformation {
+                    score: self.clause_score(left, &left_matched)
+                        + self.clause_score(right, &right_matched),
+                    positions: self.spans(&[left_matched, right_matched].concat()),
+                })
+            }
+            QueryNode::And(clauses) => clauses
+                .iter()
+                .try_fold(MatchInformation::default(), |acc, clause| {
+                    Some(acc.merge(self.evaluate(clause)?))
+                }),
+            QueryNode::Or(clauses) => clauses
+                .iter()
+                .filter_map(|clause| self.evaluate(clause))
+                .reduce(MatchInformation::merge),
+            QueryNode::Not(clause) => match self.evaluate(clause) {
+                Some(_) => None,
+                None => Some(MatchInformation::default()),
+            },
+            QueryNode::Field { field, clause } => {
+                let source = self.document.source;
+                let target = match (
+                    source.fields.get_key_value(field),
+                    source.metadata.get(field),
+                ) {
+                    (Some((field, _)), _) => Target::Text(Some(field.as_str())),
+                    (None, Some(value)) => Target::Metadata(value),
+                    (None, None) => return None,
+                };
+                let scoped = Evaluation {
+                    target,
+                    tokens: OnceCell::new(),
+                    ..*self
+                };
+                // Positions only ever point into the main text
+                Some(MatchInformation {
+                    score: scoped.evaluate(clause)?.score,
+                    positions: Vec::new(),
+                })
+            }
+            QueryNode::Compare { field, op, value } => {
+                let ordering = self.document.source.metadata.get(field)?.compare(value)?;
+                op.holds(ordering).then(MatchInformation::default)
+            }
+            QueryNode::Range {
+                field,
+                lower,
+                upper,
+            } => {
+                let value = self.document.source.metadata.get(field)?;
+                let within = |bound: &Option<RangeBound>, op: fn(&RangeBound) -> Comparison| {
+                    let Some(bound) = bound else {
+                        return Some(true);
+                    };
+                    Some(op(bound).holds(value.compare(&bound.value)?))
+                };
+                (within(lower, RangeBound::lower)? && within(upper, RangeBound::upper)?)
+                    .then(MatchInformation::default)
+            }
+        }
+    }
+
+    /// Matches a term, phrase or pattern against a metadata value. Text has to equal it as a whole
+    /// once both are analyzed, keyword lists need one keyword to, and numbers and timestamps
+    /// compare by value, so `severity:3` matches `3.0`. Fuzzy terms never match metadata.
+    fn match_metadata(&self, value: &MetadataValue, node: &QueryNode) -> Option<MatchInformation> {
+        let texts = match value {
+            MetadataValue::Text(text) => std::slice::from_ref(text),
+            MetadataValue::Keywords(keywords) => keywords.as_slice(),
+            MetadataValue::Number(_) | MetadataValue::Timestamp(_) => {
+                let QueryNode::Term(term) = node else {
+                    return None;
+                };
+                return (value.compare(term)? == Ordering::Equal).then(|| MatchInformation {
+                    score: self.metadata_score(node, self.exact_score(node)),
+                    positions: Vec::new(),
+                });
+            }
+        };
+        let analyzed = |text: &str| {
+            self.analyzer
+                .analyze(text)
+                .into_iter()
+                .map(|token| token.text)
+                .collect::<Vec<_>>()
+        };
+        let score = match node {
+            QueryNode::Term(_) | QueryNode::Phrase(_) => {
+                let clause = self
+                    .analyze_clause(node)
+                    .into_iter()
+                    .map(|token| token.text)
+                    .collect::<Vec<_>>();
+                texts.iter().find(|text| analyzed(text) == clause)?;
+                self.exact_score(node)
+            }
+            QueryNode::Wildcard(_) => {
+                let pattern = self.patterns.get(node)?;
+                let matched = texts
+                    .iter()
+                    .map(|text| analyzed(text).join(" "))
+                    .find(|text| pattern.is_match(text))?;
+                self.self_score(&matched)
+            }
+            QueryNode::Regex(_) => {
+                let pattern = self.patterns.get(node)?;
+                let matched = texts.iter().find_map(|text| pattern.find(text))?;
+                self.self_score(matched.as_str())
+            }
+            _ => return None,
+        };
+        Some(MatchInformation {
+            score: self.metadata_score(node, score),
+            positions: Vec::new(),
+        })
+    }
+
+    /// A metadata match's score. Under BM25 a value either matches or it doesn't, so every match
+    /// is worth as much as the clause could be.
+    fn metadata_score(&self, node: &QueryNode, skim_score: i64) -> f64 {
+        match self.scoring {
+            ScoringModel::Skim => skim_score as f64,
+            ScoringModel::Bm25 | ScoringModel::Normalized => self.ceiling(node),
+        }
+    }
+
+    /// The first text a wildcard or regex matched, and char positions of everything it matched.
+    /// Wildcards match whole analyzed words, while regexes run over the text as written.
+    fn pattern_matches(&self, node: &QueryNode) -> Option<(String, Vec<[usize; 2]>)> {
+        let pattern = self.patterns.get(node)?;
+        if let QueryNode::Wildcard(_) = node {
+            let tokens = self.tokens();
+            let occurrences = (0..tokens.len())
+                .filter(|&idx| pattern.is_match(&tokens[idx].text))
+                .map(|idx| [idx, idx])
+                .collect::<Vec<_>>();
+            let first = occurrences.first()?;
+            return Some((tokens[first[0]].text.clone(), self.spans(&occurrences)));
+        }
+        let Target::Text(field) = self.target else {
+            return None;
+        };
+        let text = self.document.text(field);
+        let (mut byte_pos, mut char_pos) = (0, 0);
+        let mut first = None;
+        let mut positions = Vec::new();
+        // Empty matches, like a lone `\b`, have no span to report
+        for found in pattern
+            .find_iter(text)
+            .filter(|found| !found.as_str().is_empty())
+        {
+            char_pos += text[byte_pos..found.start()].chars().count();
+            let len = found.as_str().chars().count();
+            positions.push([char_pos, char_pos + len - 1]);
+            (byte_pos, char_pos) = (found.end(), char_pos + len);
+            first.get_or_insert_with(|| found.as_str().to_string());
+        }
+        Some((first?, positions))
+    }
+
+    /// Runs a term or phrase through the query's analyzer
+    fn analyze_clause(&self, node: &QueryNode) -> Vec<Token> {
+        match node {
+            QueryNode::Term(term) | QueryNode::FuzzyTerm { term, .. } => {
+                self.analyzer.analyze(term)
+            }
+            QueryNode::Phrase(words) => self.analyzer.analyze(&words.join(" ")),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Inclusive ranges of token indices where a term or phrase appears in the document.
+    ///
+    /// A term can analyze to several tokens (`us-east`), in which case it's matched as a phrase.
+    /// Tokens have to sit at the same relative positions as in the clause, so a phrase with a
+    /// stopword in the middle still only matches with a word in the gap.
+    fn occurrences(&self, node: &QueryNode) -> Vec<[usize; 2]> {
+        if let QueryNode::FuzzyTerm { term, distance } = node {
+            return self
+                .fuzzy_occurrences(term, *distance)
+                .into_iter()
+                .map(|(idx, _)| [idx, idx])
+                .collect();
+        }
+        let clause = self.analyze_clause(node);
+        let Some(first) = clause.first() else {
+            return Vec::new();
+        };
+        let tokens = self.tokens();
+        let mut occurrences = Vec::new();
+        for (start, token) in tokens.iter().enumerate() {
+            if token.text != first.text {
+                continue;
+            }
+            let mut last = start;
+            let matched = clause[1..].iter().all(|expected| {
+                let position = token.position + expected.position - first.position;
+                let rest = &tokens[last..];
+                match rest.binary_search_by_key(&position, |t| t.position) {
+                    Ok(idx) if rest[idx].text == expected.text => {
+                        last += idx;
+                        true
+                    }
+                    _ => false,
+                }
+            });
+            if matched {
+                occurrences.push([start, last]);
+            }
+        }
+        occurrences
+    }
+
+    /// Token indices within `distance` edits of a fuzzy term, along with how many edits away
+    fn fuzzy_occurrences(&self, term: &str, distance: u32) -> Vec<(usize, u32)> {
+        let Some(term) = fuzzy_terms::fuzzy_token(self.analyzer, term) else {
+            return Vec::new();
+        };
+        let Target::Text(field) = self.target else {
+            return Vec::new();
+        };
+        let expansions = self.document.expansions(self.analyzer, field);
+        let Some(words) = expansions.get(&term) else {
+            return Vec::new();
+        };
+        let words = words
+            .iter()
+            .filter(|(_, edits)| *edits <= distance)
+            .collect::<Vec<_>>();
+        self.tokens()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, token)| {
+                words
+                    .iter()
+                    .find(|(word, _)| *word == token.text)
+                    .map(|(_, edits)| (idx, *edits))
+            })
+            .collect()
+    }
+
+    /// Score for a term, fuzzy term or phrase found at `occurrences` in the document. Fuzzy terms
+    /// lose a share of their score for each edit their closest occurrence needed.
+    fn clause_score(&self, node: &QueryNode, occurrences: &[[usize; 2]]) -> f64 {
+        let QueryNode::FuzzyTerm { term, distance } = node else {
+            return match self.scoring {
+                ScoringModel::Skim => self.exact_score(node) as f64,
+                ScoringModel::Bm25 | ScoringModel::Normalized => {
+                    self.bm25(self.clause_idf(node), occurrences.len())
+                }
+            };
+        };
+        let closest = self
+            .fuzzy_occurrences(term, *distance)
+            .into_iter()
+            .min_by_key(|&(_, edits)| edits);
+        let edits = closest.map(|(_, edits)| edits as i64).unwrap_or_default();
+        let len = term.chars().count() as i64;
+        match self.scoring {
+            ScoringModel::Skim => {
+                (self.exact_score(node) * (len - edits).max(0) / len.max(1)) as f64
+            }
+            ScoringModel::Bm25 | ScoringModel::Normalized => {
+                // Scored as the word it matched, which is what the statistics know about
+                let idf = closest.map_or(0.0, |(idx, _)| self.idf(&self.tokens()[idx].text));
+                let share = (len - edits).max(0) as f64 / len.max(1) as f64;
+                self.bm25(idf, occurrences.len()) * share
+            }
+        }
+    }
+
+    /// Inverse document frequency of an analyzed term
+    fn idf(&self, term: &str) -> f64 {
+        match self.stats {
+            Some(stats) => stats.idf(term),
+            None => corpus::idf(0, 0),
+        }
+    }
+
+    /// Combined inverse document frequency of the words in a term or phrase
+    fn clause_idf(&self, node: &QueryNode) -> f64 {
+        let tokens = self.analyze_clause(node);
+        tokens.iter().map(|token| self.idf(&token.text)).sum()
+    }
+
+    /// Inverse document frequency for a pattern, going by what it first matched. A wildcard
+    /// matches a single token, but a regex can match any text, or none the analyzer would keep.
+    fn pattern_idf(&self, node: &QueryNode, matched: &str) -> f64 {
+        if let QueryNode::Wildcard(_) = node {
+            return self.idf(matched);
+        }
+        let tokens = self.analyzer.analyze(matched);
+        match tokens.is_empty() {
+            true => self.unseen_idf(),
+            false => tokens.iter().map(|token| self.idf(&token.text)).sum(),
+        }
+    }
+
+    fn unseen_idf(&self) -> f64 {
+        self.stats.map_or(corpus::idf(0, 0), TermStats::unseen_idf)
+    }
+
+    /// BM25 for something found `frequency` times in the text being evaluated
+    fn bm25(&self, idf: f64, frequency: usize) -> f64 {
+        let Target::Text(field) = self.target else {
+            return 0.0;
+        };
+        let length = self.tokens().len();
+        let average_length = self
+            .stats
+            .and_then(|stats| stats.average_length(field))
+            .unwrap_or(length as f64);
+        corpus::bm25(idf, frequency, length, average_length)
+    }
+
+    /// BM25 for a plain fuzzy query: each of its words found whole in the text scores as a term
+    fn fuzzy_bm25(&self, query: &str) -> f64 {
+        let tokens = self.tokens();
+        let mut words = self.analyzer.analyze(query);
+        words.sort_unstable_by(|a, b| a.text.cmp(&b.text));
+        words.dedup_by(|a, b| a.text == b.text);
+        words
+            .iter()
+            .map(|word| {
+                let frequency = tokens
+                    .iter()
+                    .filter(|token| token.text == word.text)
+                    .count();
+                match frequency {
+                    0 => 0.0,
+                    frequency => self.bm25(self.idf(&word.text), frequency),
+                }
+            })
+            .sum()
+    }
+
+    /// The most BM25 could score `node`, were every word in it to saturate. Alternatives of an OR
+    /// count as their best, so a match on one alternative isn't held back by the rest.
+    fn ceiling(&self, node: &QueryNode) -> f64 {
+        let saturated = |idf: f64| idf * (corpus::K1 + 1.0);
+        match node {
+            QueryNode::Fuzzy(query) => saturated(
+                self.analyzer
+                    .analyze(query)
+                    .iter()
+                    .map(|token| self.idf(&token.text))
+                    .sum(),
+            ),
+            QueryNode::Term(_) | QueryNode::FuzzyTerm { .. } | QueryNode::Phrase(_) => {
+                saturated(self.clause_idf(node))
+            }
+            QueryNode::Wildcard(_) | QueryNode::Regex(_) => saturated(self.unseen_idf()),
+            QueryNode::Near { left, right, .. } => self.ceiling(left) + self.ceiling(right),
+            QueryNode::And(clauses) => clauses.iter().map(|clause| self.ceiling(clause)).sum(),
+            QueryNode::Or(clauses) => clauses
+                .iter()
+                .map(|clause| self.ceiling(clause))
+                .fold(0.0, f64::max),
+            QueryNode::Field { clause, .. } => self.ceiling(clause),
+            QueryNode::Not(_) | QueryNode::Compare { .. } | QueryNode::Range { .. } => 0.0,
+        }
+    }
+
+    /// Evaluates a whole query, ready to compare to its threshold: any match is scored on the
+    /// query's model, with its positions in order. Normalized scores are the share of the query's
+    /// ceiling the match reached.
+    fn evaluate_query(&self, node: &QueryNode) -> Option<MatchInformation> {
+        let mut found = self.evaluate(node)?;
+        if self.scoring == ScoringModel::Normalized {
+            let ceiling = self.ceiling(node);
+            // Matching several alternatives of an OR can take a score past its ceiling
+            found.score = match ceiling > 0.0 {
+                true => (found.score / ceiling).min(1.0),
+                false => 0.0,
+            };
+        }
+        found.positions.sort_unstable();
+        found.positions.dedup();
+        Some(found)
+    }
+
+    /// Converts ranges of token indices into char positions in the document
+    fn spans(&self, token_ranges: &[[usize; 2]]) -> Vec<[usize; 2]> {
+        let tokens = self.tokens();
+        token_ranges
+            .iter()
+            .map(|[first, last]| [tokens[*first].span[0], tokens[*last].span[1]])
+            .collect()
+    }
+
+    /// The score skim gives a term or phrase matching itself perfectly. Exact hits are scored like
+    /// this so their thresholds stay on the same scale as fuzzy queries.
+    fn exact_score(&self, node: &QueryNode) -> i64 {
+        let text = self
+            .analyze_clause(node)
+            .into_iter()
+            .map(|token| token.text)
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.self_score(&text)
+    }
+
+    /// The score skim gives text matching itself, which is also how patterns are scored, on what
+    /// they first matched
+    fn self_score(&self, text: &str) -> i64 {
+        self.searcher
+            .matcher
+            .fuzzy_match(text, text)
+            .unwrap_or_default()
+    }
+}
+
+impl Searcher {
+    pub fn new() -> Self {
+        Self {
+            matcher: SkimMatcherV2::default().ignore_case().use_cache(true),
+        }
+    }
+
+    pub(crate) fn search_raw(&self, query: &str, text: &str) -> Option<MatchInformation> {
+        self.matcher
+            .fuzzy_indices(text, query)
+            .map(|(score, positions)| {
+                let indices = get_contiguous(&positions);
+                MatchInformation {
+                    score: score as f64,
+                    positions: indices,
+                }
+            })
+    }
+
+    pub(crate) fn search(&self, query: &CompiledQuery, document: &Document) -> Option<IndexData> {
+        let text_src = document.source;
+        let analyzer = Analyzer::new(&query.query.analyzer);
+        let evaluation = self.evaluation(query, &analyzer, document, None);
+        let query = &query.query;
+        evaluation
+            .evaluate_query(&query.parsed)
+            .filter(|match_info| match_info.score >= query.score_threshold)
+            .map(|match_data| {
+                event!(
+                    Level::INFO,
+                    message = "Running search on text",
+                    query.id,
+                    query.query
+                );
+                IndexData {
+                    source_query: query.id,
+                    name: text_src.name.clone(),
+                    key: IndexData::result_key(query.id, text_src.id),
+                    sequence: 0,
+                    document_id: text_src.id,
+                    match_indices: match_data.positions,
+                    score: match_data.score,
+                    scoring: query.scoring,
+                    backfilled: false,
+                }
+            })
+    }
+
+    /// Runs a query against a document the way [`Searcher::search`] does, recording how each of
+    /// its clauses fared instead of producing a result
+    pub(crate) fn explain(&self, query: &CompiledQuery, document: &Document) -> Explanation {
+        let analyzer = Analyzer::new(&query.query.analyzer);
+        let trace = RefCell::new(vec![Vec::new()]);
+        let evaluation = self.evaluation(query, &analyzer, document, Some(&trace));
+        let query = &query.query;
+        let found = evaluation.evaluate_query(&query.parsed);
+        let clauses = trace
+            .into_inner()
+            .pop()
+            .and_then(|mut root| root.pop())
+            .expect("The root clause is always explained");
+        let score = found.as_ref().map(|found| found.score);
+        Explanation {
+            query: query.clone(),
+            clauses,
+            score,
+            score_threshold: query.score_threshold,
+            passed: score.is_some_and(|score| score >= query.score_threshold),
+            match_indices: found.map(|found| found.positions).unwrap_or_default(),
+        }
+    }
+
+    fn evaluation<'a, 'd>(
+        &'a self,
+        query: &'a CompiledQuery,
+        analyzer: &'a Analyzer,
+        document: &'a Document<'d>,
+        trace: Option<&'a Trace>,
+    ) -> Evaluation<'a, 'd> {
+        Evaluation {
+            searcher: self,
+            analyzer,
+            document,
+            patterns: &query.patterns,
+            scoring: query.query.scoring,
+            stats: document
+                .corpus_stats
+                .and_then(|stats| stats.get(&query.query.analyzer)),
+            target: Target::Text(None),
+            tokens: OnceCell::new(),
+            trace,
+        }
+    }
+}
+
+/// Checks every term and phrase in the query still means something after analysis, returning a
+/// description of the first problem found.
+fn validate(query: &PersistentQuery) -> Result<(), String> {
+    fn walk(analyzer: &Analyzer, node: &QueryNode) -> Result<(), String> {
+        let only_stopwords = |text: &str| format!("\"{text}\" is made up entirely of stopwords");
+        match node {
+            QueryNode::Fuzzy(_) | QueryNode::Wildcard(_) | QueryNode::Regex(_) => Ok(()),
+            QueryNode::Term(term) if analyzer.analyze(term).is_empty() => Err(only_stopwords(term)),
+            QueryNode::Term(_) => Ok(()),
+            QueryNode::FuzzyTerm { term, .. } => match analyzer.analyze(term).len() {
+                0 => Err(only_stopwords(term)),
+                1 => Ok(()),
+                _ => Err(format!(
+                    "\"{term}\" has to be a single word to match it with an edit distance"
+                )),
+            },
+            QueryNode::Phrase(words) => {
+                let phrase = words.join(" ");
+                match analyzer.analyze(&phrase).is_empty() {
+                    true => Err(only_stopwords(&phrase)),
+                    false => Ok(()),
+                }
+            }
+            QueryNode::Near { left, right, .. } => {
+                walk(analyzer, left)?;
+                walk(analyzer, right)
+            }
+            QueryNode::And(clauses) | QueryNode::Or(clauses) => {
+                clauses.iter().try_for_each(|clause| walk(analyzer, clause))
+            }
+            QueryNode::Not(clause) | QueryNode::Field { clause, .. } => walk(analyzer, clause),
+            QueryNode::Compare { .. } | QueryNode::Range { .. } => Ok(()),
+        }
+    }
+    walk(&Analyzer::new(&query.analyzer), &query.parsed)
+}
+
+fn get_contiguous(v: &[usize]) -> Vec<[usize; 2]> {
+    if v.len() < 2 {
+        return Vec::new();
+    }
+    let mut arr: Vec<[usize; 2]> = Vec::with_capacity(v.len());
+
+    let first = v.first().unwrap();
+    let (mut minimum, mut maximum) = (first, first);
+    for current in v.iter() {
+        let difference = current - maximum;
+        (minimum, maximum) = match difference {
+            1 => {
+                maximum = current;
+                (minimum, maximum)
+            }
+            2.. => {
+                arr.push([*minimum, *maximum]);
+                minimum = current;
+                maximum = current;
+                (minimum, maximum)
+            }
+            _ => (minimum, maximum),
+        };
+    }
+    arr.push([*minimum, *maximum]);
+    arr.shrink_to_fit();
+    arr
+}
+
+#[cfg(test)]
+mod search_tests {
+
+    use super::*;
+    use chrono::{Duration, Utc};
+    use lib::analysis::{Language, Stopwords};
+    use unicode_segmentation::UnicodeSegmentation;
+
+    /// Compiles a query the way the shards get it when it's submitted
+    fn compile(query: &PersistentQuery) -> CompiledQuery {
+        CompiledQuery::new(query.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_fuzzy_matching() {
+        let src_text = "this is some text";
+        let query = "text";
+        let matcher = SkimMatcherV2::default();
+        let res = matcher.fuzzy_match(src_text, query);
+        dbg!(res);
+        assert!(res.is_some())
+    }
+
+    #[test]
+    fn test_search_large_text() {
+        let matcher = SkimMatcherV2::default();
+        let query = "prid n prejudice";
+        let austen = include_str!("../../data/pride_and_prejudice.txt");
+        let frankenstein = include_str!("../../data/frankenstein.txt");
+        if let Some((score, indices)) = matcher.fuzzy_indices(austen, query) {
+            println!("Score: {score}");
+            let y = indices.get(0..=2).unwrap();
+            dbg!(y);
+            let x: String = austen
+                .graphemes(true)
+                .skip(y[0])
+                .take(y[2] - y[0])
+                .collect();
+            dbg!(x);
+        }
+        let res2 = matcher.fuzzy_match(frankenstein, query);
+        dbg!(res2);
+    }
+
+    #[test]
+    fn test_boolean_query() {
+        let searcher = Searcher::new();
+        let query =
+            PersistentQuery::new(1, "alerts", "(outage OR incident) AND NOT test", 1.0).unwrap();
+        let matches = |text: &str| {
+            let source = TextSource::new(text, "doc".to_string());
+            searcher.search(&compile(&query), &Document::new(&source))
+        };
+
+        let hit = matches("Major Outage reported in us-east").unwrap();
+        assert_eq!(hit.match_indices, [[6, 11]]);
+        assert!(matches("An incident occurred").is_some());
+        assert!(matches("This incident is only a test").is_none());
+        // Terms are whole words, not fuzzy subsequences
+        assert!(matches("our outages were testy").is_none());
+    }
+
+    #[test]
+    fn test_resubmitted_documents_match_the_same_way() {
+        let searcher = Searcher::new();
+        let query = PersistentQuery::new(1, "darcy", "mr darcy", 1.0).unwrap();
+        let text = "To Mr. Darcy it was welcome intelligence";
+        let first = TextSource::from_content(text, "austen".to_string());
+        let second = TextSource::from_content(text, "austen".to_string());
+        assert_eq!(first.id, second.id);
+        assert_ne!(
+            first.id,
+            TextSource::from_content(text, "austen2".to_string()).id
+        );
+
+        let first_hit = searcher
+            .search(&compile(&query), &Document::new(&first))
+            .unwrap();
+        let second_hit = searcher
+            .search(&compile(&query), &Document::new(&second))
+            .unwrap();
+        assert_eq!(first_hit.key, second_hit.key);
+        assert_eq!(first_hit.key, IndexData::result_key(1, first.id));
+        assert_ne!(first_hit.key, IndexData::result_key(2, first.id));
+    }
+
+    #[test]
+    fn test_phrase_and_proximity() {
+        let searcher = Searcher::new();
+        let text = "To Mr. Darcy it was welcome intelligence—Elizabeth had been at Netherfield";
+        let source = TextSource::new(text, "austen".to_string());
+        let document = Document::new(&source);
+        let search = |q: &str| {
+            let query = PersistentQuery::new(1, "q", q, 1.0).unwrap();
+            searcher.search(&compile(&query), &document)
+        };
+
+        let phrase = search("\"mr darcy\"").unwrap();
+        assert_eq!(phrase.match_indices, [[3, 11]]);
+        assert!(search("\"darcy mr\"").is_none());
+
+        let near = search("elizabeth NEAR/5 darcy").unwrap();
+        assert_eq!(near.match_indices, [[7, 11], [41, 49]]);
+        assert!(search("darcy NEAR/4 elizabeth").is_none());
+        assert!(search("\"mr darcy\" NEAR/1 it").is_some());
+    }
+
+    #[test]
+    fn test_query_analyzer() {
+        let searcher = Searcher::new();
+        let source = TextSource::new(
+            "Several outages were reported at the Bank of England",
+            "news".to_string(),
+        );
+        let document = Document::new(&source);
+        let analyzer = AnalyzerConfig {
+            stemmer: Some(Language::English),
+            stopwords: Stopwords::English,
+            ..AnalyzerConfig::default()
+        };
+        let search = |q: &str, analyzer: &AnalyzerConfig| {
+            let query = PersistentQuery::new(1, "q", q, 1.0)
+                .unwrap()
+                .with_analyzer(analyzer.clone());
+            searcher.search(&compile(&query), &document)
+        };
+
+        assert!(search("outage AND reports", &AnalyzerConfig::default()).is_none());
+        let stemmed = search("outage AND reports", &analyzer).unwrap();
+        assert_eq!(stemmed.match_indices, [[8, 14], [21, 28]]);
+        // The dropped stopword still takes up a position in the phrase
+        assert!(search("\"bank of england\"", &analyzer).is_some());
+        assert!(search("\"bank england\"", &analyzer).is_none());
+
+        let query = PersistentQuery::new(1, "q", "the AND bank", 1.0)
+            .unwrap()
+            .with_analyzer(analyzer);
+        assert_eq!(
+            validate(&query),
+            Err("\"the\" is made up entirely of stopwords".to_string())
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_terms() {
+        let searcher = Searcher::new();
+        let source = TextSource::new(
+            "To Mr. Darcey it was welcome intelligence",
+            "austen".to_string(),
+        );
+        let queries = [
+            PersistentQuery::new(1, "q", "darcy~1", 1.0).unwrap(),
+            PersistentQuery::new(2, "q", "darcy~0", 1.0).unwrap(),
+            PersistentQuery::new(3, "q", "darcey~0 NEAR/3 welcome", 1.0).unwrap(),
+        ];
+        let index = FuzzyTermIndex::build(&queries);
+        let document = Document::new(&source).with_fuzzy_terms(&index);
+
+        let one_edit = searcher.search(&compile(&queries[0]), &document).unwrap();
+        assert_eq!(one_edit.match_indices, [[7, 12]]);
+        assert!(searcher.search(&compile(&queries[1]), &document).is_none());
+        let exact = searcher.search(&compile(&queries[2]), &document).unwrap();
+        // An edit costs a fifth of the score of a five letter word
+        let darcy = searcher.matcher.fuzzy_match("darcy", "darcy").unwrap();
+        assert_eq!(one_edit.score, (darcy * 4 / 5) as f64);
+        assert!(exact.score > one_edit.score);
+    }
+
+    #[test]
+    fn test_fields_and_metadata() {
+        let searcher = Searcher::new();
+        let published = lib::metadata::parse_timestamp("2026-01-03T09:30:00Z").unwrap();
+        let source = TextSource::new("Rates were left unchanged on Thursday", "news".to_string())
+            .with_field("title", "Bank of England holds rates")
+            .with_metadata("source", MetadataValue::Text("Reuters".to_string()))
+            .with_metadata("severity", MetadataValue::Number(3.0))
+            .with_metadata("published", MetadataValue::Timestamp(published))
+            .with_metadata(
+                "tags",
+                MetadataValue::Keywords(vec!["markets".to_string(), "central banks".to_string()]),
+            );
+        let document = Document::new(&source);
+        let search = |q: &str| {
+            let query = PersistentQuery::new(1, "q", q, 1.0).unwrap();
+            searcher.search(&compile(&query), &document)
+        };
+
+        // Field matches count towards the score, but only the main text has positions
+        let hit = search("title:\"bank of england\" AND rates").unwrap();
+        assert_eq!(hit.match_indices, [[0, 4]]);
+        assert!(search("title:thursday").is_none());
+        assert!(search("body:rates").is_none());
+        assert!(search("thursday AND NOT title:rates").is_none());
+
+        assert!(search("source:reuters AND published>2026-01-01").is_some());
+        assert!(search("source:reuters AND published>=2026-01-04").is_none());
+        assert!(search("source:(ap OR reuters)").is_some());
+        assert!(search("tags:\"central banks\"").is_some());
+        // Text metadata has to match as a whole
+        assert!(search("tags:central").is_none());
+        assert!(search("severity:3 AND severity<5").is_some());
+        assert!(search("rates AND severity>3").is_none());
+        assert!(search("rates AND missing>3").is_none());
+    }
+
+    #[test]
+    fn test_metadata_ranges() {
+        let searcher = Searcher::new();
+        let hours_ago = |hours| MetadataValue::Timestamp(Utc::now() - Duration::hours(hours));
+        let listing = |price, published| {
+            TextSource::new("Two bedroom flat to let", "listing".to_string())
+                .with_metadata("price", MetadataValue::Number(price))
+                .with_metadata("published", published)
+        };
+        let search = |q: &str, source: &TextSource| {
+            let query = PersistentQuery::new(1, "q", q, 1.0).unwrap();
+            searcher
+                .search(&compile(&query), &Document::new(source))
+                .is_some()
+        };
+
+        let query = "flat AND price:[1000 TO 1500} AND published>now-24h";
+        assert!(search(query, &listing(1000.0, hours_ago(2))));
+        assert!(!search(query, &listing(1500.0, hours_ago(2))));
+        assert!(!search(query, &listing(1200.0, hours_ago(30))));
+        assert!(search(
+            "flat AND (price:{* TO 900] OR published:[now-1h TO now])",
+            &listing(1200.0, hours_ago(0))
+        ));
+        assert!(!search(
+            "flat AND NOT price:[1000 TO *]",
+            &listing(1200.0, hours_ago(0))
+        ));
+        // Text can't be compared against a number
+        let text_price = listing(0.0, MetadataValue::Text("cheap".to_string()));
+        assert!(!search("flat AND published:[1000 TO *]", &text_price));
+    }
+
+    #[test]
+    fn test_regex_and_wildcards() {
+        let searcher = Searcher::new();
+        let source = TextSource::new(
+            "Paged for INC-004211: Microsoft login errors",
+            "ticket".to_string(),
+        )
+        .with_field("title", "Follow up on INC-004211")
+        .with_metadata("tags", MetadataValue::Keywords(vec!["sev-2".to_string()]));
+        let document = Document::new(&source);
+        let search = |q: &str| {
+            let query = PersistentQuery::new(1, "q", q, 1.0).unwrap();
+            searcher.search(&compile(&query), &document)
+        };
+
+        // Regexes see the text as written, so they can span punctuation
+        let hit = search(r"/INC-\d{6}/").unwrap();
+        assert_eq!(hit.match_indices, [[10, 19]]);
+        assert!(search(r"/INC-\d{7}/").is_none());
+        assert!(search(r"title:/up on INC/").is_some());
+        assert!(search("tags:/^sev-[12]$/").is_some());
+        // Wildcards match whole words after analysis
+        let hit = search("micro* AND login").unwrap();
+        assert_eq!(hit.match_indices, [[22, 30], [32, 36]]);
+        assert!(search("login AND micr?").is_none());
+        assert!(search("er*rs AND NOT log?n").is_none());
+    }
+
+    #[test]
+    fn test_scoring_models() {
+        let searcher = Searcher::new();
+        let config = AnalyzerConfig::default();
+        let sources = [
+            TextSource::new("The bank raised rates", "a".to_string()),
+            TextSource::new("The bank cut rates again", "b".to_string()),
+            TextSource::new("Floods along the river bank", "c".to_string()),
+            TextSource::new("Bank holiday traffic on the river road", "d".to_string()),
+        ];
+        let mut stats = CorpusStats::default();
+        for source in &sources {
+            stats.add(&Document::new(source), [&config]);
+        }
+        let score = |q: &str, scoring, source: &TextSource| {
+            let query = PersistentQuery::new(1, "q", q, 0.01)
+                .unwrap()
+                .with_scoring(scoring);
+            let document = Document::new(source).with_corpus_stats(&stats);
+            searcher
+                .search(&compile(&query), &document)
+                .map(|hit| hit.score)
+        };
+
+        // Rarer words count for more, and shorter texts beat longer ones
+        let bank = score("bank AND rates", ScoringModel::Bm25, &sources[0]).unwrap();
+        let river = score("river AND bank", ScoringModel::Bm25, &sources[2]).unwrap();
+        assert!(
+            score("bank", ScoringModel::Bm25, &sources[2])
+                < score("river", ScoringModel::Bm25, &sources[2])
+        );
+        assert!(
+            score("river", ScoringModel::Bm25, &sources[2])
+                > score("river", ScoringModel::Bm25, &sources[3])
+        );
+        assert!(bank > 0.0 && river > 0.0);
+        // Plain queries still match fuzzily, but score the words found whole
+        assert_eq!(
+            score("river bank", ScoringModel::Bm25, &sources[2]),
+            Some(river)
+        );
+
+        let normalized = |q: &str, source| score(q, ScoringModel::Normalized, source).unwrap();
+        for q in [
+            "bank AND rates",
+            "river OR rates",
+            "(bank NEAR/2 rates) OR /ra.es/",
+        ] {
+            let score = normalized(q, &sources[1]);
+            assert!(score > 0.0 && score <= 1.0, "{q}: {score}");
+        }
+        assert!(
+            normalized("bank OR rates", &sources[1]) > normalized("bank OR river", &sources[1])
+        );
+        assert!(score("rates AND NOT cut", ScoringModel::Normalized, &sources[1]).is_none());
+
+        // Skim scores are unchanged
+        let skim = searcher
+            .matcher
+            .fuzzy_match(&sources[0].data, "bank")
+            .unwrap() as f64;
+        assert_eq!(score("bank", ScoringModel::Skim, &sources[0]), Some(skim));
+    }
+
+    #[test]
+    fn test_explain_clauses() {
+        let searcher = Searcher::new();
+        let query =
+            PersistentQuery::new(1, "alerts", "(outage OR incident) AND NOT test", 1.0).unwrap();
+        let source = TextSource::new("Major Outage reported in us-east", "doc".to_string());
+        let document = Document::new(&source);
+        let explanation = searcher.explain(&compile(&query), &document);
+        let hit = searcher.search(&compile(&query), &document).unwrap();
+        assert!(explanation.passed);
+        assert_eq!(explanation.score, Some(hit.score));
+        assert_eq!(explanation.match_indices, hit.match_indices);
+        let [either, not] = &explanation.clauses.children[..] else {
+            panic!("Expected both clauses of the AND");
+        };
+        let outcomes = either.children.iter().map(|clause| clause.matched);
+        assert_eq!(outcomes.collect::<Vec<_>>(), [true, false]);
+        assert_eq!(either.children[0].positions, [[6, 11]]);
+        assert!(not.matched && !not.children[0].matched);
+
+        // Matching clauses that fall short of the threshold still get a score
+        let strict = PersistentQuery::new(1, "alerts", "outage", 1000.0).unwrap();
+        let explanation = searcher.explain(&compile(&strict), &document);
+        assert!(explanation.score.is_some() && !explanation.passed);
+
+        let source = TextSource::new("An incident during a test", "doc".to_string());
+        let explanation = searcher.explain(&compile(&query), &Document::new(&source));
+        assert_eq!(explanation.score, None);
+        assert!(!explanation.passed && !explanation.clauses.matched);
+        let not = &explanation.clauses.children[1];
+        assert!(!not.matched && not.children[0].matched);
+    }
+
+    #[test]
+    fn test_get_contiguous_blocks() {
+        let v = [1, 2, 3, 5, 6];
+        let expected = get_contiguous(&v);
+        assert_eq!(expected, [[1, 3], [5, 6]])
+    }
+
+    fn get_contiguous(v: &[usize]) -> Vec<[usize; 2]> {
+        let mut arr: Vec<[usize; 2]> = Vec::with_capacity(v.len());
+
+        let first = v.first().unwrap();
+        let (mut minimum, mut maximum) = (first, first);
+        for current in v.iter() {
+            let difference = current - maximum;
+            (minimum, maximum) = match difference {
+                1 => {
+                    maximum = current;
+                    (minimum, maximum)
+                }
+                2.. => {
+                    arr.push([*minimum, *maximum]);
+                    minimum = current;
+                    maximum = current;
+                    (minimum, maximum)
+                }
+                _ => (minimum, maximum),
+            };
+        }
+        arr.push([*minimum, *maximum]);
+        arr
+    }
+}
@@ -0,0 +1,145 @@
+use lib::{query::QueryNode, PersistentQuery};
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+
+use super::analyzer::Analyzer;
+
+/// Most memory a pattern's compiled program can take. The regex engine never backtracks, so
+/// matching stays linear in the text, but counted repetitions like `(a{100}){100}` still blow up
+/// the program - those are rejected rather than compiled.
+const PATTERN_SIZE_LIMIT: usize = 1 << 20;
+
+/// Most memory the lazy DFA behind each pattern can cache states in before it starts throwing
+/// them away and slowing down
+const PATTERN_DFA_SIZE_LIMIT: usize = 1 << 20;
+
+/// Every regex and wildcard clause in a query, compiled when the query's submitted and kept
+/// alongside it in the shard, so documents never pay for compilation
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Patterns {
+    compiled: HashMap<QueryNode, Regex>,
+}
+
+impl Patterns {
+    /// Compiles the query's patterns, or describes the first one that couldn't be
+    pub(crate) fn compile(query: &PersistentQuery) -> Result<Self, String> {
+        let analyzer = Analyzer::new(&query.analyzer);
+        let mut compiled = HashMap::new();
+        for node in pattern_clauses(&query.parsed) {
+            if compiled.contains_key(node) {
+                continue;
+            }
+            let regex = match node {
+                QueryNode::Regex(pattern) => compile(pattern, pattern)?,
+                QueryNode::Wildcard(pattern) => {
+                    compile(pattern, &wildcard_regex(&analyzer, pattern))?
+                }
+                _ => unreachable!("only patterns are collected"),
+            };
+            compiled.insert(node.clone(), regex);
+        }
+        Ok(Self { compiled })
+    }
+
+    /// The compiled form of a regex or wildcard clause from the query these were compiled for
+    pub(crate) fn get(&self, node: &QueryNode) -> Option<&Regex> {
+        self.compiled.get(node)
+    }
+}
+
+fn compile(written: &str, regex: &str) -> Result<Regex, String> {
+    let compiled = RegexBuilder::new(regex)
+        .size_limit(PATTERN_SIZE_LIMIT)
+        .dfa_size_limit(PATTERN_DFA_SIZE_LIMIT)
+        .build()
+        .map_err(|err| match err {
+            regex::Error::CompiledTooBig(_) => format!("pattern \"{written}\" is too expensive"),
+            err => format!("pattern \"{written}\" isn't valid: {err}"),
+        })?;
+    // Anything that matches nothing would match every document
+    if compiled.is_match("") {
+        return Err(format!("pattern \"{written}\" matches empty text"));
+    }
+    Ok(compiled)
+}
+
+/// A wildcard as an anchored regex over a single token. The literal parts go through the query's
+/// analyzer (without stemming, which would mangle word fragments), so `Micro*` still matches the
+/// lowercased `microsoft`.
+fn wildcard_regex(analyzer: &Analyzer, pattern: &str) -> String {
+    let mut regex = String::from("^(?s:");
+    let mut literal = String::new();
+    for c in pattern.chars() {
+        if c != '*' && c != '?' {
+            literal.push(c);
+            continue;
+        }
+        regex.push_str(&regex::escape(&analyzer.normalize_fragment(&literal)));
+        literal.clear();
+        regex.push_str(if c == '*' { ".*" } else { "." });
+    }
+    regex.push_str(&regex::escape(&analyzer.normalize_fragment(&literal)));
+    regex.push_str(")$");
+    regex
+}
+
+/// Every regex and wildcard clause in a query
+pub(crate) fn pattern_clauses(node: &QueryNode) -> Vec<&QueryNode> {
+    match node {
+        QueryNode::Regex(_) | QueryNode::Wildcard(_) => vec![node],
+        QueryNode::Fuzzy(_)
+        | QueryNode::Term(_)
+        | QueryNode::FuzzyTerm { .. }
+        | QueryNode::Phrase(_)
+        | QueryNode::Compare { .. }
+        | QueryNode::Range { .. } => Vec::new(),
+        QueryNode::Near { left, right, .. } => {
+            let mut clauses = pattern_clauses(left);
+            clauses.extend(pattern_clauses(right));
+            clauses
+        }
+        QueryNode::And(clauses) | QueryNode::Or(clauses) => {
+            clauses.iter().flat_map(pattern_clauses).collect()
+        }
+        QueryNode::Not(clause) | QueryNode::Field { clause, .. } => pattern_clauses(clause),
+    }
+}
+
+#[cfg(test)]
+mod patterns_tests {
+    use super::*;
+
+    fn compile_query(query: &str) -> Result<Patterns, String> {
+        Patterns::compile(&PersistentQuery::new(1, "q", query, 1.0).unwrap())
+    }
+
+    #[test]
+    fn test_wildcards_match_whole_tokens() {
+        let query = PersistentQuery::new(1, "q", "Micro* OR colo?r", 1.0).unwrap();
+        let patterns = Patterns::compile(&query).unwrap();
+        let micro = patterns
+            .get(&QueryNode::Wildcard("Micro*".to_string()))
+            .unwrap();
+        assert!(micro.is_match("microsoft") && micro.is_match("micro"));
+        assert!(!micro.is_match("amicrobe"));
+        let colour = patterns
+            .get(&QueryNode::Wildcard("colo?r".to_string()))
+            .unwrap();
+        assert!(colour.is_match("colour") && !colour.is_match("color"));
+    }
+
+    #[test]
+    fn test_rejects_expensive_and_empty_patterns() {
+        assert!(compile_query(r"a AND /INC-\d{6}/").is_ok());
+        let too_big = compile_query(r"/(\w{100}){100}/").unwrap_err();
+        assert!(too_big.contains("too expensive"), "{too_big}");
+        // The regex engine has no backtracking to support these with
+        assert!(compile_query(r"/(a)\1/")
+            .unwrap_err()
+            .contains("isn't valid"));
+        assert!(compile_query("/a*/")
+            .unwrap_err()
+            .contains("matches empty text"));
+        assert!(compile_query("a AND *").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use lib::{analysis::AnalyzerConfig, metadata, query::QueryNode, PersistentQuery};
+use xxhash_rust::xxh3::{xxh3_64, xxh3_64_with_seed};
+
+use super::{analyzer::Analyzer, fuzzy_terms::fuzzy_token};
+
+/// Posting list key for queries with no required terms, which have to be run against every document.
+///
+/// A real term hashing to this just means its queries get run against everything, which is slower but
+/// still correct.
+pub(crate) const UNINDEXED: u64 = 0;
+
+/// Identifies an analyzer config, so terms produced by different analyzers don't share posting lists.
+///
+/// Hashes the config's JSON with xxh3 rather than going through `Hash`, so the id is the same from
+/// one build to the next.
+pub(crate) fn analyzer_id(config: &AnalyzerConfig) -> u64 {
+    let json = serde_json::to_vec(config).expect("Analyzer configs always serialize");
+    xxh3_64(&json)
+}
+
+/// Posting list key for an analyzed term. Collisions only add candidates, which are still checked
+/// by the full search, so they never cost a match.
+pub(crate) fn term_key(analyzer_id: u64, term: &str) -> u64 {
+    xxh3_64_with_seed(term.as_bytes(), analyzer_id)
+}
+
+/// Posting list keys for a query: a document has to contain at least one of these terms for the
+/// query to have any chance of matching it.
+pub(crate) fn index_keys(query: &PersistentQuery) -> Vec<u64> {
+    let analyzer = Analyzer::new(&query.analyzer);
+    match required_terms(&analyzer, &query.parsed) {
+        Some(terms) => {
+            let id = analyzer_id(&query.analyzer);
+            let mut keys = terms
+                .iter()
+                .map(|term| term_key(id, term))
+                .collect::<Vec<_>>();
+            keys.sort_unstable();
+            keys.dedup();
+            keys
+        }
+        None => vec![UNINDEXED],
+    }
+}
+
+/// Analyzed terms at least one of which must be present for `node` to match, or `None` if there's
+/// no such set - fuzzy clauses can match text that contains none of their words, `NOT` clauses
+/// match documents by what they lack, comparisons match values that aren't text at all, and
+/// patterns match words that can't be known up front.
+///
+/// Documents are keyed on the terms in their named fields and text metadata as well as their main
+/// text, so field clauses are keyed on their inner clause. The exception is a number or date,
+/// which can match metadata of the same name without ever being analyzed.
+fn required_terms(analyzer: &Analyzer, node: &QueryNode) -> Option<Vec<String>> {
+    match node {
+        QueryNode::Fuzzy(_)
+        | QueryNode::Wildcard(_)
+        | QueryNode::Regex(_)
+        | QueryNode::Not(_)
+        | QueryNode::Compare { .. }
+        | QueryNode::Range { .. } => None,
+        QueryNode::Field { clause, .. } if has_comparable_term(clause) => None,
+        QueryNode::Field { clause, .. } => required_terms(analyzer, clause),
+        QueryNode::Term(term) => rarest_token(analyzer, term),
+        // Keyed on the term as written - the fuzzy term index expands documents into the query
+        // terms they're close to before looking up candidates
+        QueryNode::FuzzyTerm { term, .. } => fuzzy_token(analyzer, term).map(|term| vec![term]),
+        QueryNode::Phrase(words) => rarest_token(analyzer, &words.join(" ")),
+        // Both sides have to be present, so either one will do
+        QueryNode::Near { left, right, .. } => narrowest(
+            [left, right]
+                .into_iter()
+                .map(|side| required_terms(analyzer, side)),
+        ),
+        QueryNode::And(clauses) => narrowest(
+            clauses
+                .iter()
+                .map(|clause| required_terms(analyzer, clause)),
+        ),
+        QueryNode::Or(clauses) => clauses.iter().try_fold(Vec::new(), |mut terms, clause| {
+            terms.extend(required_terms(analyzer, clause)?);
+            Some(terms)
+        }),
+    }
+}
+
+fn has_comparable_term(node: &QueryNode) -> bool {
+    match node {
+        QueryNode::Term(term) => metadata::is_comparable(term),
+        QueryNode::Near { left, right, .. } => {
+            has_comparable_term(left) || has_comparable_term(right)
+        }
+        QueryNode::And(clauses) | QueryNode::Or(clauses) => clauses.iter().any(has_comparable_term),
+        QueryNode::Not(clause) | QueryNode::Field { clause, .. } => has_comparable_term(clause),
+        QueryNode::Fuzzy(_)
+        | QueryNode::FuzzyTerm { .. }
+        | QueryNode::Phrase(_)
+        | QueryNode::Wildcard(_)
+        | QueryNode::Regex(_)
+        | QueryNode::Compare { .. }
+        | QueryNode::Range { .. } => false,
+    }
+}
+
+/// Every token in a term or phrase has to be present, so only the longest is indexed - long words
+/// tend to be rare ones, which keeps posting lists short.
+fn rarest_token(analyzer: &Analyzer, text: &str) -> Option<Vec<String>> {
+    analyzer
+        .analyze(text)
+        .into_iter()
+        .max_by_key(|token| token.text.chars().count())
+        .map(|token| vec![token.text])
+}
+
+/// Picks the smallest set out of several that are all required
+fn narrowest(sets: impl Iterator<Item = Option<Vec<String>>>) -> Option<Vec<String>> {
+    sets.flatten().min_by_key(Vec::len)
+}
+
+#[cfg(test)]
+mod prefilter_tests {
+    use super::*;
+
+    fn terms(query: &str) -> Option<Vec<String>> {
+        let query = PersistentQuery::new(1, "q", query, 1.0).unwrap();
+        let mut terms = required_terms(&Analyzer::new(&query.analyzer), &query.parsed)?;
+        terms.sort();
+        Some(terms)
+    }
+
+    #[test]
+    fn test_required_terms() {
+        assert_eq!(terms("mr darcy"), None);
+        assert_eq!(
+            terms("Outage AND reported"),
+            Some(vec!["outage".to_string()])
+        );
+        assert_eq!(
+            terms("(outage OR incident) AND NOT test"),
+            Some(vec!["incident".to_string(), "outage".to_string()])
+        );
+        assert_eq!(
+            terms("outage AND (incident OR failure)"),
+            Some(vec!["outage".to_string()])
+        );
+        assert_eq!(
+            terms("\"bank of england\" NEAR/3 rates"),
+            Some(vec!["england".to_string()])
+        );
+        assert_eq!(
+            terms("Outage~1 AND reported"),
+            Some(vec!["outage".to_string()])
+        );
+        assert_eq!(
+            terms("NOT test AND outage"),
+            Some(vec!["outage".to_string()])
+        );
+        assert_eq!(
+            terms("title:outage AND published>2026-01-01"),
+            Some(vec!["outage".to_string()])
+        );
+        // Could be numeric metadata, which isn't indexed
+        assert_eq!(
+            terms("severity:(3 OR 4) AND source:reuters"),
+            Some(vec!["reuters".to_string()])
+        );
+        assert_eq!(terms("severity:3 OR source:reuters"), None);
+    }
+
+    #[test]
+    fn test_analyzer_ids_are_stable() {
+        // Pinned to the JSON encoding, so a change that moves ids shows up here
+        let json = br#"{"lowercase":true,"stemmer":null,"stopwords":"None"}"#;
+        assert_eq!(analyzer_id(&AnalyzerConfig::default()), xxh3_64(json));
+        let stemmed = AnalyzerConfig {
+            stemmer: Some(lib::analysis::Language::English),
+            ..AnalyzerConfig::default()
+        };
+        assert_ne!(
+            analyzer_id(&stemmed),
+            analyzer_id(&AnalyzerConfig::default())
+        );
+    }
+}
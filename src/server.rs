@@ -1,149 +1,419 @@
 use axum::{
-    extract::{Extension, Path},
-    routing::{get, post},
+    extract::{Extension, Path, Query},
+    http::HeaderMap,
+    routing::{delete, get, post},
     Json, Router,
 };
 
-use futures::lock::Mutex;
+use lib::{
+    analysis::AnalyzerConfig, metadata::MetadataValue, query::QueryParseError, sample,
+    scoring::ScoringModel, Calibration, CalibrationOptions, DryRun, Explanation, IndexData,
+    PersistentQuery, QueryPage, QueryUpdate, ResultLabel, ResultRange, TextSource, TrialQuery,
+    Webhook,
+};
 use serde::{Deserialize, Serialize};
 use tower_http::trace::TraceLayer;
 use tracing::{event, Level};
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::BTreeMap, net::SocketAddr};
 
 use crate::{
     errors::ApiError,
-    queries::{IndexData, PersistentQuery},
-    search::TextSource,
+    state::NodeState,
+    streams::{stream_query_results, stream_results_socket},
+    webhooks::DeadLetter,
 };
 
 pub async fn http_server(
     addr: SocketAddr,
-    query_map: flashmap::WriteHandle<u64, PersistentQuery>,
-    doc_channel: tachyonix::Sender<TextSource>,
+    state: NodeState,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let state = Arc::new(Mutex::new(State {
-        query_map,
-        document_channel: doc_channel,
-    }));
     let app = Router::new()
         .route("/", get(healthcheck))
         .route("/healthcheck", get(healthcheck))
         .route("/query/get/:query_id", get(get_query))
         .route("/query/submit", post(submit_query))
+        .route("/query/update/:query_id", post(update_query))
+        .route("/query/delete/:query_id", delete(delete_query))
+        .route("/query/list", get(list_queries))
         .route("/document/submit", post(submit_document))
         .route("/query/get_results/:query_id", get(get_query_results))
+        .route("/query/feedback/:query_id", post(label_results))
+        .route("/query/calibrate/:query_id", post(calibrate_threshold))
+        .route("/query/explain", post(explain))
+        .route("/query/dry_run", post(dry_run))
+        .route("/query/:query_id/stream", get(stream_query_results))
+        .route("/query/stream", get(stream_results_socket))
+        .route("/webhooks/dead_letters", get(get_dead_letters))
+        .route(
+            "/webhooks/dead_letters/:dead_letter_id",
+            delete(discard_dead_letter),
+        )
+        .route(
+            "/webhooks/dead_letters/:dead_letter_id/replay",
+            post(replay_dead_letter),
+        )
         .layer(TraceLayer::new_for_http())
         .layer(Extension(state));
     event!(Level::INFO, message = "Starting to listen", ?addr);
     axum::Server::bind(&addr)
+        // HTTP/2 with prior knowledge is still detected, but WebSocket upgrades need HTTP/1.1
         .http1_only(false)
-        .http2_only(true)
+        .http2_only(false)
         .tcp_nodelay(true)
         .serve(app.into_make_service())
         .await?;
     Ok(())
 }
 
+/// Takes an optional `Idempotency-Key` header - resubmitting with the same key returns the id
+/// allocated the first time rather than storing the query again. Setting `backfill` runs the query
+/// over the documents the node has retained too.
 async fn submit_query(
     Json(payload): Json<SubmitQueryRequest>,
-    Extension(state): Extension<Arc<Mutex<State>>>,
+    headers: HeaderMap,
+    Extension(state): Extension<NodeState>,
 ) -> Result<Json<QuerySubmitResponse>, ApiError> {
     // Todo: separate out validation logic from actual path handler
-    if payload.query_string.is_empty() || payload.threshold <= 0 {
-        dbg!(payload);
+    if payload.query_string.is_empty() || payload.threshold <= 0.0 {
+        event!(
+            Level::DEBUG,
+            message = "Rejected query submission",
+            ?payload
+        );
+        return Err(ApiError::QuerySubmission);
+    }
+    let idempotency_key = match headers.get("idempotency-key") {
+        Some(key) => Some(
+            key.to_str()
+                .map_err(|_e| ApiError::QuerySubmission)?
+                .to_string(),
+        ),
+        None => None,
+    };
+    let backfill = payload.backfill;
+    let query_id = state
+        .submit_query(payload.try_into()?, idempotency_key, backfill)
+        .await?;
+    Ok(Json(QuerySubmitResponse::created(query_id)))
+}
+
+async fn update_query(
+    Path(query_id): Path<u64>,
+    Json(update): Json<QueryUpdate>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<PersistentQuery>, ApiError> {
+    if update.query.as_ref().is_some_and(String::is_empty)
+        || update
+            .score_threshold
+            .is_some_and(|threshold| threshold <= 0.0)
+    {
+        event!(Level::DEBUG, message = "Rejected query update", ?update);
         return Err(ApiError::QuerySubmission);
     }
-    // Additionally, the lock ownership section should be segmented into an outer and an inner function
-    // in which the inner does not care about Mutex/Lock semantics
-    let query: PersistentQuery = payload.into();
-    let mut state_lock = state.lock().await;
-    // We could make this lock-free if we implemented the poll-loop directly
-    state_lock.query_map.guard().insert(query.id, query);
+    Ok(Json(state.update_query(query_id, update).await?))
+}
+
+async fn delete_query(
+    Path(query_id): Path<u64>,
+    Query(options): Query<DeleteQueryOptions>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<QuerySubmitResponse>, ApiError> {
+    state.delete_query(query_id, options.purge_results).await?;
     Ok(Json(QuerySubmitResponse::succeeded()))
 }
 
+async fn list_queries(
+    Query(page): Query<QueryPage>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<Vec<PersistentQuery>>, ApiError> {
+    Ok(Json(state.list_queries(page)?))
+}
+
+/// Documents submitted without an id get one derived from their name and text, so resubmitting
+/// one within the dedup window is dropped rather than matched again
 async fn submit_document(
-    Json(text_payload): Json<TextSource>,
-    Extension(state): Extension<Arc<Mutex<State>>>,
+    Json(text_payload): Json<SubmitDocumentRequest>,
+    Extension(state): Extension<NodeState>,
 ) -> Result<Json<DocumentSubmissionResult>, ApiError> {
     if text_payload.data.is_empty() {
         return Err(ApiError::DocSubmission);
     }
-    let state_lock = state.lock().await;
-    state_lock.document_channel.send(text_payload).await?;
-    Ok(Json(DocumentSubmissionResult { successful: true }))
+    let document = TextSource::from(text_payload);
+    let id = document.id;
+    let sent = state.submit_document(document).await?;
+    Ok(Json(DocumentSubmissionResult {
+        successful: true,
+        id,
+        duplicate: !sent,
+    }))
 }
 
 async fn get_query(
     Path(query_id): Path<u64>,
-    Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(state): Extension<NodeState>,
 ) -> Result<Json<PersistentQuery>, ApiError> {
-    let mut state_guard = state.lock().await;
-    // A bit less than ideal, we own the write-half of the map, we can't get a non-mutable, non-blocking
-    // view into it. It'll do for now.
-    let query_guard = state_guard.query_map.guard();
-    query_guard
-        .get(&query_id)
-        .map(|x| Json(x.to_owned()))
-        .ok_or(ApiError::NonExistentId)
+    Ok(Json(state.get_query(query_id)?))
 }
 
-async fn get_query_results(Path(query_id): Path<u64>) -> Result<Json<Vec<IndexData>>, ApiError> {
-    Ok(Json(Vec::new()))
+async fn get_query_results(
+    Path(query_id): Path<u64>,
+    Query(range): Query<ResultRange>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<Vec<IndexData>>, ApiError> {
+    Ok(Json(state.get_results(query_id, range).await?))
 }
 
-async fn healthcheck() -> &'static str {
-    "Healthy!"
+async fn label_results(
+    Path(query_id): Path<u64>,
+    Json(feedback): Json<FeedbackRequest>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<Calibration>, ApiError> {
+    let calibration = state
+        .label_results(query_id, feedback.labels, feedback.options)
+        .await?;
+    Ok(Json(calibration))
 }
 
-struct State {
-    query_map: flashmap::WriteHandle<u64, PersistentQuery>,
-    document_channel: tachyonix::Sender<TextSource>,
+/// Takes no body to just see the recommendation, or `{"apply": true}` to apply it too
+async fn calibrate_threshold(
+    Path(query_id): Path<u64>,
+    options: Option<Json<CalibrationOptions>>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<Calibration>, ApiError> {
+    let Json(options) = options.unwrap_or_default();
+    Ok(Json(state.calibrate_threshold(query_id, options).await?))
 }
 
-pub fn server_runtime(
-    addr: SocketAddr,
-    query_map: flashmap::WriteHandle<u64, PersistentQuery>,
-    doc_channel: tachyonix::Sender<TextSource>,
-) {
+/// Takes either the id of a stored query or a query in the shape `/query/submit` takes, along
+/// with a document in the shape `/document/submit` takes
+async fn explain(
+    Json(request): Json<ExplainRequest>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<Explanation>, ApiError> {
+    if request.document.data.is_empty() {
+        return Err(ApiError::DocSubmission);
+    }
+    let query = trial_query(request.query_id, request.query)?;
+    Ok(Json(state.explain(query, request.document.into()).await?))
+}
+
+/// Takes a query like `/query/explain` does, along with a list of documents, each either in the
+/// shape `/document/submit` takes or any JSON object, like a line of a JSONL export
+async fn dry_run(
+    Json(request): Json<DryRunRequest>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<DryRun>, ApiError> {
+    let query = trial_query(request.query_id, request.query)?;
+    let documents = request
+        .documents
+        .into_iter()
+        .enumerate()
+        .map(|(idx, document)| sample::from_json(document, &format!("document {idx}")))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| {
+            event!(Level::DEBUG, message = "Rejected dry run", %err);
+            ApiError::DocSubmission
+        })?;
+    Ok(Json(state.dry_run(query, documents, request.top).await?))
+}
+
+/// The stored query with the given id, or the ad-hoc one - there has to be exactly one
+fn trial_query(
+    query_id: Option<u64>,
+    query: Option<SubmitQueryRequest>,
+) -> Result<TrialQuery, ApiError> {
+    match (query_id, query) {
+        (Some(query_id), None) => Ok(TrialQuery::Stored(query_id)),
+        (None, Some(query)) if !query.query_string.is_empty() => {
+            Ok(TrialQuery::AdHoc(Box::new(query.try_into()?)))
+        }
+        _ => {
+            event!(Level::DEBUG, message = "Rejected trial query");
+            Err(ApiError::QuerySubmission)
+        }
+    }
+}
+
+async fn get_dead_letters(
+    Query(page): Query<DeadLetterPage>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<Vec<DeadLetter>>, ApiError> {
+    Ok(Json(state.dead_letters(page.limit)?))
+}
+
+async fn replay_dead_letter(
+    Path(dead_letter_id): Path<u64>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<QuerySubmitResponse>, ApiError> {
+    state.replay_dead_letter(dead_letter_id).await?;
+    Ok(Json(QuerySubmitResponse::succeeded()))
+}
+
+async fn discard_dead_letter(
+    Path(dead_letter_id): Path<u64>,
+    Extension(state): Extension<NodeState>,
+) -> Result<Json<QuerySubmitResponse>, ApiError> {
+    state.discard_dead_letter(dead_letter_id)?;
+    Ok(Json(QuerySubmitResponse::succeeded()))
+}
+
+async fn healthcheck() -> &'static str {
+    "Healthy!"
+}
+
+pub fn server_runtime(addr: SocketAddr, state: NodeState) {
     let runtime = tokio::runtime::Builder::new_current_thread()
         .worker_threads(1)
         .enable_io()
+        // Stream keep-alives and result watches run on timers
+        .enable_time()
         .thread_name("http server")
         .build()
         .expect("Couldn't build server");
     runtime
-        .block_on(http_server(addr, query_map, doc_channel))
+        .block_on(http_server(addr, state))
         .expect("Server failed");
 }
 
 #[derive(Debug, Deserialize)]
 struct SubmitQueryRequest {
-    id: u64,
     name: String,
     query_string: String,
-    threshold: i64,
+    /// On the scale of `scoring`
+    threshold: f64,
+    #[serde(default)]
+    scoring: ScoringModel,
+    #[serde(default)]
+    analyzer: AnalyzerConfig,
+    #[serde(default)]
+    webhook: Option<Webhook>,
+    /// Also run the query over retained documents. Ignored by explanations and dry runs.
+    #[serde(default)]
+    backfill: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeedbackRequest {
+    labels: Vec<ResultLabel>,
+    #[serde(flatten)]
+    options: CalibrationOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExplainRequest {
+    #[serde(default)]
+    query_id: Option<u64>,
+    #[serde(default)]
+    query: Option<SubmitQueryRequest>,
+    document: SubmitDocumentRequest,
+}
+
+#[derive(Debug, Deserialize)]
+struct DryRunRequest {
+    #[serde(default)]
+    query_id: Option<u64>,
+    #[serde(default)]
+    query: Option<SubmitQueryRequest>,
+    documents: Vec<serde_json::Value>,
+    /// How many of the best matches to return
+    #[serde(default = "DryRunRequest::default_top")]
+    top: u32,
+}
+
+impl DryRunRequest {
+    fn default_top() -> u32 {
+        10
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteQueryOptions {
+    #[serde(default)]
+    purge_results: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeadLetterPage {
+    #[serde(default = "DeadLetterPage::default_limit")]
+    limit: usize,
 }
 
-impl From<SubmitQueryRequest> for crate::PersistentQuery {
-    fn from(src: SubmitQueryRequest) -> Self {
-        PersistentQuery::new(src.id, src.name, src.query_string, src.threshold)
+impl DeadLetterPage {
+    fn default_limit() -> usize {
+        100
+    }
+}
+
+impl TryFrom<SubmitQueryRequest> for PersistentQuery {
+    type Error = QueryParseError;
+
+    fn try_from(src: SubmitQueryRequest) -> Result<Self, Self::Error> {
+        // The id is allocated when the query is stored
+        let query = PersistentQuery::new(0, src.name, src.query_string, src.threshold)?
+            .with_analyzer(src.analyzer)
+            .with_scoring(src.scoring);
+        Ok(match src.webhook {
+            Some(webhook) => query.with_webhook(webhook),
+            None => query,
+        })
     }
 }
 
 #[derive(Debug, Serialize)]
 struct QuerySubmitResponse {
     successful: bool,
+    /// The id allocated to a newly submitted query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
 }
 
 impl QuerySubmitResponse {
     fn succeeded() -> Self {
-        Self { successful: true }
+        Self {
+            successful: true,
+            id: None,
+        }
+    }
+
+    fn created(id: u64) -> Self {
+        Self {
+            successful: true,
+            id: Some(id),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SubmitDocumentRequest {
+    #[serde(default)]
+    id: Option<u64>,
+    name: String,
+    data: String,
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, MetadataValue>,
+}
+
+impl From<SubmitDocumentRequest> for TextSource {
+    fn from(src: SubmitDocumentRequest) -> Self {
+        let document = TextSource {
+            fields: src.fields,
+            metadata: src.metadata,
+            ..TextSource::new(src.data, src.name)
+        };
+        match src.id {
+            Some(id) => TextSource { id, ..document },
+            None => document.with_content_id(),
+        }
     }
 }
 
 #[derive(Debug, Serialize)]
 struct DocumentSubmissionResult {
     successful: bool,
+    id: u64,
+    /// Whether the document was dropped for repeating one seen within the dedup window
+    duplicate: bool,
 }
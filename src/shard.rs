@@ -0,0 +1,853 @@
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    StreamExt,
+};
+use itertools::Itertools;
+use lib::{analysis::AnalyzerConfig, IndexData, PersistentQuery, TextSource, Webhook};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{event, Level};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    results::{ResultStore, SegmentWriter},
+    retention::DocumentRetention,
+    search::{
+        corpus::{CorpusStats, SharedCorpusStats, PUBLISH_INTERVAL},
+        fuzzy_terms::{self, FuzzyTermIndex},
+        prefilter::{self, UNINDEXED},
+        CompiledQuery, Document, Searcher,
+    },
+    webhooks::Webhooks,
+};
+
+type PostingsWriter<'a> = flashmap::View<flashmap::WriteGuard<'a, u64, Vec<u64>>>;
+type AnalyzersWriter<'a> = flashmap::View<flashmap::WriteGuard<'a, u64, (AnalyzerConfig, usize)>>;
+
+/// The subset of standing queries owned by a single search thread
+pub(crate) struct QueryShard {
+    reader: ShardReader,
+    engine: Searcher,
+    // Rebuilt from the query map whenever the writer bumps the fuzzy term generation
+    fuzzy_terms: RefCell<(u64, Arc<FuzzyTermIndex>)>,
+    // Every document reaches every shard, so each one ends up with the same statistics. Each keeps
+    // its own rather than waiting on a shared copy.
+    corpus_stats: RefCell<CorpusStats>,
+    // Where the shard publishes snapshots of its statistics, if it does, and when it last did
+    published: Option<(SharedCorpusStats, Cell<Option<Instant>>)>,
+    // Queries held for backfilling that this shard has since backfilled
+    released: RefCell<HashSet<u64>>,
+}
+
+/// What the search shards are sent. Every shard gets them in the same order.
+pub(crate) enum ShardMessage {
+    Document(Arc<TextSource>),
+    /// A newly submitted query to run over the documents the shard has retained
+    Backfill(Arc<CompiledQuery>),
+}
+
+/// What one shard found backfilling a query over its share of the retained documents
+pub(crate) struct BackfillMatches {
+    pub(crate) query_id: u64,
+    pub(crate) results: Vec<IndexData>,
+    /// Just the documents that matched, for webhook snippets
+    pub(crate) documents: Vec<TextSource>,
+}
+
+/// Read halves of a single shard's query map and the inverted index over it
+pub(crate) struct ShardReader {
+    pub(crate) queries: flashmap::ReadHandle<u64, CompiledQuery>,
+    // Posting list key -> ids of the queries that need that term
+    postings: flashmap::ReadHandle<u64, Vec<u64>>,
+    // Every analyzer config in use by the shard's queries, keyed by analyzer id
+    analyzers: flashmap::ReadHandle<u64, (AnalyzerConfig, usize)>,
+    // Bumped every time a query with a `term~n` clause is added or replaced
+    fuzzy_generation: Arc<AtomicU64>,
+}
+
+impl QueryShard {
+    pub(crate) fn new(reader: ShardReader) -> Self {
+        Self {
+            reader,
+            engine: Searcher::new(),
+            fuzzy_terms: RefCell::new((0, Arc::default())),
+            corpus_stats: RefCell::default(),
+            published: None,
+            released: RefCell::default(),
+        }
+    }
+
+    /// Picks up counting from `corpus_stats`, as persisted before a restart
+    pub(crate) fn with_starting_stats(self, corpus_stats: &CorpusStats) -> Self {
+        *self.corpus_stats.borrow_mut() = corpus_stats.clone();
+        self
+    }
+
+    /// Publishes snapshots of the shard's corpus statistics to `corpus_stats`, so they can be read
+    /// from outside it
+    pub(crate) fn with_corpus_stats(mut self, corpus_stats: SharedCorpusStats) -> Self {
+        self.published = Some((corpus_stats, Cell::new(None)));
+        self
+    }
+
+    pub(crate) async fn search(&self, text: &TextSource) -> Vec<IndexData> {
+        // Later, a stream of results?
+        let fuzzy_terms = self.fuzzy_terms();
+        let document = Document::new(text).with_fuzzy_terms(&fuzzy_terms);
+        // Counted before searching, so a document is part of the corpus it's scored against
+        let mut corpus_stats = self.corpus_stats.borrow_mut();
+        corpus_stats.add(
+            &document,
+            self.reader
+                .analyzers
+                .guard()
+                .values()
+                .map(|(config, _)| config),
+        );
+        self.publish(&corpus_stats);
+        let document = document.with_corpus_stats(&corpus_stats);
+        let queries = self.reader.queries.guard();
+        let released = self.released.borrow();
+        self.candidates(&document)
+            .into_iter()
+            .filter_map(|id| queries.get(&id))
+            .filter(|q| !q.held_for_backfill || released.contains(&q.query.id))
+            .filter_map(|q| self.engine.search(q, &document))
+            .collect_vec()
+    }
+
+    /// Runs a single query over documents retained from before it was submitted, keeping the ones
+    /// it matches. They were counted towards the corpus statistics when they first arrived, so
+    /// aren't counted again.
+    pub(crate) fn backfill(
+        &self,
+        query: &CompiledQuery,
+        documents: Vec<TextSource>,
+    ) -> BackfillMatches {
+        let fuzzy_terms = FuzzyTermIndex::build([&query.query]);
+        let corpus_stats = self.corpus_stats.borrow();
+        let (results, documents) = documents
+            .into_iter()
+            .filter_map(|source| {
+                let document = Document::new(&source)
+                    .with_fuzzy_terms(&fuzzy_terms)
+                    .with_corpus_stats(&corpus_stats);
+                let mut result = self.engine.search(query, &document)?;
+                result.backfilled = true;
+                Some((result, source))
+            })
+            .unzip();
+        BackfillMatches {
+            query_id: query.query.id,
+            results,
+            documents,
+        }
+    }
+
+    /// Starts matching a query held for backfilling against live documents, once this shard has
+    /// backfilled it. Every document after the backfill message reaches the shard after this.
+    pub(crate) fn release(&self, query_id: u64) {
+        let queries = self.reader.queries.guard();
+        let mut released = self.released.borrow_mut();
+        released.insert(query_id);
+        // Forgets queries since deleted, or replaced by a version that isn't held
+        released.retain(|id| queries.get(id).is_some_and(|q| q.held_for_backfill));
+    }
+
+    /// Swaps in a fresh snapshot of the shard's statistics, if it shares them and the last one is
+    /// old enough
+    fn publish(&self, corpus_stats: &CorpusStats) {
+        let Some((shared, published_at)) = &self.published else {
+            return;
+        };
+        let now = Instant::now();
+        if published_at
+            .get()
+            .is_some_and(|at| now.duration_since(at) < PUBLISH_INTERVAL)
+        {
+            return;
+        }
+        shared.publish(corpus_stats.clone());
+        published_at.set(Some(now));
+    }
+
+    /// Where to deliver a query's matches, if anywhere
+    pub(crate) fn webhook(&self, query_id: u64) -> Option<Webhook> {
+        self.reader
+            .queries
+            .guard()
+            .get(&query_id)?
+            .query
+            .webhook
+            .clone()
+    }
+
+    /// Ids of the queries that could match the document: those with a required term present in
+    /// it, plus those with no required terms at all.
+    fn candidates(&self, document: &Document) -> Vec<u64> {
+        let postings = self.reader.postings.guard();
+        let mut candidates = postings.get(&UNINDEXED).cloned().unwrap_or_default();
+        for (&analyzer_id, (config, _)) in self.reader.analyzers.guard().iter() {
+            for key in document.term_keys(analyzer_id, config) {
+                if let Some(ids) = postings.get(&key) {
+                    candidates.extend(ids);
+                }
+            }
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    fn fuzzy_terms(&self) -> Arc<FuzzyTermIndex> {
+        // Loaded before reading the query map, so the rebuilt index is at least this new
+        let generation = self.reader.fuzzy_generation.load(Ordering::Acquire);
+        let mut cached = self.fuzzy_terms.borrow_mut();
+        if cached.0 != generation {
+            let queries = self.reader.queries.guard();
+            let index = FuzzyTermIndex::build(queries.values().map(|compiled| &compiled.query));
+            *cached = (generation, Arc::new(index));
+        }
+        cached.1.clone()
+    }
+}
+
+/// A search shard's thread: searches documents as they arrive, keeps them for backfilling if
+/// retention is on, and stores and delivers the results of the queries it owns
+pub(crate) struct ShardRuntime {
+    pub(crate) shard_id: usize,
+    pub(crate) shard: QueryShard,
+    pub(crate) results: ResultStore,
+    pub(crate) webhooks: Webhooks,
+    pub(crate) retention: Option<DocumentRetention>,
+    pub(crate) routes: BackfillRoutes,
+}
+
+impl ShardRuntime {
+    /// Runs until the document channel closes. Has to be run on the shard's glommio executor.
+    pub(crate) async fn run(
+        self,
+        mut messages: tachyonix::Receiver<ShardMessage>,
+        mut backfilled: mpsc::UnboundedReceiver<BackfillMatches>,
+    ) {
+        let shard_id = self.shard_id;
+        let mut segments = self
+            .results
+            .writer(shard_id)
+            .await
+            .expect("Couldn't open result segment");
+        let mut retained = match &self.retention {
+            Some(retention) => Some(
+                retention
+                    .buffer(shard_id)
+                    .await
+                    .expect("Couldn't open document retention"),
+            ),
+            None => None,
+        };
+        loop {
+            match future::select(pin!(messages.recv()), backfilled.next()).await {
+                Either::Left((Ok(ShardMessage::Document(doc)), _)) => {
+                    event!(Level::INFO, message="document received", thread_id=glommio::executor().id(), ?doc.id, ?doc.name);
+                    let search_results = self.shard.search(&doc).await;
+                    // Retained before the next message is taken, so any backfill sent after this
+                    // document covers it
+                    if let Some(retained) = retained.as_mut() {
+                        if let Err(err) = retained.retain(&doc).await {
+                            event!(Level::ERROR, message="Couldn't retain document", ?doc.id, ?err);
+                        }
+                    }
+                    self.store(&mut segments, search_results, |_| Some(doc.as_ref()))
+                        .await;
+                }
+                Either::Left((Ok(ShardMessage::Backfill(query)), _)) => {
+                    let documents = match retained.as_mut() {
+                        Some(retained) => retained.documents().await.unwrap_or_else(|err| {
+                            event!(
+                                Level::ERROR,
+                                message = "Couldn't read retained documents",
+                                query.id = query.query.id,
+                                ?err
+                            );
+                            Vec::new()
+                        }),
+                        None => Vec::new(),
+                    };
+                    let searched = documents.len();
+                    let matches = self.shard.backfill(&query, documents);
+                    event!(
+                        Level::INFO,
+                        message = "Backfilled query",
+                        query.id = query.query.id,
+                        searched,
+                        matched = matches.results.len()
+                    );
+                    self.shard.release(query.query.id);
+                    self.routes.send(matches);
+                }
+                Either::Right((Some(matches), _)) => {
+                    let documents = matches
+                        .documents
+                        .iter()
+                        .map(|doc| (doc.id, doc))
+                        .collect::<HashMap<_, _>>();
+                    self.store(&mut segments, matches.results, |result| {
+                        documents.get(&result.document_id).copied()
+                    })
+                    .await;
+                }
+                Either::Left((Err(_), _)) | Either::Right((None, _)) => {
+                    event!(
+                        Level::INFO,
+                        message = "Document channel closed, stopping shard",
+                        shard_id
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Stores a batch of results, then queues them for delivery to their queries' webhooks
+    async fn store<'a>(
+        &self,
+        segments: &mut SegmentWriter,
+        mut search_results: Vec<IndexData>,
+        document: impl Fn(&IndexData) -> Option<&'a TextSource>,
+    ) {
+        if search_results.is_empty() {
+            return;
+        }
+        match segments.append(&mut search_results).await {
+            Ok(()) => event!(
+                Level::INFO,
+                message = "Stored results",
+                count = search_results.len()
+            ),
+            Err(err) => {
+                event!(Level::ERROR, message = "Couldn't store results", ?err);
+                return;
+            }
+        }
+        // Only once they're stored, so every delivery has a sequence number to resume from
+        for result in search_results {
+            let webhook = self.shard.webhook(result.source_query);
+            if let (Some(webhook), Some(doc)) = (webhook, document(&result)) {
+                self.webhooks.notify(webhook, doc, result).await;
+            }
+        }
+    }
+}
+
+/// Hands each shard's backfilled matches to the shard that owns their query. Only the owner stores
+/// a query's results, so their sequence numbers follow the order they were stored in, and only the
+/// owner has the query's webhook.
+///
+/// The channels are unbounded because every shard sends to every other, and bounded ones could
+/// leave two shards each waiting on the other. They only hold matches for queries being backfilled.
+#[derive(Clone)]
+pub(crate) struct BackfillRoutes {
+    owners: Vec<mpsc::UnboundedSender<BackfillMatches>>,
+}
+
+impl BackfillRoutes {
+    /// Creates a channel per shard, returning the receiving ends in shard order
+    pub(crate) fn new(shard_count: usize) -> (Self, Vec<mpsc::UnboundedReceiver<BackfillMatches>>) {
+        let (owners, receivers) = (0..shard_count).map(|_| mpsc::unbounded()).unzip();
+        (Self { owners }, receivers)
+    }
+
+    fn send(&self, matches: BackfillMatches) {
+        if matches.results.is_empty() {
+            return;
+        }
+        let (query_id, count) = (matches.query_id, matches.results.len());
+        let owner = &self.owners[shard_for(query_id, self.owners.len())];
+        if owner.unbounded_send(matches).is_err() {
+            event!(
+                Level::ERROR,
+                message = "Owning shard has stopped, dropping backfilled matches",
+                query_id,
+                count
+            );
+        }
+    }
+}
+
+/// Picks the shard that owns `query_id` out of `shard_count` shards.
+pub(crate) fn shard_for(query_id: u64, shard_count: usize) -> usize {
+    (xxh3_64(&query_id.to_le_bytes()) % shard_count as u64) as usize
+}
+
+/// Write halves of a single shard's query map and index
+struct ShardWriter {
+    queries: flashmap::WriteHandle<u64, CompiledQuery>,
+    postings: flashmap::WriteHandle<u64, Vec<u64>>,
+    analyzers: flashmap::WriteHandle<u64, (AnalyzerConfig, usize)>,
+    fuzzy_generation: Arc<AtomicU64>,
+}
+
+impl ShardWriter {
+    fn new() -> (Self, ShardReader) {
+        let (queries, query_reader) = flashmap::with_capacity(1000);
+        let (postings, postings_reader) = flashmap::with_capacity(1000);
+        let (analyzers, analyzers_reader) = flashmap::new();
+        let fuzzy_generation = Arc::new(AtomicU64::new(0));
+        let writer = Self {
+            queries,
+            postings,
+            analyzers,
+            fuzzy_generation: fuzzy_generation.clone(),
+        };
+        let reader = ShardReader {
+            queries: query_reader,
+            postings: postings_reader,
+            analyzers: analyzers_reader,
+            fuzzy_generation,
+        };
+        (writer, reader)
+    }
+
+    /// Inserts or replaces a batch of queries, publishing each map once.
+    ///
+    /// The query map is published before the index, so a query is never a candidate before it
+    /// can be looked up.
+    fn insert(&mut self, batch: Vec<CompiledQuery>) {
+        let mut queries = self.queries.guard();
+        let mut postings = self.postings.guard();
+        let mut analyzers = self.analyzers.guard();
+        let mut fuzzy_terms_changed = false;
+        for compiled in batch {
+            let query = &compiled.query;
+            let (id, keys) = (query.id, prefilter::index_keys(query));
+            let analyzer = query.analyzer.clone();
+            fuzzy_terms_changed |= has_fuzzy_terms(query);
+            if let Some(replaced) = queries.insert(id, compiled) {
+                fuzzy_terms_changed |= has_fuzzy_terms(&replaced.query);
+                unindex(&mut postings, &mut analyzers, &replaced.query);
+            }
+            for key in keys {
+                add_posting(&mut postings, key, id);
+            }
+            let analyzer_id = prefilter::analyzer_id(&analyzer);
+            if analyzers
+                .replace(analyzer_id, |(config, count)| (config.clone(), count + 1))
+                .is_none()
+            {
+                analyzers.insert(analyzer_id, (analyzer, 1));
+            }
+        }
+        queries.publish();
+        if fuzzy_terms_changed {
+            self.fuzzy_generation.fetch_add(1, Ordering::Release);
+        }
+        analyzers.publish();
+        postings.publish();
+    }
+
+    /// Removes a batch of queries, publishing each map once
+    fn remove(&mut self, ids: Vec<u64>) {
+        let mut queries = self.queries.guard();
+        let mut postings = self.postings.guard();
+        let mut analyzers = self.analyzers.guard();
+        let mut fuzzy_terms_changed = false;
+        for id in ids {
+            if let Some(removed) = queries.remove(id) {
+                fuzzy_terms_changed |= has_fuzzy_terms(&removed.query);
+                unindex(&mut postings, &mut analyzers, &removed.query);
+            }
+        }
+        // Candidates missing from the query map are skipped, so the order doesn't matter here
+        queries.publish();
+        if fuzzy_terms_changed {
+            self.fuzzy_generation.fetch_add(1, Ordering::Release);
+        }
+        analyzers.publish();
+        postings.publish();
+    }
+}
+
+/// Drops a query's posting list entries and its hold on its analyzer
+fn unindex(
+    postings: &mut PostingsWriter,
+    analyzers: &mut AnalyzersWriter,
+    query: &PersistentQuery,
+) {
+    for key in prefilter::index_keys(query) {
+        remove_posting(postings, key, query.id);
+    }
+    let analyzer_id = prefilter::analyzer_id(&query.analyzer);
+    match analyzers.get(&analyzer_id) {
+        Some((_, 1)) => {
+            analyzers.remove(analyzer_id);
+        }
+        Some(_) => {
+            analyzers.replace(analyzer_id, |(config, count)| (config.clone(), count - 1));
+        }
+        None => {}
+    }
+}
+
+fn has_fuzzy_terms(query: &PersistentQuery) -> bool {
+    !fuzzy_terms::fuzzy_terms(&query.parsed).is_empty()
+}
+
+fn add_posting(postings: &mut PostingsWriter, key: u64, query_id: u64) {
+    match postings.get(&key) {
+        Some(ids) if ids.contains(&query_id) => {}
+        Some(_) => {
+            postings.replace(key, |ids| {
+                let mut ids = ids.clone();
+                ids.push(query_id);
+                ids
+            });
+        }
+        None => {
+            postings.insert(key, vec![query_id]);
+        }
+    }
+}
+
+fn remove_posting(postings: &mut PostingsWriter, key: u64, query_id: u64) {
+    match postings.get(&key) {
+        Some(ids) if ids == &[query_id] => {
+            postings.remove(key);
+        }
+        Some(_) => {
+            postings.replace(key, |ids| {
+                ids.iter().copied().filter(|&id| id != query_id).collect()
+            });
+        }
+        None => {}
+    }
+}
+
+/// Write halves of every shard's query map and index.
+///
+/// Each query lives in exactly one shard, picked by hashing its id.
+pub(crate) struct ShardedQueries {
+    shards: Vec<ShardWriter>,
+}
+
+impl ShardedQueries {
+    /// Creates `shard_count` empty shards, returning the read halves in shard order.
+    pub(crate) fn new(shard_count: usize) -> (Self, Vec<ShardReader>) {
+        let (shards, readers) = (0..shard_count).map(|_| ShardWriter::new()).unzip();
+        (Self { shards }, readers)
+    }
+
+    pub(crate) fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub(crate) fn insert(&mut self, query: CompiledQuery) {
+        self.extend([query]);
+    }
+
+    /// Removes queries from whichever shards they're in
+    pub(crate) fn remove(&mut self, ids: impl IntoIterator<Item = u64>) {
+        let shard_count = self.shards.len();
+        let mut batches = vec![Vec::new(); shard_count];
+        for id in ids {
+            batches[shard_for(id, shard_count)].push(id);
+        }
+        for (shard, batch) in self.shards.iter_mut().zip(batches) {
+            if !batch.is_empty() {
+                shard.remove(batch);
+            }
+        }
+    }
+
+    /// Inserts a batch of queries, publishing each shard's maps once rather than per query.
+    pub(crate) fn extend(&mut self, queries: impl IntoIterator<Item = CompiledQuery>) {
+        let shard_count = self.shards.len();
+        let mut batches = (0..shard_count).map(|_| Vec::new()).collect::<Vec<_>>();
+        for compiled in queries {
+            batches[shard_for(compiled.query.id, shard_count)].push(compiled);
+        }
+        for (shard, batch) in self.shards.iter_mut().zip(batches) {
+            if !batch.is_empty() {
+                shard.insert(batch);
+            }
+        }
+    }
+}
+
+/// Sends every incoming document to all of the search shards, dropping any already sent within
+/// the dedup window
+#[derive(Clone)]
+pub(crate) struct DocumentBroadcast {
+    senders: Vec<tachyonix::Sender<ShardMessage>>,
+    recent: Arc<Mutex<RecentDocuments>>,
+    // Held while sending to every shard, so they all see messages in the same order
+    order: Arc<futures::lock::Mutex<()>>,
+}
+
+impl DocumentBroadcast {
+    /// Creates a channel per shard, returning the receiving ends in shard order. Deduplication is
+    /// off until a window is set.
+    pub(crate) fn new(
+        shard_count: usize,
+        capacity: usize,
+    ) -> (Self, Vec<tachyonix::Receiver<ShardMessage>>) {
+        let (senders, receivers) = (0..shard_count)
+            .map(|_| tachyonix::channel(capacity))
+            .unzip();
+        let recent = Arc::new(Mutex::new(RecentDocuments::new(Duration::ZERO)));
+        let order = Arc::default();
+        let broadcast = Self {
+            senders,
+            recent,
+            order,
+        };
+        (broadcast, receivers)
+    }
+
+    /// Drops documents whose id has already been sent within `window`
+    pub(crate) fn with_dedup_window(self, window: Duration) -> Self {
+        *self.recent.lock().expect("Dedup window lock poisoned") = RecentDocuments::new(window);
+        self
+    }
+
+    /// Sends a document to every shard, returning `false` if it was dropped as a duplicate
+    pub(crate) async fn send(
+        &self,
+        document: TextSource,
+    ) -> Result<bool, tachyonix::SendError<ShardMessage>> {
+        let first_sighting = self
+            .recent
+            .lock()
+            .expect("Dedup window lock poisoned")
+            .insert(document.id, Instant::now());
+        if !first_sighting {
+            return Ok(false);
+        }
+        let document = Arc::new(document);
+        let _order = self.order.lock().await;
+        for sender in &self.senders {
+            sender
+                .send(ShardMessage::Document(document.clone()))
+                .await?;
+        }
+        Ok(true)
+    }
+
+    /// Asks every shard to run a query over the documents it has retained. The query has to be
+    /// live already, so every document not retained by the time a shard gets this is matched live.
+    pub(crate) async fn backfill(
+        &self,
+        query: CompiledQuery,
+    ) -> Result<(), tachyonix::SendError<ShardMessage>> {
+        let query = Arc::new(query);
+        let _order = self.order.lock().await;
+        for sender in &self.senders {
+            sender.send(ShardMessage::Backfill(query.clone())).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Ids of the documents sent within the dedup window
+struct RecentDocuments {
+    window: Duration,
+    seen: HashSet<u64>,
+    // Oldest first, for expiring them
+    order: VecDeque<(Instant, u64)>,
+}
+
+impl RecentDocuments {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records a document, returning `false` if it was already seen within the window. The window
+    /// runs from when a document was first seen, so resending it doesn't extend it.
+    fn insert(&mut self, id: u64, now: Instant) -> bool {
+        while let Some(&(seen_at, expired)) = self.order.front() {
+            if now.duration_since(seen_at) < self.window {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&expired);
+        }
+        if self.window.is_zero() {
+            return true;
+        }
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.order.push_back((now, id));
+        true
+    }
+}
+
+#[cfg(test)]
+mod shard_tests {
+    use super::*;
+
+    fn compiled(id: u64, query: &str) -> CompiledQuery {
+        CompiledQuery::new(PersistentQuery::new(id, "q", query, 1.0).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_queries_spread_across_shards() {
+        let (mut queries, readers) = ShardedQueries::new(4);
+        for id in 0..100 {
+            queries.insert(compiled(id, "darcy"));
+        }
+        let sizes = readers
+            .iter()
+            .map(|r| r.queries.guard().len())
+            .collect_vec();
+        assert_eq!(sizes.iter().sum::<usize>(), 100);
+        assert!(sizes.iter().all(|&size| size > 0), "{sizes:?}");
+        for (shard, reader) in readers.iter().enumerate() {
+            assert!(reader
+                .queries
+                .guard()
+                .keys()
+                .all(|&id| shard_for(id, 4) == shard));
+        }
+    }
+
+    #[test]
+    fn test_only_candidate_queries_are_searched() {
+        let (mut queries, mut readers) = ShardedQueries::new(1);
+        let shard = QueryShard::new(readers.remove(0));
+        queries.extend([
+            compiled(1, "outage AND NOT test"),
+            compiled(2, "mr darcy"),
+            compiled(3, "incident OR failure"),
+        ]);
+        let source = TextSource::new("a major outage was reported", "doc".to_string());
+        let document = Document::new(&source);
+        assert_eq!(shard.candidates(&document), [1, 2]);
+
+        // Replacing a query drops its old terms from the index
+        queries.insert(compiled(1, "failure AND disk"));
+        assert_eq!(shard.candidates(&document), [2]);
+        let source = TextSource::new("disk failure", "doc".to_string());
+        assert_eq!(shard.candidates(&Document::new(&source)), [1, 2, 3]);
+
+        // Fuzzy terms are candidates when the document has a word close enough to them
+        queries.insert(compiled(4, "failure~1 AND disk"));
+        let source = TextSource::new("disk failur", "doc".to_string());
+        let fuzzy_terms = shard.fuzzy_terms();
+        let document = Document::new(&source).with_fuzzy_terms(&fuzzy_terms);
+        // Queries 1 and 3 share the posting list for "failure", so they get checked too
+        assert_eq!(shard.candidates(&document), [1, 2, 3, 4]);
+
+        // Removed queries leave nothing behind in the index
+        let generation = shard.reader.fuzzy_generation.load(Ordering::Acquire);
+        queries.remove([1, 2, 3, 4]);
+        assert!(shard.reader.fuzzy_generation.load(Ordering::Acquire) > generation);
+        assert!(shard.candidates(&document).is_empty());
+        assert!(shard.reader.postings.guard().is_empty());
+        assert!(shard.reader.analyzers.guard().is_empty());
+    }
+
+    #[test]
+    fn test_held_queries_wait_for_their_backfill() {
+        let (mut queries, mut readers) = ShardedQueries::new(1);
+        let shard = QueryShard::new(readers.remove(0));
+        let held = compiled(1, "outage").hold_for_backfill();
+        queries.extend([held.clone(), compiled(2, "outage")]);
+        let earlier = TextSource::new("an outage yesterday", "earlier".to_string());
+        let source = TextSource::new("another outage", "doc".to_string());
+        let retained = vec![
+            earlier,
+            TextSource::new("another outage", "doc".to_string()),
+        ];
+        let live = futures::executor::block_on(shard.search(&source));
+        assert_eq!(live.iter().map(|r| r.source_query).collect_vec(), [2]);
+
+        let backfilled = shard.backfill(&held, retained);
+        assert_eq!(backfilled.results.len(), 2);
+        assert_eq!(backfilled.documents.len(), 2);
+        assert!(backfilled
+            .results
+            .iter()
+            .all(|r| r.source_query == 1 && r.backfilled));
+        shard.release(1);
+        let live = futures::executor::block_on(shard.search(&source));
+        assert_eq!(live.iter().map(|r| r.source_query).collect_vec(), [1, 2]);
+        assert!(live.iter().all(|r| !r.backfilled));
+        // Forgotten once the query's gone
+        queries.remove([1]);
+        shard.release(3);
+        assert!(shard.released.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_corpus_stats_are_published_at_intervals() {
+        let (mut queries, mut readers) = ShardedQueries::new(1);
+        let shared = SharedCorpusStats::default();
+        let shard = QueryShard::new(readers.remove(0)).with_corpus_stats(shared.clone());
+        queries.insert(compiled(1, "outage"));
+        let config = AnalyzerConfig::default();
+        for text in ["an outage", "another outage"] {
+            futures::executor::block_on(shard.search(&TextSource::new(text, "doc".to_string())));
+        }
+        // The first document is published straight away, the second waits for the interval
+        let published = shared.snapshot().unwrap();
+        let idf = |stats: &CorpusStats| stats.get(&config).unwrap().unseen_idf();
+        assert_eq!(idf(&published), crate::search::corpus::idf(1, 0));
+        assert_eq!(
+            idf(&shard.corpus_stats.borrow()),
+            crate::search::corpus::idf(2, 0)
+        );
+    }
+
+    #[test]
+    fn test_documents_differing_in_metadata_are_both_sent() {
+        let (broadcast, mut receivers) = DocumentBroadcast::new(1, 4);
+        let broadcast = broadcast.with_dedup_window(Duration::from_secs(60));
+        let document = |source: &str| {
+            TextSource::new("Rates held", "boe".to_string())
+                .with_metadata("source", lib::metadata::MetadataValue::Text(source.into()))
+                .with_content_id()
+        };
+        futures::executor::block_on(async {
+            assert!(broadcast.send(document("reuters")).await.unwrap());
+            assert!(broadcast.send(document("bloomberg")).await.unwrap());
+            assert!(!broadcast.send(document("reuters")).await.unwrap());
+            for source in ["reuters", "bloomberg"] {
+                let Ok(ShardMessage::Document(sent)) = receivers[0].recv().await else {
+                    panic!("Expected a document");
+                };
+                let expected = lib::metadata::MetadataValue::Text(source.into());
+                assert_eq!(sent.metadata["source"], expected);
+            }
+        });
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let start = Instant::now();
+        let mut recent = RecentDocuments::new(Duration::from_secs(10));
+        assert!(recent.insert(1, start));
+        assert!(recent.insert(2, start + Duration::from_secs(5)));
+        assert!(!recent.insert(1, start + Duration::from_secs(9)));
+        // Expires ten seconds after it was first seen, not after the duplicate
+        assert!(recent.insert(1, start + Duration::from_secs(10)));
+        assert!(!recent.insert(2, start + Duration::from_secs(14)));
+        assert!(recent.insert(2, start + Duration::from_secs(15)));
+
+        let mut disabled = RecentDocuments::new(Duration::ZERO);
+        assert!(disabled.insert(1, start));
+        assert!(disabled.insert(1, start));
+    }
+}
@@ -0,0 +1,1028 @@
+use futures::lock::Mutex;
+use lib::{
+    Calibration, CalibrationOptions, DryRun, Explanation, IndexData, PersistentQuery, QueryPage,
+    QueryUpdate, ResultCursor, ResultLabel, ResultRange, TarkineError, TextSource, TrialQuery,
+};
+use sled::transaction::{TransactionError, Transactional};
+use std::{collections::HashMap, fmt, ops::Bound, path::PathBuf, sync::Arc};
+use tokio::time::Instant;
+use tracing::instrument;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    dry_run,
+    feedback::{self, Feedback, Label},
+    results::ResultStore,
+    retention::RetentionLimits,
+    search::{
+        corpus::SharedCorpusStats, fuzzy_terms::FuzzyTermIndex, CompiledQuery, Document, Searcher,
+    },
+    shard::{DocumentBroadcast, ShardedQueries},
+    webhooks::{DeadLetter, Delivery, Webhooks},
+};
+
+/// Everything the RPC and HTTP front ends need to service a request.
+///
+/// Cheap to clone - each front end hands a copy to every connection/handler.
+#[derive(Clone)]
+pub(crate) struct NodeState {
+    doc_channel: DocumentBroadcast,
+    db: sled::Db,
+    query_map: sled::Tree,
+    // Idempotency key -> id and fingerprint of the query first submitted with it
+    idempotency_keys: sled::Tree,
+    // Query id -> the idempotency key it was submitted with, so deleting it frees the key
+    query_keys: sled::Tree,
+    // Write halves of the maps the search shards read from, so submitted queries go live immediately
+    live_queries: Arc<Mutex<ShardedQueries>>,
+    results: ResultStore,
+    webhooks: Webhooks,
+    feedback: Feedback,
+    // One of the shards' corpus statistics, which explanations are scored against
+    corpus_stats: SharedCorpusStats,
+    // How much of the document stream the shards keep for backfilling, if they keep any at all
+    retention: Option<RetentionLimits>,
+}
+
+impl NodeState {
+    /// Opens the database and reloads every persisted query into the search shards.
+    ///
+    /// Neither front end exists until this returns, so no document is accepted while the shards are
+    /// only partially populated.
+    pub(crate) fn open(
+        db_path: PathBuf,
+        results_path: PathBuf,
+        doc_channel: DocumentBroadcast,
+        deliveries: tachyonix::Sender<Delivery>,
+        mut live_queries: ShardedQueries,
+    ) -> Result<Self, TarkineError> {
+        tracing::info!(message="Opening node state", database_path=?db_path);
+        let db = sled::Config::default()
+            .use_compression(true)
+            .path(db_path)
+            .open()?;
+        let query_map = db.open_tree("queries")?;
+        let idempotency_keys = db.open_tree("idempotency_keys")?;
+        let query_keys = db.open_tree("query_idempotency_keys")?;
+        let results = ResultStore::open(results_path, &db, live_queries.shard_count())?;
+        let webhooks = Webhooks::open(&db, deliveries)?;
+        let feedback = Feedback::open(&db)?;
+        let corpus_stats = SharedCorpusStats::open(&db)?;
+        let recovered = recover_queries(&query_map, &mut live_queries)?;
+        tracing::info!(message = "Recovered persisted queries", recovered);
+        Ok(Self {
+            doc_channel,
+            db,
+            query_map,
+            idempotency_keys,
+            query_keys,
+            live_queries: Arc::new(Mutex::new(live_queries)),
+            results,
+            webhooks,
+            feedback,
+            corpus_stats,
+            retention: None,
+        })
+    }
+
+    /// Lets queries be backfilled, as the search shards are retaining documents within `limits`
+    pub(crate) fn with_retention(mut self, limits: RetentionLimits) -> Self {
+        self.retention = Some(limits);
+        self
+    }
+
+    /// The store the search shards append their results to
+    pub(crate) fn results(&self) -> ResultStore {
+        self.results.clone()
+    }
+
+    /// Where the search shards queue matches for delivery to webhooks
+    pub(crate) fn webhooks(&self) -> Webhooks {
+        self.webhooks.clone()
+    }
+
+    /// Where one of the search shards keeps its corpus statistics, for explanations to be scored
+    /// against
+    pub(crate) fn corpus_stats(&self) -> SharedCorpusStats {
+        self.corpus_stats.clone()
+    }
+
+    #[instrument]
+    pub(crate) fn get_query(&self, query_id: u64) -> Result<PersistentQuery, TarkineError> {
+        let Some(raw_query) = self.query_map.get(query_id.to_ne_bytes())? else {
+            return Err(TarkineError::Id);
+        };
+        lib::from_archive::<PersistentQuery>(&raw_query)
+    }
+
+    /// Stores a new query under a freshly allocated id, returning the id.
+    ///
+    /// An idempotency key seen before returns the id it was first used for, as long as it's for
+    /// the same query - reusing a key for a different one is a conflict.
+    ///
+    /// Backfilling runs the query over the documents the shards have retained as well, marking
+    /// what it finds as backfilled. The query isn't matched live in its shard until that's done,
+    /// so no document matches twice - unless the node restarts first, in which case it goes live
+    /// without being backfilled.
+    #[instrument(skip(self))]
+    pub(crate) async fn submit_query(
+        &self,
+        mut query: PersistentQuery,
+        idempotency_key: Option<String>,
+        backfill: bool,
+    ) -> Result<u64, TarkineError> {
+        if backfill && self.retention.is_none() {
+            return Err(TarkineError::InvalidQuery(
+                "backfilling needs document retention, which is turned off".to_string(),
+            ));
+        }
+        query.reparse()?;
+        let fingerprint = fingerprint(&query)?;
+        // Held from checking the key to storing the query, so concurrent retries can't both store
+        let mut live_queries = self.live_queries.lock().await;
+        if let Some(key) = &idempotency_key {
+            if let Some(record) = self.idempotency_keys.get(key)? {
+                let (query_id, first_fingerprint) = decode_idempotency_record(&record)?;
+                if first_fingerprint != fingerprint {
+                    return Err(TarkineError::Conflict(format!(
+                        "idempotency key {key:?} was used for a different query"
+                    )));
+                }
+                tracing::info!(message = "Repeated query submission", query_id);
+                return Ok(query_id);
+            }
+        }
+        // Checked before allocating an id, so rejected queries don't use one up
+        let mut compiled = check_query(&query)?;
+        query.id = self.allocate_id()?;
+        compiled.query.id = query.id;
+        let query_bytes = rkyv::to_bytes::<_, 1024>(&query).map_err(|_e| TarkineError::Parsing)?;
+        let record = [query.id.to_le_bytes(), fingerprint.to_le_bytes()].concat();
+        (&self.query_map, &self.idempotency_keys, &self.query_keys)
+            .transaction(|(queries, keys, query_keys)| {
+                queries.insert(&query.id.to_ne_bytes(), query_bytes.as_slice())?;
+                if let Some(key) = &idempotency_key {
+                    keys.insert(key.as_bytes(), record.as_slice())?;
+                    query_keys.insert(&query.id.to_ne_bytes(), key.as_bytes())?;
+                }
+                Ok(())
+            })
+            .map_err(|_: TransactionError| TarkineError::Storage)?;
+        self.db.flush_async().await?;
+        tracing::info!(message = "Stored query", query.id, query.query, backfill);
+        if backfill {
+            let compiled = compiled.hold_for_backfill();
+            live_queries.insert(compiled.clone());
+            // Still holding the lock, so the query can't be replaced before its shard has it
+            self.doc_channel.backfill(compiled).await?;
+        } else {
+            live_queries.insert(compiled);
+        }
+        Ok(query.id)
+    }
+
+    /// The next id from sled's generator that isn't taken. Queries stored before ids were
+    /// allocated by the server chose their own, which the generator knows nothing about.
+    fn allocate_id(&self) -> Result<u64, TarkineError> {
+        loop {
+            let id = self.db.generate_id()?;
+            if !self.query_map.contains_key(id.to_ne_bytes())? {
+                return Ok(id);
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn update_query(
+        &self,
+        query_id: u64,
+        update: QueryUpdate,
+    ) -> Result<PersistentQuery, TarkineError> {
+        // Held from the read to the write, so concurrent changes to the query can't be lost
+        let mut live_queries = self.live_queries.lock().await;
+        let mut query = self.get_query(query_id)?;
+        query.apply(update)?;
+        let compiled = self.store_query(&query).await?;
+        tracing::info!(message = "Updated query", query.id, query.query);
+        live_queries.insert(compiled);
+        Ok(query)
+    }
+
+    #[instrument(skip(self))]
+    pub(crate) async fn delete_query(
+        &self,
+        query_id: u64,
+        purge_results: bool,
+    ) -> Result<(), TarkineError> {
+        let mut live_queries = self.live_queries.lock().await;
+        // Its idempotency key goes with it, so a retry with the key submits the query afresh
+        let removed = (&self.query_map, &self.idempotency_keys, &self.query_keys)
+            .transaction(|(queries, keys, query_keys)| {
+                let id = query_id.to_ne_bytes();
+                if queries.remove(&id)?.is_none() {
+                    return Ok(false);
+                }
+                if let Some(key) = query_keys.remove(&id)? {
+                    keys.remove(key)?;
+                }
+                Ok(true)
+            })
+            .map_err(|_: TransactionError| TarkineError::Storage)?;
+        if !removed {
+            return Err(TarkineError::Id);
+        }
+        self.db.flush_async().await?;
+        live_queries.remove([query_id]);
+        // After the shards have dropped it, so it can't produce any more results to purge
+        if purge_results {
+            self.results.purge(query_id)?;
+        }
+        self.feedback.clear(query_id)?;
+        tracing::info!(message = "Deleted query", query_id, purge_results);
+        Ok(())
+    }
+
+    /// Lists a page of the stored queries in storage order, which follows the bytes of their
+    /// native endian ids rather than the ids themselves. Unreadable queries are skipped, as they
+    /// are on startup.
+    #[instrument]
+    pub(crate) fn list_queries(
+        &self,
+        page: QueryPage,
+    ) -> Result<Vec<PersistentQuery>, TarkineError> {
+        let start = match page.after {
+            Some(after) => Bound::Excluded(after.to_ne_bytes()),
+            None => Bound::Unbounded,
+        };
+        let limit = page.limit.map_or(usize::MAX, |limit| limit as usize);
+        let mut queries = Vec::new();
+        for entry in self.query_map.range((start, Bound::Unbounded)) {
+            if queries.len() >= limit {
+                break;
+            }
+            let (key, raw_query) = entry?;
+            match lib::from_archive::<PersistentQuery>(&raw_query) {
+                Ok(query) => queries.push(query),
+                Err(_) => tracing::error!(message = "Skipping unreadable persisted query", ?key),
+            }
+        }
+        Ok(queries)
+    }
+
+    /// Validates and persists a query, without touching the shards, returning it compiled for them
+    async fn store_query(&self, query: &PersistentQuery) -> Result<CompiledQuery, TarkineError> {
+        let compiled = check_query(query)?;
+        let query_bytes = rkyv::to_bytes::<_, 1024>(query).map_err(|_e| TarkineError::Parsing)?;
+        self.query_map
+            .insert(query.id.to_ne_bytes(), query_bytes.as_slice())?;
+        self.query_map.flush_async().await?;
+        Ok(compiled)
+    }
+
+    /// Sends a document to the search shards, returning `false` if it was dropped for having the
+    /// same id as one sent within the dedup window
+    #[instrument(skip(self, document), fields(document.id, document.name))]
+    pub(crate) async fn submit_document(&self, document: TextSource) -> Result<bool, TarkineError> {
+        let document_id = document.id;
+        let sent = self.doc_channel.send(document).await?;
+        if !sent {
+            tracing::info!(message = "Dropped duplicate document", document_id);
+        }
+        Ok(sent)
+    }
+
+    #[instrument]
+    pub(crate) async fn get_results(
+        &self,
+        query_id: u64,
+        range: ResultRange,
+    ) -> Result<Vec<IndexData>, TarkineError> {
+        let results = self.results.clone();
+        let results = tokio::task::spawn_blocking(move || results.range(query_id, &range))
+            .await
+            .map_err(|_e| TarkineError::Storage)??;
+        // A deleted query's results stay readable unless they were purged
+        if results.is_empty() && !self.query_map.contains_key(query_id.to_ne_bytes())? {
+            return Err(TarkineError::Id);
+        }
+        Ok(results)
+    }
+
+    /// Labels some of a query's results as relevant or not, then recalibrates its threshold
+    #[instrument(skip(self, labels))]
+    pub(crate) async fn label_results(
+        &self,
+        query_id: u64,
+        labels: Vec<ResultLabel>,
+        options: CalibrationOptions,
+    ) -> Result<Calibration, TarkineError> {
+        if !self.query_map.contains_key(query_id.to_ne_bytes())? {
+            return Err(TarkineError::Id);
+        }
+        if !labels.is_empty() {
+            let sequences = labels.iter().map(|label| label.sequence).collect();
+            let results = self.results.clone();
+            let results = tokio::task::spawn_blocking(move || results.lookup(query_id, &sequences))
+                .await
+                .map_err(|_e| TarkineError::Storage)??;
+            let scores = results
+                .iter()
+                .map(|result| (result.sequence, (result.score, result.scoring)))
+                .collect::<HashMap<_, _>>();
+            let labels = labels
+                .iter()
+                .map(|label| {
+                    let &(score, scoring) = scores.get(&label.sequence).ok_or(TarkineError::Id)?;
+                    Ok(Label {
+                        sequence: label.sequence,
+                        score,
+                        scoring,
+                        relevant: label.relevant,
+                    })
+                })
+                .collect::<Result<Vec<_>, TarkineError>>()?;
+            self.feedback.record(query_id, &labels).await?;
+            tracing::info!(message = "Labeled results", query_id, labels = labels.len());
+        }
+        self.calibrate_threshold(query_id, options).await
+    }
+
+    /// Recommends a threshold for a query from its labeled results, and applies it if asked to
+    #[instrument(skip(self))]
+    pub(crate) async fn calibrate_threshold(
+        &self,
+        query_id: u64,
+        options: CalibrationOptions,
+    ) -> Result<Calibration, TarkineError> {
+        let query = self.get_query(query_id)?;
+        let labels = self.feedback.labels(query_id)?;
+        let mut calibration = feedback::calibrate(&query, &labels, options.min_precision);
+        let Some(recommended) = calibration.recommended_threshold else {
+            return Ok(calibration);
+        };
+        if options.apply && recommended != query.score_threshold {
+            let update = QueryUpdate {
+                score_threshold: Some(recommended),
+                ..QueryUpdate::default()
+            };
+            self.update_query(query_id, update).await?;
+            calibration.score_threshold = recommended;
+            calibration.applied = true;
+            tracing::info!(
+                message = "Applied calibrated threshold",
+                query_id,
+                recommended
+            );
+        }
+        Ok(calibration)
+    }
+
+    /// Runs a query against a document and reports how each of its clauses fared, without storing
+    /// or delivering anything.
+    ///
+    /// Scored against the statistics the shards last published, which lag the documents they've
+    /// seen by a few seconds and don't include this one. Ad-hoc queries using an analyzer no live
+    /// query does have no statistics, so score every term as equally rare.
+    #[instrument(skip(self, document))]
+    pub(crate) async fn explain(
+        &self,
+        query: TrialQuery,
+        document: TextSource,
+    ) -> Result<Explanation, TarkineError> {
+        let compiled = self.trial_query(query)?;
+        let corpus_stats = self.corpus_stats.snapshot()?;
+        // Kept off the front end's runtime, like dry runs
+        tokio::task::spawn_blocking(move || {
+            // The shards' index covers every live query, but only this one's terms can matter here
+            let fuzzy_terms = FuzzyTermIndex::build([&compiled.query]);
+            let document = Document::new(&document)
+                .with_fuzzy_terms(&fuzzy_terms)
+                .with_corpus_stats(&corpus_stats);
+            Searcher::new().explain(&compiled, &document)
+        })
+        .await
+        .map_err(|_e| TarkineError::InternalChannel)
+    }
+
+    /// Runs a query against a sample of documents and sums up what it would have matched, keeping
+    /// the `top` best matches. Nothing is stored or delivered.
+    #[instrument(skip(self, documents), fields(documents = documents.len()))]
+    pub(crate) async fn dry_run(
+        &self,
+        query: TrialQuery,
+        documents: Vec<TextSource>,
+        top: u32,
+    ) -> Result<DryRun, TarkineError> {
+        let compiled = self.trial_query(query)?;
+        // Searching a large sample takes a while, so it's kept off the front end's runtime
+        tokio::task::spawn_blocking(move || dry_run::dry_run(&compiled, &documents, top as usize))
+            .await
+            .map_err(|_e| TarkineError::InternalChannel)
+    }
+
+    /// Loads a stored query, or checks an ad-hoc one as if it were being submitted
+    fn trial_query(&self, query: TrialQuery) -> Result<CompiledQuery, TarkineError> {
+        let query = match query {
+            TrialQuery::Stored(query_id) => self.get_query(query_id)?,
+            TrialQuery::AdHoc(mut query) => {
+                query.reparse()?;
+                *query
+            }
+        };
+        check_query(&query)
+    }
+
+    /// Webhook deliveries that failed for good, oldest first
+    #[instrument]
+    pub(crate) fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, TarkineError> {
+        self.webhooks.dead_letters(limit)
+    }
+
+    #[instrument]
+    pub(crate) async fn replay_dead_letter(&self, id: u64) -> Result<(), TarkineError> {
+        self.webhooks.replay(id).await
+    }
+
+    #[instrument]
+    pub(crate) fn discard_dead_letter(&self, id: u64) -> Result<(), TarkineError> {
+        self.webhooks.discard(id)
+    }
+
+    /// Waits until at least one of the queries has results after its cursor, or until `deadline`,
+    /// in which case nothing is returned.
+    #[instrument]
+    pub(crate) async fn watch_results(
+        &self,
+        cursors: Vec<ResultCursor>,
+        deadline: Instant,
+    ) -> Result<Vec<IndexData>, TarkineError> {
+        for cursor in &cursors {
+            if !self.query_map.contains_key(cursor.query_id.to_ne_bytes())? {
+                return Err(TarkineError::Id);
+            }
+        }
+        if cursors.is_empty() {
+            tokio::time::sleep_until(deadline).await;
+            return Ok(Vec::new());
+        }
+        // Only woken by commits with results for one of these queries
+        let mut commits = cursors
+            .iter()
+            .map(|cursor| self.results.subscribe(cursor.query_id))
+            .collect::<Vec<_>>();
+        loop {
+            // Marked as seen before reading, so a commit landing mid-read still wakes us up after
+            for commits in &mut commits {
+                commits.borrow_and_update();
+            }
+            let results = self.results.clone();
+            let cursors = cursors.clone();
+            let new_results = tokio::task::spawn_blocking(move || read_after(&results, &cursors))
+                .await
+                .map_err(|_e| TarkineError::Storage)??;
+            if !new_results.is_empty() {
+                return Ok(new_results);
+            }
+            let changed = commits
+                .iter_mut()
+                .map(|commits| Box::pin(commits.changed()));
+            match tokio::time::timeout_at(deadline, futures::future::select_all(changed)).await {
+                Ok((Ok(()), ..)) => continue,
+                // The result store is gone, so nothing else is coming
+                Ok((Err(_), ..)) => return Err(TarkineError::Storage),
+                Err(_) => return Ok(Vec::new()),
+            }
+        }
+    }
+}
+
+/// Rejects queries that parse but could never run properly, and compiles the rest for the
+/// shards, so that only happens once per query
+fn check_query(query: &PersistentQuery) -> Result<CompiledQuery, TarkineError> {
+    if let Some(webhook) = &query.webhook {
+        webhook.url().map_err(TarkineError::InvalidQuery)?;
+    }
+    query
+        .scoring
+        .check_threshold(query.score_threshold)
+        .map_err(TarkineError::InvalidQuery)?;
+    CompiledQuery::new(query.clone()).map_err(TarkineError::InvalidQuery)
+}
+
+/// Identifies what was submitted, regardless of the id it ended up with, so a retry can be told
+/// apart from a different query reusing the same idempotency key
+fn fingerprint(query: &PersistentQuery) -> Result<u64, TarkineError> {
+    let mut query = query.clone();
+    query.id = 0;
+    let query_bytes = rkyv::to_bytes::<_, 1024>(&query).map_err(|_e| TarkineError::Parsing)?;
+    Ok(xxh3_64(&query_bytes))
+}
+
+fn decode_idempotency_record(record: &[u8]) -> Result<(u64, u64), TarkineError> {
+    let record: [u8; 16] = record.try_into().map_err(|_e| TarkineError::Parsing)?;
+    let (query_id, fingerprint) = record.split_at(8);
+    Ok((
+        u64::from_le_bytes(query_id.try_into().unwrap()),
+        u64::from_le_bytes(fingerprint.try_into().unwrap()),
+    ))
+}
+
+/// Most results returned per query by a single watch, so a client resuming far behind catches up
+/// over several calls rather than in one huge response
+const WATCH_BATCH: u32 = 1000;
+
+fn read_after(
+    results: &ResultStore,
+    cursors: &[ResultCursor],
+) -> Result<Vec<IndexData>, TarkineError> {
+    let mut new_results = Vec::new();
+    for cursor in cursors {
+        let range = ResultRange {
+            after: cursor.after,
+            limit: Some(WATCH_BATCH),
+        };
+        new_results.extend(results.range(cursor.query_id, &range)?);
+    }
+    new_results.sort_unstable_by_key(|result| result.sequence);
+    Ok(new_results)
+}
+
+impl fmt::Debug for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeState")
+            .field("query_map", &self.query_map)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Loads every valid query in `query_map` into `live_queries`, returning how many were loaded.
+///
+/// Entries that fail rkyv validation are logged and skipped rather than failing the whole boot.
+fn recover_queries(
+    query_map: &sled::Tree,
+    live_queries: &mut ShardedQueries,
+) -> Result<usize, sled::Error> {
+    let mut recovered = Vec::new();
+    for entry in query_map.iter() {
+        let (key, raw_query) = entry?;
+        let Ok(query) = lib::from_archive::<PersistentQuery>(&raw_query) else {
+            tracing::error!(message = "Skipping unreadable persisted query", ?key);
+            continue;
+        };
+        match CompiledQuery::new(query) {
+            Ok(compiled) => recovered.push(compiled),
+            Err(err) => tracing::error!(message = "Skipping invalid persisted query", ?key, err),
+        }
+    }
+    let recovered_count = recovered.len();
+    live_queries.extend(recovered);
+    Ok(recovered_count)
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+    use crate::shard::ShardReader;
+
+    #[test]
+    fn test_recover_skips_corrupt_queries() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let query_map = db.open_tree("queries").unwrap();
+        let query = PersistentQuery::new(3, "darcy", "mr darcy", 11.0).unwrap();
+        let query_bytes = rkyv::to_bytes::<_, 1024>(&query).unwrap();
+        query_map
+            .insert(query.id.to_ne_bytes(), query_bytes.as_slice())
+            .unwrap();
+        query_map
+            .insert(4u64.to_ne_bytes(), &b"garbage"[..])
+            .unwrap();
+
+        let (mut live_queries, readers) = ShardedQueries::new(2);
+        let recovered = recover_queries(&query_map, &mut live_queries).unwrap();
+
+        assert_eq!(recovered, 1);
+        let guard = readers[crate::shard::shard_for(3, 2)].queries.guard();
+        assert_eq!(guard.get(&3).map(|compiled| &compiled.query), Some(&query));
+        assert!(readers.iter().all(|r| r.queries.guard().get(&4).is_none()));
+    }
+
+    #[test]
+    fn test_submissions_get_fresh_ids_and_retries_are_idempotent() {
+        let root = std::env::temp_dir().join(format!("tarkine-state-{}", rand::random::<u64>()));
+        let (live_queries, readers) = ShardedQueries::new(1);
+        let (doc_channel, _doc_receivers) = DocumentBroadcast::new(1, 1);
+        let (deliveries, _delivery_queue) = tachyonix::channel(1);
+        let state = NodeState::open(
+            root.join("db"),
+            root.join("results"),
+            doc_channel,
+            deliveries,
+            live_queries,
+        )
+        .unwrap();
+        let query = |text: &str| PersistentQuery::new(42, "q", text, 1.0).unwrap();
+
+        futures::executor::block_on(async {
+            let first = state
+                .submit_query(query("darcy AND bingley"), None, false)
+                .await
+                .unwrap();
+            let second = state
+                .submit_query(query("darcy AND bingley"), None, false)
+                .await
+                .unwrap();
+            assert_ne!(first, second);
+            assert_ne!(first, 42);
+            // Normalized scores only go up to 1
+            let rejected = PersistentQuery::new(42, "q", "darcy", 2.0)
+                .unwrap()
+                .with_scoring(lib::scoring::ScoringModel::Normalized);
+            assert!(matches!(
+                state.submit_query(rejected, None, false).await,
+                Err(TarkineError::InvalidQuery(_))
+            ));
+            let third = state
+                .submit_query(query("jane AND bingley"), None, false)
+                .await
+                .unwrap();
+            assert_eq!(third, second + 1);
+
+            let key = Some("retry-me".to_string());
+            let keyed = state
+                .submit_query(query("elizabeth AND darcy"), key.clone(), false)
+                .await
+                .unwrap();
+            let retried = state
+                .submit_query(query("elizabeth AND darcy"), key.clone(), false)
+                .await
+                .unwrap();
+            assert_eq!(keyed, retried);
+            // Deleting the query frees its key
+            state.delete_query(keyed, false).await.unwrap();
+            let resubmitted = state
+                .submit_query(query("elizabeth AND darcy"), key.clone(), false)
+                .await
+                .unwrap();
+            assert_ne!(resubmitted, keyed);
+            assert_eq!(
+                state.get_query(resubmitted).unwrap().query,
+                "elizabeth AND darcy"
+            );
+            assert!(matches!(
+                state
+                    .submit_query(query("wickham AND lydia"), key, false)
+                    .await,
+                Err(TarkineError::Conflict(_))
+            ));
+            // Nothing's retained to backfill from
+            assert!(matches!(
+                state.submit_query(query("jane"), None, true).await,
+                Err(TarkineError::InvalidQuery(_))
+            ));
+        });
+        assert_eq!(readers[0].queries.guard().len(), 4);
+        drop(state);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// A node with `shard_count` shards that nothing is reading documents or deliveries from
+    fn open_state(root: &std::path::Path, shard_count: usize) -> (NodeState, Vec<ShardReader>) {
+        let (live_queries, readers) = ShardedQueries::new(shard_count);
+        let (doc_channel, _doc_receivers) = DocumentBroadcast::new(shard_count, 1);
+        let (deliveries, _delivery_queue) = tachyonix::channel(1);
+        let state = NodeState::open(
+            root.join("db"),
+            root.join("results"),
+            doc_channel,
+            deliveries,
+            live_queries,
+        )
+        .unwrap();
+        (state, readers)
+    }
+
+    #[test]
+    fn test_updates_reach_the_shards() {
+        let root = std::env::temp_dir().join(format!("tarkine-state-{}", rand::random::<u64>()));
+        let (state, readers) = open_state(&root, 2);
+        let query = PersistentQuery::new(0, "darcy", "darcy", 1.0).unwrap();
+        futures::executor::block_on(async {
+            let query_id = state.submit_query(query, None, false).await.unwrap();
+            let update = QueryUpdate {
+                score_threshold: Some(25.0),
+                query: Some("darcy AND bingley".to_string()),
+                ..QueryUpdate::default()
+            };
+            let updated = state.update_query(query_id, update).await.unwrap();
+            assert_eq!(updated.score_threshold, 25.0);
+            assert_eq!(state.get_query(query_id).unwrap(), updated);
+            let guard = readers[crate::shard::shard_for(query_id, 2)]
+                .queries
+                .guard();
+            let live = guard.get(&query_id).unwrap();
+            assert_eq!(live.query.score_threshold, 25.0);
+            assert_eq!(live.query.query, "darcy AND bingley");
+            // A rejected update leaves the query as it was
+            let rejected = QueryUpdate {
+                query: Some("darcy AND (".to_string()),
+                ..QueryUpdate::default()
+            };
+            assert!(state.update_query(query_id, rejected).await.is_err());
+            assert_eq!(state.get_query(query_id).unwrap(), updated);
+            assert!(matches!(
+                state
+                    .update_query(query_id + 1, QueryUpdate::default())
+                    .await,
+                Err(TarkineError::Id)
+            ));
+        });
+        drop(state);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_deletes_clear_the_shards_key_and_feedback() {
+        let root = std::env::temp_dir().join(format!("tarkine-state-{}", rand::random::<u64>()));
+        let (state, readers) = open_state(&root, 2);
+        let query = PersistentQuery::new(0, "darcy", "darcy", 1.0).unwrap();
+        futures::executor::block_on(async {
+            let key = Some("delete-me".to_string());
+            let query_id = state.submit_query(query, key, false).await.unwrap();
+            let label = Label {
+                sequence: 7,
+                score: 3.0,
+                scoring: lib::scoring::ScoringModel::Skim,
+                relevant: true,
+            };
+            state.feedback.record(query_id, &[label]).await.unwrap();
+            let shard = &readers[crate::shard::shard_for(query_id, 2)];
+            assert!(shard.queries.guard().get(&query_id).is_some());
+
+            state.delete_query(query_id, false).await.unwrap();
+            assert!(shard.queries.guard().get(&query_id).is_none());
+            assert!(matches!(state.get_query(query_id), Err(TarkineError::Id)));
+            assert!(state.idempotency_keys.is_empty());
+            assert!(state.query_keys.is_empty());
+            assert!(state.feedback.labels(query_id).unwrap().is_empty());
+            assert!(matches!(
+                state.delete_query(query_id, false).await,
+                Err(TarkineError::Id)
+            ));
+        });
+        drop(state);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_paging_lists_every_query_once() {
+        let root = std::env::temp_dir().join(format!("tarkine-state-{}", rand::random::<u64>()));
+        let (state, _readers) = open_state(&root, 1);
+        let mut submitted = futures::executor::block_on(async {
+            let mut submitted = Vec::new();
+            for idx in 0..7 {
+                let query = PersistentQuery::new(0, "q", format!("darcy{idx}"), 1.0).unwrap();
+                submitted.push(state.submit_query(query, None, false).await.unwrap());
+            }
+            submitted
+        });
+        // Skipped rather than failing the page it's on
+        state
+            .query_map
+            .insert(1234u64.to_ne_bytes(), &b"garbage"[..])
+            .unwrap();
+
+        let mut listed = Vec::new();
+        let mut after = None;
+        loop {
+            let page = QueryPage {
+                after,
+                limit: Some(3),
+            };
+            let queries = state.list_queries(page).unwrap();
+            let Some(last) = queries.last() else {
+                break;
+            };
+            assert!(queries.len() <= 3);
+            after = Some(last.id);
+            listed.extend(queries.iter().map(|query| query.id));
+        }
+        listed.sort_unstable();
+        submitted.sort_unstable();
+        assert_eq!(listed, submitted);
+        drop(state);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_labels_keep_the_model_their_result_was_scored_under() {
+        let root = std::env::temp_dir().join(format!("tarkine-state-{}", rand::random::<u64>()));
+        let (live_queries, _readers) = ShardedQueries::new(1);
+        let (doc_channel, _doc_receivers) = DocumentBroadcast::new(1, 1);
+        let (deliveries, _delivery_queue) = tachyonix::channel(1);
+        let state = NodeState::open(
+            root.join("db"),
+            root.join("results"),
+            doc_channel,
+            deliveries,
+            live_queries,
+        )
+        .unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let query = PersistentQuery::new(0, "darcy", "darcy", 10.0).unwrap();
+        let query_id = runtime
+            .block_on(state.submit_query(query, None, false))
+            .unwrap();
+        let results = state.results();
+        let stored = glommio::LocalExecutorBuilder::default()
+            .spawn(move || async move {
+                let mut writer = results.writer(0).await.unwrap();
+                let mut stored = (0..3)
+                    .map(|document_id| IndexData {
+                        source_query: query_id,
+                        key: 0,
+                        sequence: 0,
+                        document_id,
+                        name: "letter".to_string(),
+                        match_indices: Vec::new(),
+                        score: 50.0,
+                        scoring: lib::scoring::ScoringModel::Skim,
+                        backfilled: false,
+                    })
+                    .collect::<Vec<_>>();
+                writer.append(&mut stored).await.unwrap();
+                stored
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        runtime.block_on(async {
+            let update = QueryUpdate {
+                scoring: Some(lib::scoring::ScoringModel::Bm25),
+                score_threshold: Some(2.0),
+                ..QueryUpdate::default()
+            };
+            state.update_query(query_id, update).await.unwrap();
+            let labels = vec![ResultLabel {
+                sequence: stored[1].sequence,
+                relevant: true,
+            }];
+            let calibration = state
+                .label_results(query_id, labels, CalibrationOptions::default())
+                .await
+                .unwrap();
+            // Scored under Skim, so left out of a Bm25 calibration
+            assert_eq!(calibration.scoring, lib::scoring::ScoringModel::Bm25);
+            assert_eq!(calibration.relevant, 0);
+            assert_eq!(calibration.recommended_threshold, None);
+            let recorded = state.feedback.labels(query_id).unwrap();
+            assert_eq!(recorded.len(), 1);
+            assert_eq!(recorded[0].scoring, lib::scoring::ScoringModel::Skim);
+            assert_eq!(recorded[0].score, 50.0);
+            // Labeling a result that was never stored is rejected
+            let missing = vec![ResultLabel {
+                sequence: stored[2].sequence + 1,
+                relevant: false,
+            }];
+            assert!(matches!(
+                state
+                    .label_results(query_id, missing, CalibrationOptions::default())
+                    .await,
+                Err(TarkineError::Id)
+            ));
+        });
+        drop(state);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_backfilled_results_are_stored_and_delivered_by_the_query_owner() {
+        use crate::{
+            retention::DocumentRetention,
+            shard::{BackfillRoutes, QueryShard, ShardRuntime},
+        };
+        use std::collections::HashSet;
+
+        let shard_count = 3;
+        let root = std::env::temp_dir().join(format!("tarkine-state-{}", rand::random::<u64>()));
+        let (live_queries, readers) = ShardedQueries::new(shard_count);
+        let (doc_channel, doc_receivers) = DocumentBroadcast::new(shard_count, 16);
+        let (deliveries, mut delivery_queue) = tachyonix::channel(64);
+        let limits = RetentionLimits {
+            max_bytes: 1024 * 1024,
+            max_age: None,
+        };
+        let state = NodeState::open(
+            root.join("db"),
+            root.join("results"),
+            doc_channel,
+            deliveries,
+            live_queries,
+        )
+        .unwrap()
+        .with_retention(limits);
+        let retention = DocumentRetention::new(root.join("retained"), limits, shard_count);
+        let (routes, inboxes) = BackfillRoutes::new(shard_count);
+        let shards = readers
+            .into_iter()
+            .zip(doc_receivers)
+            .zip(inboxes)
+            .enumerate()
+            .map(|(shard_id, ((reader, messages), backfilled))| {
+                let runtime = ShardRuntime {
+                    shard_id,
+                    shard: QueryShard::new(reader),
+                    results: state.results(),
+                    webhooks: state.webhooks(),
+                    retention: Some(retention.clone()),
+                    routes: routes.clone(),
+                };
+                glommio::LocalExecutorBuilder::default()
+                    .spawn(move || runtime.run(messages, backfilled))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        drop(routes);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let documents = (0..30)
+                .map(|idx| {
+                    TextSource::from_content(
+                        format!("Mr Darcy, letter {idx}"),
+                        "letter".to_string(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            // Spread over the shards, so most of the matches aren't found by the query's owner
+            let retained_by = documents
+                .iter()
+                .map(|doc| crate::shard::shard_for(doc.id, shard_count))
+                .collect::<HashSet<_>>();
+            assert!(retained_by.len() > 1);
+            for document in documents {
+                assert!(state.submit_document(document).await.unwrap());
+            }
+            let query = PersistentQuery::new(0, "darcy", "darcy", 0.1)
+                .unwrap()
+                .with_webhook(lib::Webhook {
+                    url: "https://example.com/hook".to_string(),
+                    secret: None,
+                    snippet: true,
+                });
+            let query_id = state.submit_query(query, None, true).await.unwrap();
+            let live = TextSource::from_content("Mr Darcy, in person", "visit".to_string());
+            assert!(state.submit_document(live).await.unwrap());
+
+            let mut watched: Vec<IndexData> = Vec::new();
+            while watched.len() < 31 {
+                let cursor = ResultCursor {
+                    query_id,
+                    after: watched.last().map(|result| result.sequence),
+                };
+                let deadline = Instant::now() + std::time::Duration::from_secs(10);
+                let results = state.watch_results(vec![cursor], deadline).await.unwrap();
+                assert!(
+                    !results.is_empty(),
+                    "Timed out after {} results",
+                    watched.len()
+                );
+                watched.extend(results);
+            }
+            assert_eq!(watched.len(), 31);
+            let document_ids = watched
+                .iter()
+                .map(|r| r.document_id)
+                .collect::<HashSet<_>>();
+            assert_eq!(document_ids.len(), 31);
+            assert_eq!(watched.iter().filter(|r| r.backfilled).count(), 30);
+            assert!(watched
+                .windows(2)
+                .all(|pair| pair[0].sequence < pair[1].sequence));
+
+            let mut delivered = HashSet::new();
+            for _ in 0..31 {
+                let delivery =
+                    tokio::time::timeout(std::time::Duration::from_secs(10), delivery_queue.recv())
+                        .await
+                        .expect("Every stored result is queued for delivery")
+                        .unwrap();
+                assert!(delivery.payload.snippet.is_some());
+                delivered.insert(delivery.payload.result.document_id);
+            }
+            assert_eq!(delivered, document_ids);
+        });
+        // Closes the document channel, which stops the shards
+        drop(state);
+        for shard in shards {
+            shard.join().unwrap();
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
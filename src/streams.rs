@@ -0,0 +1,214 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, Path,
+    },
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+};
+use futures::{stream, Stream, StreamExt};
+use lib::{IndexData, ResultCursor};
+use serde_json::json;
+use std::{convert::Infallible, time::Duration};
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+use crate::{errors::ApiError, state::NodeState};
+
+/// How long each underlying watch waits before starting a fresh one. Only bounds how long a
+/// stream holds on to its cursors between checks - nothing is missed when a watch times out.
+const STREAM_POLL: Duration = Duration::from_secs(30);
+
+/// `GET /query/:query_id/stream` - every new result for a query as a Server-Sent Event.
+///
+/// Each event's id is the result's sequence number, so a reconnecting `EventSource` picks up
+/// after the last result it saw through `Last-Event-ID`, with anything it missed replayed from the
+/// result store first.
+pub(crate) async fn stream_query_results(
+    Path(query_id): Path<u64>,
+    headers: HeaderMap,
+    Extension(state): Extension<NodeState>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    state.get_query(query_id)?;
+    let cursor = ResultCursor {
+        query_id,
+        after: last_event_id(&headers),
+    };
+    let events = result_stream(state, vec![cursor]).filter_map(|result| async move {
+        match Event::default()
+            .id(result.sequence.to_string())
+            .json_data(&result)
+        {
+            Ok(event) => Some(Ok(event)),
+            Err(err) => {
+                event!(Level::ERROR, message = "Couldn't encode result event", %err);
+                None
+            }
+        }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// `GET /query/stream` - results for several queries over one WebSocket.
+///
+/// The client sends a JSON list of cursors (`[{"query_id": 1, "after": 41}, ...]`) to choose which
+/// queries to follow and where to resume each from, and can send a new list at any point to
+/// replace it. Every new result for those queries comes back as a JSON text message, tagged with
+/// its `source_query`.
+pub(crate) async fn stream_results_socket(
+    upgrade: WebSocketUpgrade,
+    Extension(state): Extension<NodeState>,
+) -> impl IntoResponse {
+    upgrade.on_upgrade(|socket| serve_socket(socket, state))
+}
+
+async fn serve_socket(mut socket: WebSocket, state: NodeState) {
+    let mut results = result_stream(state.clone(), Vec::new()).boxed();
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let cursors = match message {
+                    Some(Ok(Message::Text(text))) => serde_json::from_str::<Vec<ResultCursor>>(&text),
+                    Some(Ok(Message::Close(_))) | None => return,
+                    // Pings are answered by axum, everything else is ignored
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => {
+                        event!(Level::DEBUG, message = "Result socket failed", %err);
+                        return;
+                    }
+                };
+                let reply = match cursors {
+                    Ok(cursors) => match check_queries(&state, &cursors) {
+                        Ok(()) => {
+                            results = result_stream(state.clone(), cursors).boxed();
+                            continue;
+                        }
+                        Err(err) => json!({ "error": err.to_string() }),
+                    },
+                    Err(err) => json!({ "error": format!("Invalid cursors: {err}") }),
+                };
+                if socket.send(Message::Text(reply.to_string())).await.is_err() {
+                    return;
+                }
+            }
+            Some(result) = results.next() => {
+                let Ok(text) = serde_json::to_string(&result) else {
+                    continue;
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Rejects a subscription naming a query that doesn't exist, leaving the current one in place
+fn check_queries(state: &NodeState, cursors: &[ResultCursor]) -> Result<(), ApiError> {
+    for cursor in cursors {
+        state.get_query(cursor.query_id)?;
+    }
+    Ok(())
+}
+
+/// Every result for the cursors' queries after their positions, in the order they're stored:
+/// first whatever's already in the result store, then new results as the shards commit them.
+///
+/// Ends if the store can't be read or one of the queries disappears.
+fn result_stream(state: NodeState, cursors: Vec<ResultCursor>) -> impl Stream<Item = IndexData> {
+    stream::unfold((state, cursors), |(state, mut cursors)| async move {
+        loop {
+            let deadline = Instant::now() + STREAM_POLL;
+            match state.watch_results(cursors.clone(), deadline).await {
+                Ok(results) if results.is_empty() => continue,
+                Ok(results) => {
+                    advance(&mut cursors, &results);
+                    return Some((stream::iter(results), (state, cursors)));
+                }
+                Err(err) => {
+                    event!(Level::WARN, message = "Ending result stream", ?cursors, %err);
+                    return None;
+                }
+            }
+        }
+    })
+    .flatten()
+}
+
+/// Moves each cursor past the results returned for its query
+fn advance(cursors: &mut [ResultCursor], results: &[IndexData]) {
+    for cursor in cursors.iter_mut() {
+        let last_seen = results
+            .iter()
+            .filter(|result| result.source_query == cursor.query_id)
+            .map(|result| result.sequence)
+            .max();
+        if last_seen.is_some() {
+            cursor.after = cursor.after.max(last_seen);
+        }
+    }
+}
+
+/// The sequence number an SSE client reconnecting with `Last-Event-ID` saw last
+fn last_event_id(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("last-event-id")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod streams_tests {
+    use super::*;
+
+    fn result(source_query: u64, sequence: u64) -> IndexData {
+        IndexData {
+            source_query,
+            key: 0,
+            sequence,
+            document_id: 1,
+            name: "doc".to_string(),
+            match_indices: Vec::new(),
+            score: 1.0,
+            scoring: lib::scoring::ScoringModel::Skim,
+            backfilled: false,
+        }
+    }
+
+    #[test]
+    fn test_cursors_advance_per_query() {
+        let mut cursors = [
+            ResultCursor {
+                query_id: 1,
+                after: None,
+            },
+            ResultCursor {
+                query_id: 2,
+                after: Some(10),
+            },
+            ResultCursor {
+                query_id: 3,
+                after: Some(4),
+            },
+        ];
+        advance(&mut cursors, &[result(1, 5), result(2, 12), result(1, 7)]);
+        let positions = cursors.map(|cursor| cursor.after);
+        assert_eq!(positions, [Some(7), Some(12), Some(4)]);
+    }
+
+    #[test]
+    fn test_last_event_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(last_event_id(&headers), None);
+        headers.insert("last-event-id", "41".parse().unwrap());
+        assert_eq!(last_event_id(&headers), Some(41));
+        headers.insert("last-event-id", "not a sequence".parse().unwrap());
+        assert_eq!(last_event_id(&headers), None);
+    }
+}
@@ -0,0 +1,407 @@
+use bytecheck::CheckBytes;
+use hmac::{Hmac, Mac};
+use lib::{IndexData, TarkineError, TextSource, Webhook};
+use rkyv::{Archive, Deserialize, Serialize};
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+use tracing::{event, Level};
+
+/// Most deliveries in flight to any one destination at a time
+const MAX_CONCURRENT_PER_DESTINATION: usize = 4;
+/// Most deliveries in flight or waiting on their destination's limit, across every destination.
+/// Past this the queue fills up, and the search shards wait to queue more.
+const MAX_IN_FLIGHT: usize = 1024;
+/// Attempts at a delivery before it's parked as a dead letter
+const MAX_ATTEMPTS: u32 = 6;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Characters of text either side of a match sent as its snippet
+const SNIPPET_CONTEXT: usize = 80;
+
+/// A match on its way to a query's webhook
+#[derive(Archive, Debug, Clone, serde::Serialize, serde::Deserialize, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub(crate) struct Delivery {
+    pub(crate) webhook: Webhook,
+    pub(crate) payload: WebhookPayload,
+}
+
+/// The JSON body POSTed to a webhook: the stored result, plus the text around it if asked for
+#[derive(Archive, Debug, Clone, serde::Serialize, serde::Deserialize, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub(crate) struct WebhookPayload {
+    #[serde(flatten)]
+    pub(crate) result: IndexData,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) snippet: Option<String>,
+}
+
+/// A delivery that ran out of attempts, or that the destination refused outright
+#[derive(Archive, Debug, serde::Serialize, serde::Deserialize, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub(crate) struct DeadLetter {
+    pub(crate) id: u64,
+    pub(crate) delivery: Delivery,
+    pub(crate) attempts: u32,
+    /// Why the last attempt failed
+    pub(crate) error: String,
+    /// Unix time in milliseconds
+    pub(crate) failed_at: i64,
+}
+
+/// Queues matches for delivery to their queries' webhooks, and keeps the ones that couldn't be
+/// delivered.
+///
+/// Cheap to clone - the search shards queue deliveries through it, and the API inspects and
+/// replays dead letters through it.
+#[derive(Clone)]
+pub(crate) struct Webhooks {
+    db: sled::Db,
+    dead_letters: sled::Tree,
+    queue: tachyonix::Sender<Delivery>,
+}
+
+impl Webhooks {
+    pub(crate) fn open(
+        db: &sled::Db,
+        queue: tachyonix::Sender<Delivery>,
+    ) -> Result<Self, TarkineError> {
+        Ok(Self {
+            db: db.clone(),
+            dead_letters: db.open_tree("dead_letters")?,
+            queue,
+        })
+    }
+
+    /// Queues a stored result for delivery to its query's webhook
+    pub(crate) async fn notify(&self, webhook: Webhook, document: &TextSource, result: IndexData) {
+        let snippet = match webhook.snippet {
+            true => snippet(&document.data, &result.match_indices),
+            false => None,
+        };
+        let delivery = Delivery {
+            webhook,
+            payload: WebhookPayload { result, snippet },
+        };
+        if self.queue.send(delivery).await.is_err() {
+            event!(
+                Level::ERROR,
+                message = "Webhook queue has shut down, dropping delivery"
+            );
+        }
+    }
+
+    /// Parked deliveries, oldest first
+    pub(crate) fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, TarkineError> {
+        self.dead_letters
+            .iter()
+            .values()
+            .take(limit)
+            .map(|raw| lib::from_archive::<DeadLetter>(&raw?))
+            .collect()
+    }
+
+    /// Takes a dead letter out of the store and queues it to be delivered again
+    pub(crate) async fn replay(&self, id: u64) -> Result<(), TarkineError> {
+        let Some(raw) = self.dead_letters.remove(id.to_be_bytes())? else {
+            return Err(TarkineError::Id);
+        };
+        let dead_letter = lib::from_archive::<DeadLetter>(&raw)?;
+        self.queue.send(dead_letter.delivery).await?;
+        Ok(())
+    }
+
+    pub(crate) fn discard(&self, id: u64) -> Result<(), TarkineError> {
+        match self.dead_letters.remove(id.to_be_bytes())? {
+            Some(_) => Ok(()),
+            None => Err(TarkineError::Id),
+        }
+    }
+
+    fn park(&self, delivery: Delivery, attempts: u32, error: String) -> Result<u64, TarkineError> {
+        let dead_letter = DeadLetter {
+            id: self.db.generate_id()?,
+            delivery,
+            attempts,
+            error,
+            failed_at: chrono::Utc::now().timestamp_millis(),
+        };
+        let bytes = rkyv::to_bytes::<_, 1024>(&dead_letter).map_err(|_e| TarkineError::Parsing)?;
+        // Big endian, so the tree iterates in the order deliveries failed
+        self.dead_letters
+            .insert(dead_letter.id.to_be_bytes(), bytes.as_slice())?;
+        Ok(dead_letter.id)
+    }
+}
+
+/// Runs webhook deliveries on their own thread, so slow destinations never hold up the API
+pub(crate) fn delivery_runtime(webhooks: Webhooks, queue: tachyonix::Receiver<Delivery>) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .thread_name("webhooks")
+        .build()
+        .expect("Couldn't build webhook runtime");
+    runtime.block_on(deliver_all(webhooks, queue));
+}
+
+async fn deliver_all(webhooks: Webhooks, mut queue: tachyonix::Receiver<Delivery>) {
+    let client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("Couldn't build webhook client");
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let mut destinations: HashMap<String, Arc<Semaphore>> = HashMap::new();
+    while let Ok(delivery) = queue.recv().await {
+        let Ok(in_flight) = in_flight.clone().acquire_owned().await else {
+            return;
+        };
+        let destination = destination(&delivery.webhook);
+        if !destinations.contains_key(&destination) {
+            prune_idle(&mut destinations);
+        }
+        let limit = destinations
+            .entry(destination)
+            .or_insert_with(|| Arc::new(Semaphore::new(MAX_CONCURRENT_PER_DESTINATION)))
+            .clone();
+        let client = client.clone();
+        let webhooks = webhooks.clone();
+        tokio::spawn(async move {
+            let _in_flight = in_flight;
+            let Ok(_permit) = limit.acquire_owned().await else {
+                return;
+            };
+            deliver(&client, &webhooks, delivery).await;
+        });
+    }
+}
+
+/// Forgets the limits of destinations with nothing in flight, so ones whose queries are gone don't
+/// pile up. Each delivery task holds a reference to its destination's limit until it's done.
+fn prune_idle(destinations: &mut HashMap<String, Arc<Semaphore>>) {
+    destinations.retain(|_, limit| Arc::strong_count(limit) > 1);
+}
+
+/// Tries a delivery until it succeeds or runs out of attempts, then parks it as a dead letter
+async fn deliver(client: &reqwest::Client, webhooks: &Webhooks, delivery: Delivery) {
+    let result = &delivery.payload.result;
+    let (query_id, sequence) = (result.source_query, result.sequence);
+    let body = match serde_json::to_vec(&delivery.payload) {
+        Ok(body) => body,
+        Err(err) => {
+            event!(Level::ERROR, message = "Couldn't encode webhook delivery", query_id, %err);
+            return;
+        }
+    };
+    let mut attempts = 0;
+    let error = loop {
+        attempts += 1;
+        match post(client, &delivery, &body).await {
+            Ok(()) => {
+                event!(
+                    Level::DEBUG,
+                    message = "Delivered webhook",
+                    query_id,
+                    sequence
+                );
+                return;
+            }
+            Err(Failure::Retryable(error)) if attempts < MAX_ATTEMPTS => {
+                event!(Level::DEBUG, message = "Retrying webhook", query_id, sequence, attempts, %error);
+                tokio::time::sleep(backoff(attempts)).await;
+            }
+            Err(Failure::Retryable(error) | Failure::Permanent(error)) => break error,
+        }
+    };
+    match webhooks.park(delivery, attempts, error.clone()) {
+        Ok(id) => {
+            event!(Level::WARN, message = "Parked undeliverable webhook", query_id, sequence, dead_letter = id, %error)
+        }
+        Err(err) => {
+            event!(
+                Level::ERROR,
+                message = "Couldn't park undeliverable webhook",
+                query_id,
+                sequence,
+                ?err
+            )
+        }
+    }
+}
+
+enum Failure {
+    Retryable(String),
+    Permanent(String),
+}
+
+async fn post(client: &reqwest::Client, delivery: &Delivery, body: &[u8]) -> Result<(), Failure> {
+    let url = delivery.webhook.url().map_err(Failure::Permanent)?;
+    let result = &delivery.payload.result;
+    let mut request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        // Stable across retries and replays, so receivers can drop duplicates
+        .header(
+            "X-Tarkine-Delivery",
+            format!("{}-{}", result.source_query, result.sequence),
+        )
+        .body(body.to_vec());
+    if let Some(secret) = &delivery.webhook.secret {
+        request = request.header("X-Tarkine-Signature", signature(secret, body));
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|err| Failure::Retryable(err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+    {
+        Err(Failure::Retryable(format!(
+            "Destination responded {status}"
+        )))
+    } else {
+        // Anything else is the destination rejecting the request, which trying again won't fix
+        Err(Failure::Permanent(format!(
+            "Destination responded {status}"
+        )))
+    }
+}
+
+/// Deliveries are capped per scheme, host and port
+fn destination(webhook: &Webhook) -> String {
+    match webhook.url() {
+        Ok(url) => url.origin().ascii_serialization(),
+        Err(_) => webhook.url.clone(),
+    }
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with the webhook's secret
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Doubles with every attempt up to `MAX_BACKOFF`, with up to a quarter of it added at random so
+/// retries against a struggling destination spread out
+fn backoff(attempts: u32) -> Duration {
+    let backoff = INITIAL_BACKOFF
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF);
+    backoff + backoff.mul_f64(rand::random::<f64>() / 4.0)
+}
+
+/// The text around the first match, `SNIPPET_CONTEXT` characters either side
+pub(crate) fn snippet(text: &str, match_indices: &[[usize; 2]]) -> Option<String> {
+    let [start, end] = *match_indices.first()?;
+    Some(
+        text.chars()
+            .skip(start.saturating_sub(SNIPPET_CONTEXT))
+            .take(end + 1 + SNIPPET_CONTEXT - start.saturating_sub(SNIPPET_CONTEXT))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod webhooks_tests {
+    use super::*;
+
+    fn delivery(sequence: u64) -> Delivery {
+        Delivery {
+            webhook: Webhook {
+                url: "https://example.com/hook".to_string(),
+                secret: None,
+                snippet: false,
+            },
+            payload: WebhookPayload {
+                result: IndexData {
+                    source_query: 1,
+                    key: 2,
+                    sequence,
+                    document_id: 3,
+                    name: "doc".to_string(),
+                    match_indices: vec![[0, 4]],
+                    score: 100.0,
+                    scoring: lib::scoring::ScoringModel::Skim,
+                    backfilled: false,
+                },
+                snippet: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        for (attempts, base) in [(1, 1), (2, 2), (3, 4), (6, 32), (7, 60), (40, 60)] {
+            let backoff = backoff(attempts);
+            let base = Duration::from_secs(base);
+            assert!(backoff >= base && backoff <= base.mul_f64(1.25));
+        }
+    }
+
+    #[test]
+    fn test_idle_destinations_are_pruned() {
+        let limit = || Arc::new(Semaphore::new(MAX_CONCURRENT_PER_DESTINATION));
+        let mut destinations =
+            HashMap::from([("a".to_string(), limit()), ("b".to_string(), limit())]);
+        let in_use = destinations["a"].clone();
+        prune_idle(&mut destinations);
+        assert_eq!(destinations.keys().collect::<Vec<_>>(), ["a"]);
+        drop(in_use);
+        prune_idle(&mut destinations);
+        assert!(destinations.is_empty());
+    }
+
+    #[test]
+    fn test_snippet() {
+        let text = "a".repeat(100) + "Darcy" + &"b".repeat(100);
+        let snippet = snippet(&text, &[[100, 104]]).unwrap();
+        assert_eq!(snippet, "a".repeat(80) + "Darcy" + &"b".repeat(80));
+        assert_eq!(super::snippet("Mr Darcy", &[[3, 7]]).unwrap(), "Mr Darcy");
+        assert_eq!(super::snippet("Mr Darcy", &[]), None);
+    }
+
+    #[test]
+    fn test_dead_letters_replay_in_order() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let (sender, mut queue) = tachyonix::channel(8);
+        let webhooks = Webhooks::open(&db, sender).unwrap();
+        let first = webhooks
+            .park(delivery(7), 6, "timed out".to_string())
+            .unwrap();
+        let second = webhooks.park(delivery(8), 1, "404".to_string()).unwrap();
+
+        let parked = webhooks.dead_letters(10).unwrap();
+        let ids = parked.iter().map(|letter| letter.id).collect::<Vec<_>>();
+        assert_eq!(ids, [first, second]);
+        assert_eq!(parked[0].delivery.payload.result.sequence, 7);
+        assert_eq!(parked[1].error, "404");
+
+        futures::executor::block_on(async {
+            webhooks.replay(first).await.unwrap();
+            let replayed = queue.recv().await.unwrap();
+            assert_eq!(replayed.payload.result.sequence, 7);
+            assert!(matches!(
+                webhooks.replay(first).await,
+                Err(TarkineError::Id)
+            ));
+        });
+        webhooks.discard(second).unwrap();
+        assert!(webhooks.dead_letters(10).unwrap().is_empty());
+    }
+}